@@ -0,0 +1,247 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// A single token of a parsed query along with the label the tagger assigned it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParsedToken {
+    /// Byte range of the token in the original query
+    pub span: Range<usize>,
+    /// Transliterated text of the token, as seen by the tagger
+    pub text: String,
+    /// Label assigned to the token
+    pub label: String,
+    /// Coarse label that `label` belongs to under the model's label schema
+    pub coarse_label: String,
+    /// Marginal probability of the label at this token
+    pub probability: f64,
+}
+
+/// A run of consecutive tokens sharing the same label
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AddressComponent {
+    /// Label shared by every token in the component
+    pub label: String,
+    /// Coarse label that `label` belongs to
+    pub coarse_label: String,
+    /// Original text covered by the component, including interior whitespace
+    pub text: String,
+    /// Byte range of the component in the original query
+    pub span: Range<usize>,
+    /// Range of indices into `ParsedAddress::tokens` making up this component
+    pub tokens: Range<usize>,
+}
+
+/// The result of parsing a query
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParsedAddress {
+    /// The query as it was passed to the parser
+    pub query: String,
+    /// Every token of the query, in order
+    pub tokens: Vec<ParsedToken>,
+    /// Consecutive tokens grouped by label, in order
+    pub components: Vec<AddressComponent>,
+    /// Unnormalized score of the whole label sequence
+    pub score: f64,
+    /// Probability of the whole label sequence under the model
+    pub probability: f64,
+}
+
+/// Typed view over the components of a parsed address
+///
+/// When a label occurs in more than one component, the components' text is
+/// joined with a single space.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AddressFields {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub house: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub near: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub house_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub road: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub staircase: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entrance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub po_box: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postcode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub neighborhood: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locality: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub world_region: Option<String>,
+}
+
+impl ParsedAddress {
+    /// Build a parsed address from labeled tokens, grouping consecutive tokens
+    /// with the same label into components.
+    pub fn new(query: &str, tokens: Vec<ParsedToken>, score: f64, probability: f64) -> Self {
+        let mut components: Vec<AddressComponent> = vec![];
+        for (idx, token) in tokens.iter().enumerate() {
+            match components.last_mut() {
+                Some(component) if component.label == token.label => {
+                    component.span.end = token.span.end;
+                    component.tokens.end = idx + 1;
+                }
+                _ => components.push(AddressComponent {
+                    label: token.label.clone(),
+                    coarse_label: token.coarse_label.clone(),
+                    text: String::new(),
+                    span: token.span.clone(),
+                    tokens: idx..idx + 1,
+                }),
+            }
+        }
+        for component in &mut components {
+            component.text = query[component.span.clone()].to_string();
+        }
+        ParsedAddress {
+            query: query.to_string(),
+            tokens,
+            components,
+            score,
+            probability,
+        }
+    }
+
+    /// Label for each token, in order
+    pub fn labels(&self) -> Vec<&str> {
+        self.tokens
+            .iter()
+            .map(|token| token.label.as_str())
+            .collect()
+    }
+
+    /// Coarse label for each token, in order
+    pub fn coarse_labels(&self) -> Vec<&str> {
+        self.tokens
+            .iter()
+            .map(|token| token.coarse_label.as_str())
+            .collect()
+    }
+
+    /// The first component with the given label
+    pub fn component(&self, label: &str) -> Option<&AddressComponent> {
+        self.components
+            .iter()
+            .find(|component| component.label == label)
+    }
+
+    /// Every component with the given label
+    pub fn components_with_label<'a>(
+        &'a self,
+        label: &'a str,
+    ) -> impl Iterator<Item = &'a AddressComponent> + 'a {
+        self.components
+            .iter()
+            .filter(move |component| component.label == label)
+    }
+
+    /// Typed accessor struct for the well-known components
+    pub fn fields(&self) -> AddressFields {
+        let mut fields = AddressFields::default();
+        for component in &self.components {
+            let field = match component.label.as_str() {
+                "house" => &mut fields.house,
+                "category" => &mut fields.category,
+                "near" => &mut fields.near,
+                "house_number" => &mut fields.house_number,
+                "road" => &mut fields.road,
+                "unit" => &mut fields.unit,
+                "level" => &mut fields.level,
+                "staircase" => &mut fields.staircase,
+                "entrance" => &mut fields.entrance,
+                "po_box" => &mut fields.po_box,
+                "postcode" => &mut fields.postcode,
+                "neighborhood" => &mut fields.neighborhood,
+                "locality" => &mut fields.locality,
+                "region" => &mut fields.region,
+                "country_region" => &mut fields.country_region,
+                "country" => &mut fields.country,
+                "world_region" => &mut fields.world_region,
+                _ => continue,
+            };
+            match field {
+                Some(text) => {
+                    text.push(' ');
+                    text.push_str(&component.text);
+                }
+                None => *field = Some(component.text.clone()),
+            }
+        }
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(query: &str, word: &str, label: &str) -> ParsedToken {
+        let start = query.find(word).unwrap();
+        ParsedToken {
+            span: start..start + word.len(),
+            text: word.to_lowercase(),
+            label: label.to_string(),
+            coarse_label: label.to_string(),
+            probability: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_components_grouped() {
+        let query = "123 Main  Street, Seattle";
+        let tokens = vec![
+            token(query, "123", "house_number"),
+            token(query, "Main", "road"),
+            token(query, "Street,", "road"),
+            token(query, "Seattle", "locality"),
+        ];
+        let parsed = ParsedAddress::new(query, tokens, 0.0, 1.0);
+        assert_eq!(parsed.components.len(), 3);
+        let road = parsed.component("road").unwrap();
+        assert_eq!(road.text, "Main  Street,");
+        assert_eq!(road.span, 4..17);
+        assert_eq!(road.tokens, 1..3);
+        assert_eq!(
+            parsed.labels(),
+            vec!["house_number", "road", "road", "locality"]
+        );
+
+        let fields = parsed.fields();
+        assert_eq!(fields.house_number.as_deref(), Some("123"));
+        assert_eq!(fields.locality.as_deref(), Some("Seattle"));
+        assert_eq!(fields.postcode, None);
+    }
+
+    #[test]
+    fn test_repeated_label_joined() {
+        let query = "Main Seattle Street";
+        let tokens = vec![
+            token(query, "Main", "road"),
+            token(query, "Seattle", "locality"),
+            token(query, "Street", "road"),
+        ];
+        let parsed = ParsedAddress::new(query, tokens, 0.0, 1.0);
+        assert_eq!(parsed.components_with_label("road").count(), 2);
+        assert_eq!(parsed.fields().road.as_deref(), Some("Main Street"));
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::lp_file_stream::LpEntryToken;
+
+/// Common words and their abbreviations, which `Abbreviations` swaps in both
+/// directions
+pub const DEFAULT_ABBREVIATIONS: [(&str, &str); 30] = [
+    ("street", "st"),
+    ("avenue", "ave"),
+    ("road", "rd"),
+    ("boulevard", "blvd"),
+    ("drive", "dr"),
+    ("lane", "ln"),
+    ("court", "ct"),
+    ("place", "pl"),
+    ("highway", "hwy"),
+    ("parkway", "pkwy"),
+    ("terrace", "ter"),
+    ("square", "sq"),
+    ("circle", "cir"),
+    ("expressway", "expy"),
+    ("center", "ctr"),
+    ("mount", "mt"),
+    ("fort", "ft"),
+    ("north", "n"),
+    ("south", "s"),
+    ("east", "e"),
+    ("west", "w"),
+    ("northeast", "ne"),
+    ("northwest", "nw"),
+    ("southeast", "se"),
+    ("southwest", "sw"),
+    ("apartment", "apt"),
+    ("suite", "ste"),
+    ("building", "bldg"),
+    ("floor", "fl"),
+    ("strasse", "str"),
+];
+
+/// Characters that OCR and hurried typing confuse for one another
+const CONFUSIONS: [(char, char); 6] = [
+    ('0', 'o'),
+    ('1', 'l'),
+    ('1', 'i'),
+    ('5', 's'),
+    ('8', 'b'),
+    ('2', 'z'),
+];
+
+/// A change made to training examples to make them look more like real
+/// queries
+pub trait Transform: Send + Sync {
+    /// Name of the transform in statistics
+    fn name(&self) -> &str;
+
+    /// Change the tokens of one example, returning how many tokens were
+    /// changed, moved or removed
+    fn apply(&self, tokens: &mut Vec<LpEntryToken>, rng: &mut dyn RngCore) -> usize;
+}
+
+/// Replace each token with probability `rate` by the result of `change`, if
+/// it gives one. Returns the number of tokens replaced.
+fn change_tokens<F>(
+    tokens: &mut [LpEntryToken],
+    rate: f64,
+    rng: &mut dyn RngCore,
+    change: F,
+) -> usize
+where
+    F: Fn(&str, &mut dyn RngCore) -> Option<String>,
+{
+    let mut changed = 0;
+    for token in tokens.iter_mut() {
+        if rng.gen::<f64>() >= rate {
+            continue;
+        }
+        if let Some(word) = change(&token.transliterated, rng) {
+            *token = LpEntryToken::new(&word, &token.label);
+            changed += 1;
+        }
+    }
+    changed
+}
+
+/// Ranges of the runs of tokens with the same label
+fn components(tokens: &[LpEntryToken]) -> Vec<(usize, usize)> {
+    let mut components: Vec<(usize, usize)> = vec![];
+    for (index, token) in tokens.iter().enumerate() {
+        match components.last_mut() {
+            Some((start, end)) if tokens[*start].label == token.label => *end = index + 1,
+            _ => components.push((index, index + 1)),
+        }
+    }
+    components
+}
+
+/// Keep only a prefix of the example, as in a query that's still being typed
+#[derive(Debug, Clone)]
+pub struct Truncate {
+    /// Share of examples of two or more tokens that are truncated
+    pub rate: f64,
+}
+
+impl Transform for Truncate {
+    fn name(&self) -> &str {
+        "truncate"
+    }
+
+    fn apply(&self, tokens: &mut Vec<LpEntryToken>, rng: &mut dyn RngCore) -> usize {
+        if tokens.len() < 2 || rng.gen::<f64>() >= self.rate {
+            return 0;
+        }
+        let keep = rng.gen_range(1..tokens.len());
+        let removed = tokens.len() - keep;
+        tokens.truncate(keep);
+        removed
+    }
+}
+
+/// Leave out one component, a run of tokens with the same label
+#[derive(Debug, Clone)]
+pub struct DropComponent {
+    /// Share of examples of two or more components that lose one
+    pub rate: f64,
+}
+
+impl Transform for DropComponent {
+    fn name(&self) -> &str {
+        "drop_component"
+    }
+
+    fn apply(&self, tokens: &mut Vec<LpEntryToken>, rng: &mut dyn RngCore) -> usize {
+        let components = components(tokens);
+        if components.len() < 2 || rng.gen::<f64>() >= self.rate {
+            return 0;
+        }
+        let (start, end) = components[rng.gen_range(0..components.len())];
+        tokens.drain(start..end);
+        end - start
+    }
+}
+
+/// Swap two neighboring components, as in `seattle 5 main st`
+#[derive(Debug, Clone)]
+pub struct SwapComponents {
+    /// Share of examples of two or more components that have two swapped
+    pub rate: f64,
+}
+
+impl Transform for SwapComponents {
+    fn name(&self) -> &str {
+        "swap_components"
+    }
+
+    fn apply(&self, tokens: &mut Vec<LpEntryToken>, rng: &mut dyn RngCore) -> usize {
+        let components = components(tokens);
+        if components.len() < 2 || rng.gen::<f64>() >= self.rate {
+            return 0;
+        }
+        let first = rng.gen_range(0..components.len() - 1);
+        let (start, middle) = components[first];
+        let end = components[first + 1].1;
+        tokens[start..end].rotate_left(middle - start);
+        end - start
+    }
+}
+
+/// Swap words for their abbreviations and abbreviations for the words
+#[derive(Debug, Clone)]
+pub struct Abbreviations {
+    /// Share of tokens that are swapped, if they can be
+    pub rate: f64,
+    /// What each word or abbreviation is swapped for
+    pub swaps: HashMap<String, String>,
+}
+
+impl Abbreviations {
+    /// Swap the words and abbreviations of `pairs`, which are listed as
+    /// `(word, abbreviation)`. A word listed more than once is abbreviated as
+    /// it's first listed, and an abbreviation of more than one word is
+    /// expanded to the first one listed.
+    pub fn new<'a, I>(rate: f64, pairs: I) -> Abbreviations
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let mut swaps = HashMap::new();
+        for (word, abbreviation) in pairs {
+            swaps
+                .entry(word.to_string())
+                .or_insert_with(|| abbreviation.to_string());
+            swaps
+                .entry(abbreviation.to_string())
+                .or_insert_with(|| word.to_string());
+        }
+        Abbreviations { rate, swaps }
+    }
+}
+
+impl Transform for Abbreviations {
+    fn name(&self) -> &str {
+        "abbreviate"
+    }
+
+    fn apply(&self, tokens: &mut Vec<LpEntryToken>, rng: &mut dyn RngCore) -> usize {
+        change_tokens(tokens, self.rate, rng, |word, _| {
+            self.swaps.get(word).cloned()
+        })
+    }
+}
+
+/// Make a typo in a word of letters: leave one out, add one, replace one or
+/// swap two
+#[derive(Debug, Clone)]
+pub struct Typos {
+    /// Share of words of three or more letters that get a typo
+    pub rate: f64,
+}
+
+impl Transform for Typos {
+    fn name(&self) -> &str {
+        "typo"
+    }
+
+    fn apply(&self, tokens: &mut Vec<LpEntryToken>, rng: &mut dyn RngCore) -> usize {
+        change_tokens(tokens, self.rate, rng, |word, rng| {
+            if word.len() < 3 || !word.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+                return None;
+            }
+            let mut letters = word.as_bytes().to_vec();
+            let position = rng.gen_range(0..letters.len());
+            let letter = rng.gen_range(b'a'..=b'z');
+            match rng.gen_range(0..4) {
+                0 => {
+                    letters.remove(position);
+                }
+                1 => letters.insert(position, letter),
+                2 => letters[position] = letter,
+                _ => {
+                    let next = (position + 1) % letters.len();
+                    letters.swap(position, next);
+                }
+            }
+            let typo = String::from_utf8(letters).unwrap();
+            (typo != word).then_some(typo)
+        })
+    }
+}
+
+/// Replace a character with one it's easily confused with, like `0` and `o`
+#[derive(Debug, Clone)]
+pub struct Confusions {
+    /// Share of tokens with a confusable character that have one replaced
+    pub rate: f64,
+}
+
+impl Transform for Confusions {
+    fn name(&self) -> &str {
+        "ocr"
+    }
+
+    fn apply(&self, tokens: &mut Vec<LpEntryToken>, rng: &mut dyn RngCore) -> usize {
+        change_tokens(tokens, self.rate, rng, |word, rng| {
+            let mut chars: Vec<char> = word.chars().collect();
+            let confusable: Vec<(usize, char)> = chars
+                .iter()
+                .enumerate()
+                .flat_map(|(position, c)| {
+                    CONFUSIONS.iter().filter_map(move |(a, b)| match *c {
+                        c if c == *a => Some((position, *b)),
+                        c if c == *b => Some((position, *a)),
+                        _ => None,
+                    })
+                })
+                .collect();
+            if confusable.is_empty() {
+                return None;
+            }
+            let (position, replacement) = confusable[rng.gen_range(0..confusable.len())];
+            chars[position] = replacement;
+            Some(chars.into_iter().collect())
+        })
+    }
+}
+
+/// Rates of the transforms that make up augmentation
+///
+/// The default only truncates examples. Casing isn't varied, since the
+/// tokenizer lowercases everything.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AugmentConfig {
+    /// Share of words swapped with their abbreviations or the other way round
+    pub abbreviate: f64,
+    /// Share of words given a typo
+    pub typo: f64,
+    /// Share of tokens given an OCR-style character confusion
+    pub ocr: f64,
+    /// Share of examples that lose a component
+    pub drop_component: f64,
+    /// Share of examples with two neighboring components swapped
+    pub swap_components: f64,
+    /// Share of examples truncated to a prefix
+    pub truncate: f64,
+    /// Abbreviations to use in addition to `DEFAULT_ABBREVIATIONS`, keyed by
+    /// the word they abbreviate. They take precedence over the defaults.
+    pub abbreviations: BTreeMap<String, String>,
+}
+
+impl Default for AugmentConfig {
+    fn default() -> Self {
+        AugmentConfig {
+            abbreviate: 0.0,
+            typo: 0.0,
+            ocr: 0.0,
+            drop_component: 0.0,
+            swap_components: 0.0,
+            truncate: 0.8,
+            abbreviations: BTreeMap::new(),
+        }
+    }
+}
+
+impl AugmentConfig {
+    /// An augmenter applying the transforms with a nonzero rate: first those
+    /// of single tokens, then those of components and finally truncation
+    pub fn augmenter(&self) -> Augmenter {
+        let mut transforms: Vec<Box<dyn Transform>> = vec![];
+        if self.abbreviate > 0.0 {
+            let pairs = self
+                .abbreviations
+                .iter()
+                .map(|(word, abbreviation)| (word.as_str(), abbreviation.as_str()))
+                .chain(DEFAULT_ABBREVIATIONS);
+            transforms.push(Box::new(Abbreviations::new(self.abbreviate, pairs)));
+        }
+        if self.typo > 0.0 {
+            transforms.push(Box::new(Typos { rate: self.typo }));
+        }
+        if self.ocr > 0.0 {
+            transforms.push(Box::new(Confusions { rate: self.ocr }));
+        }
+        if self.drop_component > 0.0 {
+            transforms.push(Box::new(DropComponent {
+                rate: self.drop_component,
+            }));
+        }
+        if self.swap_components > 0.0 {
+            transforms.push(Box::new(SwapComponents {
+                rate: self.swap_components,
+            }));
+        }
+        if self.truncate > 0.0 {
+            transforms.push(Box::new(Truncate {
+                rate: self.truncate,
+            }));
+        }
+        Augmenter::new(transforms)
+    }
+}
+
+/// Counts of what a transform changed
+#[derive(Debug, Default)]
+struct Counters {
+    examples: AtomicUsize,
+    tokens: AtomicUsize,
+}
+
+/// Applies transforms in order and counts what they change. It can be shared
+/// between threads.
+pub struct Augmenter {
+    transforms: Vec<Box<dyn Transform>>,
+    examples: AtomicUsize,
+    counters: Vec<Counters>,
+}
+
+impl Augmenter {
+    pub fn new(transforms: Vec<Box<dyn Transform>>) -> Augmenter {
+        let counters = transforms.iter().map(|_| Counters::default()).collect();
+        Augmenter {
+            transforms,
+            examples: AtomicUsize::new(0),
+            counters,
+        }
+    }
+
+    /// Add a transform to apply after the others
+    pub fn with(mut self, transform: Box<dyn Transform>) -> Augmenter {
+        self.transforms.push(transform);
+        self.counters.push(Counters::default());
+        self
+    }
+
+    /// Apply every transform to the tokens of one example
+    pub fn augment(&self, tokens: &mut Vec<LpEntryToken>, rng: &mut dyn RngCore) {
+        self.examples.fetch_add(1, Ordering::Relaxed);
+        for (transform, counters) in self.transforms.iter().zip(&self.counters) {
+            let changed = transform.apply(tokens, rng);
+            if changed > 0 {
+                counters.examples.fetch_add(1, Ordering::Relaxed);
+                counters.tokens.fetch_add(changed, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// What's been changed so far
+    pub fn stats(&self) -> AugmentStats {
+        AugmentStats {
+            examples: self.examples.load(Ordering::Relaxed),
+            transforms: self
+                .transforms
+                .iter()
+                .zip(&self.counters)
+                .map(|(transform, counters)| TransformStats {
+                    name: transform.name().to_string(),
+                    examples: counters.examples.load(Ordering::Relaxed),
+                    tokens: counters.tokens.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A random number generator for the example at `index` in a corpus, so that
+/// how it's augmented depends on `seed` and not on which thread handles it
+pub fn example_rng(seed: u64, index: u64) -> StdRng {
+    // splitmix64, to spread neighboring indexes over the seed space
+    let mut mixed = seed ^ index.wrapping_mul(0x9e3779b97f4a7c15);
+    mixed = (mixed ^ (mixed >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    mixed = (mixed ^ (mixed >> 27)).wrapping_mul(0x94d049bb133111eb);
+    StdRng::seed_from_u64(mixed ^ (mixed >> 31))
+}
+
+/// How many examples and tokens a transform changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TransformStats {
+    pub name: String,
+    pub examples: usize,
+    pub tokens: usize,
+}
+
+/// What augmentation changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AugmentStats {
+    /// Number of examples augmented
+    pub examples: usize,
+    pub transforms: Vec<TransformStats>,
+}
+
+impl fmt::Display for AugmentStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Augmented {} examples", self.examples)?;
+        for transform in &self.transforms {
+            writeln!(
+                f,
+                "  {:<16} {:>10} examples ({:.1}%), {} tokens",
+                transform.name,
+                transform.examples,
+                100.0 * transform.examples as f64 / self.examples.max(1) as f64,
+                transform.tokens
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(labeled: &[(&str, &str)]) -> Vec<LpEntryToken> {
+        labeled
+            .iter()
+            .map(|(word, label)| LpEntryToken::new(word, label))
+            .collect()
+    }
+
+    fn words(tokens: &[LpEntryToken]) -> Vec<&str> {
+        tokens
+            .iter()
+            .map(|token| token.transliterated.as_str())
+            .collect()
+    }
+
+    fn example() -> Vec<LpEntryToken> {
+        tokens(&[
+            ("5", "house_number"),
+            ("Main", "road"),
+            ("Street", "road"),
+            ("Seattle", "city"),
+        ])
+    }
+
+    #[test]
+    fn test_transforms() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut example_tokens = example();
+        let abbreviations = Abbreviations::new(1.0, DEFAULT_ABBREVIATIONS);
+        assert_eq!(abbreviations.apply(&mut example_tokens, &mut rng), 1);
+        assert_eq!(words(&example_tokens), ["5", "main", "st", "seattle"]);
+        assert_eq!(example_tokens[2].label, "road");
+        abbreviations.apply(&mut example_tokens, &mut rng);
+        assert_eq!(words(&example_tokens), ["5", "main", "street", "seattle"]);
+
+        let mut example_tokens = example();
+        assert_eq!(
+            SwapComponents { rate: 1.0 }.apply(&mut example_tokens, &mut rng),
+            3
+        );
+        assert_eq!(words(&example_tokens).len(), 4);
+        assert_ne!(words(&example_tokens), words(&example()));
+
+        let mut example_tokens = example();
+        let removed = DropComponent { rate: 1.0 }.apply(&mut example_tokens, &mut rng);
+        assert_eq!(example_tokens.len(), 4 - removed);
+        assert_eq!(components(&example_tokens).len(), 2);
+
+        let mut example_tokens = example();
+        assert_eq!(Typos { rate: 1.0 }.apply(&mut example_tokens, &mut rng), 3);
+        assert_eq!(example_tokens[0].transliterated, "5");
+
+        let mut example_tokens = tokens(&[("10", "house_number"), ("xyz", "road")]);
+        assert_eq!(
+            Confusions { rate: 1.0 }.apply(&mut example_tokens, &mut rng),
+            2
+        );
+        assert!(["l0", "i0", "1o"].contains(&example_tokens[0].transliterated.as_str()));
+        assert_eq!(example_tokens[1].transliterated, "xy2");
+
+        let mut example_tokens = example();
+        assert_eq!(
+            Truncate { rate: 0.0 }.apply(&mut example_tokens, &mut rng),
+            0
+        );
+        assert!(Truncate { rate: 1.0 }.apply(&mut example_tokens, &mut rng) > 0);
+        assert!(!example_tokens.is_empty());
+    }
+
+    #[test]
+    fn test_abbreviation_overrides() {
+        let config = AugmentConfig {
+            abbreviate: 1.0,
+            truncate: 0.0,
+            abbreviations: BTreeMap::from([("street".to_string(), "str".to_string())]),
+            ..AugmentConfig::default()
+        };
+        let augmenter = config.augmenter();
+        let mut example_tokens = tokens(&[("Main", "road"), ("Street", "road"), ("Ave", "road")]);
+        augmenter.augment(&mut example_tokens, &mut StdRng::seed_from_u64(0));
+        assert_eq!(words(&example_tokens), ["main", "str", "avenue"]);
+    }
+
+    #[test]
+    fn test_augmenter() {
+        let config = AugmentConfig {
+            typo: 0.5,
+            drop_component: 0.5,
+            ..AugmentConfig::default()
+        };
+        let augmenter = config.augmenter();
+        let augmented = |index| {
+            let mut example_tokens = example();
+            augmenter.augment(&mut example_tokens, &mut example_rng(7, index));
+            words(&example_tokens)
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        for index in 0..20 {
+            assert_eq!(augmented(index), augmented(index));
+        }
+
+        let stats = augmenter.stats();
+        assert_eq!(stats.examples, 40);
+        let names: Vec<&str> = stats
+            .transforms
+            .iter()
+            .map(|transform| transform.name.as_str())
+            .collect();
+        assert_eq!(names, ["typo", "drop_component", "truncate"]);
+        assert!(stats
+            .transforms
+            .iter()
+            .all(|transform| transform.examples > 0));
+        assert!(stats.to_string().starts_with("Augmented 40 examples"));
+    }
+}
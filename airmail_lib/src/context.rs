@@ -23,6 +23,15 @@ bitflags! {
     }
 }
 
+/// An entry in the n-best Viterbi lattice: the score of one of the best paths
+/// arriving at a node, and where that path came from.
+#[derive(Debug, Clone, Copy)]
+struct NBestEntry {
+    score: f64,
+    prev_label: u32,
+    prev_rank: u32,
+}
+
 /// Context maintains internal data for an instance
 #[derive(Debug, Clone, Default)]
 pub struct Context {
@@ -97,6 +106,16 @@ pub struct Context {
     /// expectation of the transition (i--j).
     /// This member is available only with `CTXF_MARGINALS` flag.
     mexp_trans: Vec<f64>,
+    /// Allowed states
+    ///
+    /// This is a `[T][L]` matrix whose element `[t][l]` is false if label #l
+    /// may not be assigned to item #t. Empty when decoding is unconstrained.
+    state_mask: Vec<bool>,
+    /// Allowed transitions
+    ///
+    /// This is a `[L][L]` matrix whose element `[i][j]` is false if label #j
+    /// may not follow label #i. Empty when decoding is unconstrained.
+    trans_mask: Vec<bool>,
 }
 
 impl Context {
@@ -168,46 +187,267 @@ impl Context {
         }
     }
 
+    /// Compute the exponents of the state scores, which the forward-backward
+    /// algorithm works with.
+    pub fn exp_state(&mut self) {
+        let l = self.num_labels as usize;
+        let t = self.num_items as usize;
+        self.exp_state[..t * l].copy_from_slice(&self.state[..t * l]);
+        for i in 0..(t * l) {
+            self.exp_state[i] = self.exp_state[i].exp();
+        }
+    }
+
+    /// Compute the scaled forward (alpha) scores along with the log of the
+    /// normalization factor.
+    pub fn alpha_score(&mut self) {
+        let l = self.num_labels as usize;
+        let t = self.num_items as usize;
+        // Compute the alpha scores on nodes (0, *)
+        let current = &mut self.alpha_score[..l];
+        current.copy_from_slice(&self.exp_state[..l]);
+        let sum: f64 = current.iter().sum();
+        self.scale_factor[0] = if sum != 0.0 { 1.0 / sum } else { 1.0 };
+        current.iter_mut().for_each(|v| *v *= self.scale_factor[0]);
+        // Compute the alpha scores on nodes (t, *)
+        for t in 1..t {
+            let (prev, current) = self.alpha_score.split_at_mut(l * t);
+            let prev = &prev[l * (t - 1)..];
+            let current = &mut current[..l];
+            let state = &self.exp_state[l * t..l * (t + 1)];
+            current.fill(0.0);
+            for (i, prev_value) in prev.iter().enumerate() {
+                let trans = &self.exp_trans[l * i..l * (i + 1)];
+                for (j, current_value) in current.iter_mut().enumerate() {
+                    *current_value += prev_value * trans[j];
+                }
+            }
+            for (current_value, state_value) in current.iter_mut().zip(state) {
+                *current_value *= state_value;
+            }
+            let sum: f64 = current.iter().sum();
+            self.scale_factor[t] = if sum != 0.0 { 1.0 / sum } else { 1.0 };
+            current.iter_mut().for_each(|v| *v *= self.scale_factor[t]);
+        }
+        // Compute the logarithm of the normalization factor here.
+        // norm = 1. / (C[0] * C[1] ... * C[T-1])
+        // log(norm) = - \sum_{t = 0}^{T-1} log(C[t]).
+        self.log_norm = -self.scale_factor[..t].iter().map(|c| c.ln()).sum::<f64>();
+    }
+
+    /// Compute the scaled backward (beta) scores. Must be called after
+    /// `alpha_score`, since it reuses the scale factors computed there.
+    pub fn beta_score(&mut self) {
+        let l = self.num_labels as usize;
+        let t = self.num_items as usize;
+        // Compute the beta scores at (T-1, *)
+        let current = &mut self.beta_score[l * (t - 1)..l * t];
+        current.fill(self.scale_factor[t - 1]);
+        // Compute the beta scores at (t, *)
+        for t in (0..(t - 1)).rev() {
+            let (current, next) = self.beta_score.split_at_mut(l * (t + 1));
+            let current = &mut current[l * t..];
+            let next = &next[..l];
+            let state = &self.exp_state[l * (t + 1)..l * (t + 2)];
+            // row[i] = beta[t+1][i] * exp_state[t+1][i]
+            for ((row_value, next_value), state_value) in self.row.iter_mut().zip(next).zip(state) {
+                *row_value = next_value * state_value;
+            }
+            // beta[t][i] = \sum_j exp_trans[i][j] * row[j]
+            for (i, current_value) in current.iter_mut().enumerate() {
+                let trans = &self.exp_trans[l * i..l * (i + 1)];
+                *current_value = trans
+                    .iter()
+                    .zip(self.row.iter())
+                    .map(|(trans_value, row_value)| trans_value * row_value)
+                    .sum::<f64>()
+                    * self.scale_factor[t];
+            }
+        }
+    }
+
+    /// Compute the model expectations (marginal probabilities) of states and
+    /// transitions. Must be called after `alpha_score` and `beta_score`.
+    pub fn marginals(&mut self) {
+        let l = self.num_labels as usize;
+        let t = self.num_items as usize;
+        // Compute the model expectations of states.
+        // p(t,i) = fwd[t][i] * bwd[t][i] / norm
+        //        = (1. / C[t]) * fwd'[t][i] * bwd'[t][i]
+        for t in 0..t {
+            let fwd = &self.alpha_score[l * t..l * (t + 1)];
+            let bwd = &self.beta_score[l * t..l * (t + 1)];
+            let prob = &mut self.mexp_state[l * t..l * (t + 1)];
+            for i in 0..l {
+                prob[i] = fwd[i] * bwd[i] / self.scale_factor[t];
+            }
+        }
+        // Compute the model expectations of transitions.
+        // p(t,i,t+1,j)
+        //     = fwd[t][i] * edge[i][j] * state[t+1][j] * bwd[t+1][j] / norm
+        //     = (fwd'[t][i] / (C[0] ... C[t])) * edge[i][j] * state[t+1][j] * (bwd'[t+1][j] / (C[t+1] ... C[T-1])) * (C[0] * ... * C[T-1])
+        //     = fwd'[t][i] * edge[i][j] * state[t+1][j] * bwd'[t+1][j]
+        // The model expectation of a transition is the sum over t.
+        self.mexp_trans[..l * l].fill(0.0);
+        for t in 0..(t - 1) {
+            let fwd = &self.alpha_score[l * t..l * (t + 1)];
+            let state = &self.exp_state[l * (t + 1)..l * (t + 2)];
+            let bwd = &self.beta_score[l * (t + 1)..l * (t + 2)];
+            for ((row_value, bwd_value), state_value) in self.row.iter_mut().zip(bwd).zip(state) {
+                *row_value = bwd_value * state_value;
+            }
+            for (i, fwd_value) in fwd.iter().enumerate() {
+                let edge = &self.exp_trans[l * i..l * (i + 1)];
+                let prob = &mut self.mexp_trans[l * i..l * (i + 1)];
+                for j in 0..l {
+                    prob[j] += fwd_value * edge[j] * self.row[j];
+                }
+            }
+        }
+    }
+
+    /// Marginal probability of label #l at position #t. Only meaningful after
+    /// `alpha_score` and `beta_score`.
+    pub fn marginal_point(&self, l: u32, t: u32) -> f64 {
+        let num_labels = self.num_labels as usize;
+        let (l, t) = (l as usize, t as usize);
+        let fwd = self.alpha_score[num_labels * t + l];
+        let bwd = self.beta_score[num_labels * t + l];
+        fwd * bwd / self.scale_factor[t]
+    }
+
+    /// Marginal probability of every label at position #t, indexed by label.
+    /// Only meaningful after `marginals`.
+    pub fn state_marginals(&self, t: u32) -> &[f64] {
+        let l = self.num_labels as usize;
+        let t = t as usize;
+        &self.mexp_state[l * t..l * (t + 1)]
+    }
+
+    /// Expected number of times each transition occurs in the instance, as a
+    /// `[L][L]` matrix. Only meaningful after `marginals`.
+    pub fn transition_marginals(&self) -> &[f64] {
+        let l = self.num_labels as usize;
+        &self.mexp_trans[..l * l]
+    }
+
+    /// Marginal probability of the partial label sequence `path` starting at
+    /// position `begin`. Only meaningful after `alpha_score` and `beta_score`.
+    pub fn marginal_path(&self, path: &[u32], begin: u32) -> f64 {
+        let l = self.num_labels as usize;
+        let begin = begin as usize;
+        let end = begin + path.len();
+        // Compute the marginal probability of the path:
+        //     fwd'[begin][path[0]] * bwd'[end-1][path[end-1]] / C[begin] * ...
+        let fwd = &self.alpha_score[l * begin..];
+        let bwd = &self.beta_score[l * (end - 1)..];
+        let mut prob =
+            fwd[path[0] as usize] * bwd[path[path.len() - 1] as usize] / self.scale_factor[begin];
+        for (t, window) in (begin..end).zip(path.windows(2)) {
+            let edge = &self.exp_trans[l * window[0] as usize..];
+            let state = &self.exp_state[l * (t + 1)..];
+            prob *= edge[window[1] as usize] * state[window[1] as usize] * self.scale_factor[t];
+        }
+        prob
+    }
+
+    /// Total (unnormalized) score of a label sequence
+    pub fn score(&self, labels: &[u32]) -> f64 {
+        let l = self.num_labels as usize;
+        let mut prev = labels[0] as usize;
+        // Stay at (0, labels[0])
+        let mut ret = self.state[prev];
+        // Loop over the rest of items
+        for (t, cur) in labels.iter().enumerate().skip(1) {
+            let cur = *cur as usize;
+            // Transit from (t-1, labels[t-1]) to (t, labels[t])
+            ret += self.trans[l * prev + cur];
+            // Stay at (t, labels[t])
+            ret += self.state[l * t + cur];
+            prev = cur;
+        }
+        ret
+    }
+
+    /// Logarithm of the normalization factor (partition function) for the
+    /// instance. Only meaningful after `alpha_score`.
+    pub fn lognorm(&self) -> f64 {
+        self.log_norm
+    }
+
+    /// Restrict the paths searched by `viterbi` and `viterbi_nbest`. An empty
+    /// mask leaves the corresponding scores unconstrained; otherwise
+    /// `state_mask` must be `[T][L]` and `trans_mask` `[L][L]`.
+    pub fn set_constraints(&mut self, state_mask: Vec<bool>, trans_mask: Vec<bool>) {
+        self.state_mask = state_mask;
+        self.trans_mask = trans_mask;
+    }
+
+    /// Remove any constraints set by `set_constraints`.
+    pub fn clear_constraints(&mut self) {
+        self.state_mask.clear();
+        self.trans_mask.clear();
+    }
+
+    #[inline]
+    fn state_allowed(&self, t: usize, j: usize) -> bool {
+        self.state_mask.is_empty() || self.state_mask[self.num_labels as usize * t + j]
+    }
+
+    #[inline]
+    fn trans_allowed(&self, i: usize, j: usize) -> bool {
+        self.trans_mask.is_empty() || self.trans_mask[self.num_labels as usize * i + j]
+    }
+
+    /// Find the best label sequence and its score. The score is negative
+    /// infinity if the constraints leave no path through the lattice.
     pub fn viterbi(&mut self) -> (Vec<u32>, f64) {
         let mut score;
         let l = self.num_labels as usize;
         // Compute the scores at (0, *)
-        let current = &mut self.alpha_score;
-        let state = &mut self.state;
-        current[..l].clone_from_slice(&state[..l]);
+        for j in 0..l {
+            self.alpha_score[j] = if self.state_allowed(0, j) {
+                self.state[j]
+            } else {
+                f64::NEG_INFINITY
+            };
+        }
         // Compute the scores at (t, *)
         for t in 1..self.num_items as usize {
-            let (prev, current) = self.alpha_score.split_at_mut(l * t);
-            let prev = &prev[l * (t - 1)..];
-            let state = &self.state[l * t..];
-            let back = &mut self.backward_edge[l * t..];
             // Compute the score of (t, j)
             for j in 0..l {
-                let mut max_score = f64::MIN;
+                let mut max_score = f64::NEG_INFINITY;
                 let mut argmax_score = None;
-                for (i, prev_value) in prev.iter().enumerate().take(l) {
-                    // Transit from (t-1, i) to (t, j)
-                    let trans = &self.trans[l * i..];
-                    score = prev_value + trans[j];
-                    // Store this path if it has the maximum score
-                    if max_score < score {
-                        max_score = score;
-                        argmax_score = Some(i);
+                if self.state_allowed(t, j) {
+                    let prev = &self.alpha_score[l * (t - 1)..l * t];
+                    for (i, prev_value) in prev.iter().enumerate() {
+                        if !self.trans_allowed(i, j) {
+                            continue;
+                        }
+                        // Transit from (t-1, i) to (t, j)
+                        score = prev_value + self.trans[l * i + j];
+                        // Store this path if it has the maximum score
+                        if max_score < score {
+                            max_score = score;
+                            argmax_score = Some(i);
+                        }
                     }
                 }
                 // Backward link (#t, #j) -> (#t-1, #i)
                 if let Some(argmax_score) = argmax_score {
-                    back[j] = argmax_score as u32;
+                    self.backward_edge[l * t + j] = argmax_score as u32;
+                    // Add the state score on (t, j)
+                    self.alpha_score[l * t + j] = max_score + self.state[l * t + j];
+                } else {
+                    self.alpha_score[l * t + j] = f64::NEG_INFINITY;
                 }
-                // Add the state score on (t, j)
-                current[j] = max_score + state[j];
             }
         }
         // Find the node (#T, Ei) that reaches EOS with the maximum score
-        let mut max_score = f64::MIN;
+        let mut max_score = f64::NEG_INFINITY;
         let prev = &self.alpha_score[l * (self.num_items as usize - 1)..];
         // Set a score for T-1 to be overwritten later. Just in case we don't
-        // end up with something beating f64::MIN.
+        // end up with something beating negative infinity.
         let mut labels = vec![0u32; self.num_items as usize];
         for (i, prev_value) in prev.iter().enumerate().take(l) {
             if max_score < *prev_value {
@@ -216,6 +456,9 @@ impl Context {
                 labels[self.num_items as usize - 1] = i as u32;
             }
         }
+        if max_score == f64::NEG_INFINITY {
+            return (labels, max_score);
+        }
         // Tag labels by tracing teh backward links
         for t in (0..(self.num_items as usize - 1)).rev() {
             let back = &self.backward_edge[l * (t + 1)..];
@@ -223,6 +466,95 @@ impl Context {
         }
         (labels, max_score)
     }
+
+    /// Find the `k` best label sequences, best first. Every returned sequence
+    /// is distinct; fewer than `k` are returned if the lattice doesn't
+    /// contain that many paths.
+    pub fn viterbi_nbest(&self, k: usize) -> Vec<(Vec<u32>, f64)> {
+        let l = self.num_labels as usize;
+        let num_items = self.num_items as usize;
+        if k == 0 || num_items == 0 {
+            return Vec::new();
+        }
+        // lattice[t * l + j] holds the best (up to k) paths arriving at (t, j),
+        // best first.
+        let mut lattice: Vec<Vec<NBestEntry>> = Vec::with_capacity(num_items * l);
+        // Compute the scores at (0, *)
+        for j in 0..l {
+            if self.state_allowed(0, j) {
+                lattice.push(vec![NBestEntry {
+                    score: self.state[j],
+                    prev_label: 0,
+                    prev_rank: 0,
+                }]);
+            } else {
+                lattice.push(vec![]);
+            }
+        }
+        // Compute the scores at (t, *)
+        let mut candidates = Vec::with_capacity(k * l);
+        for t in 1..num_items {
+            let state = &self.state[l * t..l * (t + 1)];
+            for (j, state_value) in state.iter().enumerate() {
+                candidates.clear();
+                if !self.state_allowed(t, j) {
+                    lattice.push(vec![]);
+                    continue;
+                }
+                for i in 0..l {
+                    if !self.trans_allowed(i, j) {
+                        continue;
+                    }
+                    // Transit from each of the best paths at (t-1, i) to (t, j)
+                    let trans = self.trans[l * i + j];
+                    for (rank, prev) in lattice[l * (t - 1) + i].iter().enumerate() {
+                        candidates.push(NBestEntry {
+                            score: prev.score + trans + state_value,
+                            prev_label: i as u32,
+                            prev_rank: rank as u32,
+                        });
+                    }
+                }
+                candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+                candidates.truncate(k);
+                lattice.push(candidates.clone());
+            }
+        }
+        // Find the k best nodes (#T, Ei, rank) that reach EOS
+        let last = &lattice[l * (num_items - 1)..];
+        let mut finals: Vec<(u32, u32, f64)> = last
+            .iter()
+            .enumerate()
+            .flat_map(|(j, entries)| {
+                entries
+                    .iter()
+                    .enumerate()
+                    .map(move |(rank, entry)| (j as u32, rank as u32, entry.score))
+            })
+            .collect();
+        finals.sort_by(|a, b| b.2.total_cmp(&a.2));
+        finals.truncate(k);
+        // Tag labels by tracing the backward links of each path
+        finals
+            .into_iter()
+            .map(|(label, rank, score)| {
+                let mut labels = vec![0u32; num_items];
+                let (mut label, mut rank) = (label, rank);
+                for t in (0..num_items).rev() {
+                    labels[t] = label;
+                    let entry = lattice[l * t + label as usize][rank as usize];
+                    label = entry.prev_label;
+                    rank = entry.prev_rank;
+                }
+                (labels, score)
+            })
+            .collect()
+    }
+
+    /// Whether marginal probabilities can be computed with this context
+    pub fn has_marginals(&self) -> bool {
+        self.flag.contains(Flag::MARGINALS)
+    }
 }
 
 #[cfg(test)]
@@ -243,4 +575,157 @@ mod tests {
         ctx.reset(Reset::TRANS);
         ctx.reset(Reset::STATE | Reset::TRANS);
     }
+
+    fn random_context(l: u32, t: u32) -> Context {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut ctx = Context::new(Flag::VITERBI | Flag::MARGINALS, l, t);
+        ctx.set_num_items(t);
+        ctx.reset(Reset::ALL);
+        ctx.state
+            .iter_mut()
+            .for_each(|v| *v = rng.gen_range(-2.0..2.0));
+        ctx.trans
+            .iter_mut()
+            .for_each(|v| *v = rng.gen_range(-2.0..2.0));
+        ctx.exp_transition();
+        ctx
+    }
+
+    fn all_paths(l: u32, t: u32) -> Vec<Vec<u32>> {
+        (0..l.pow(t))
+            .map(|mut n| {
+                (0..t)
+                    .map(|_| {
+                        let label = n % l;
+                        n /= l;
+                        label
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_forward_backward_matches_brute_force() {
+        let (l, t) = (3, 4);
+        let mut ctx = random_context(l, t);
+        ctx.exp_state();
+        ctx.alpha_score();
+        ctx.beta_score();
+        ctx.marginals();
+
+        let paths = all_paths(l, t);
+        let norm: f64 = paths.iter().map(|path| ctx.score(path).exp()).sum();
+        assert!((ctx.lognorm() - norm.ln()).abs() < 1e-9);
+
+        for pos in 0..t {
+            let mut total = 0.0;
+            for label in 0..l {
+                let expected: f64 = paths
+                    .iter()
+                    .filter(|path| path[pos as usize] == label)
+                    .map(|path| ctx.score(path).exp() / norm)
+                    .sum();
+                assert!((ctx.marginal_point(label, pos) - expected).abs() < 1e-9);
+                assert!((ctx.state_marginals(pos)[label as usize] - expected).abs() < 1e-9);
+                total += ctx.marginal_point(label, pos);
+            }
+            assert!((total - 1.0).abs() < 1e-9);
+        }
+
+        let expected: f64 = paths
+            .iter()
+            .filter(|path| path[1] == 2 && path[2] == 0)
+            .map(|path| ctx.score(path).exp() / norm)
+            .sum();
+        assert!((ctx.marginal_path(&[2, 0], 1) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_viterbi_nbest_matches_brute_force() {
+        let (l, t) = (3, 4);
+        let ctx = random_context(l, t);
+        let mut expected: Vec<(Vec<u32>, f64)> = all_paths(l, t)
+            .into_iter()
+            .map(|path| {
+                let score = ctx.score(&path);
+                (path, score)
+            })
+            .collect();
+        expected.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let nbest = ctx.viterbi_nbest(5);
+        assert_eq!(nbest.len(), 5);
+        for ((labels, score), (expected_labels, expected_score)) in nbest.iter().zip(&expected) {
+            assert!((score - expected_score).abs() < 1e-9);
+            assert_eq!(labels, expected_labels);
+        }
+
+        // Asking for more paths than exist returns every path exactly once.
+        let everything = ctx.viterbi_nbest(1000);
+        assert_eq!(everything.len(), expected.len());
+    }
+
+    #[test]
+    fn test_constrained_viterbi() {
+        let (l, t) = (3, 4);
+        let mut ctx = random_context(l, t);
+        // Pin item #1 to label #2, forbid label #0 at item #3 and forbid the
+        // transition from #1 to #1.
+        let mut state_mask = vec![true; (l * t) as usize];
+        state_mask[3..6].copy_from_slice(&[false, false, true]);
+        state_mask[9] = false;
+        let mut trans_mask = vec![true; (l * l) as usize];
+        trans_mask[4] = false;
+        let consistent = |path: &Vec<u32>| {
+            path.iter()
+                .enumerate()
+                .all(|(pos, label)| state_mask[pos * l as usize + *label as usize])
+                && path
+                    .windows(2)
+                    .all(|w| trans_mask[(w[0] * l + w[1]) as usize])
+        };
+        let mut expected: Vec<(Vec<u32>, f64)> = all_paths(l, t)
+            .into_iter()
+            .filter(consistent)
+            .map(|path| {
+                let score = ctx.score(&path);
+                (path, score)
+            })
+            .collect();
+        expected.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ctx.set_constraints(state_mask.clone(), trans_mask.clone());
+        let (labels, score) = ctx.viterbi();
+        assert_eq!(labels, expected[0].0);
+        assert!((score - expected[0].1).abs() < 1e-9);
+        let nbest = ctx.viterbi_nbest(1000);
+        assert_eq!(nbest.len(), expected.len());
+        assert!(nbest.iter().all(|(path, _score)| consistent(path)));
+
+        // Forbidding every label at one position leaves no path.
+        let mut impossible = vec![true; (l * t) as usize];
+        impossible[6..9].fill(false);
+        ctx.set_constraints(impossible, vec![]);
+        assert_eq!(ctx.viterbi().1, f64::NEG_INFINITY);
+        assert!(ctx.viterbi_nbest(3).is_empty());
+
+        ctx.clear_constraints();
+        assert_eq!(ctx.viterbi_nbest(1000).len(), (l as usize).pow(t));
+    }
+
+    #[test]
+    fn test_viterbi_finds_best_path() {
+        let (l, t) = (3, 4);
+        let mut ctx = random_context(l, t);
+        let (labels, score) = ctx.viterbi();
+        let best = all_paths(l, t)
+            .into_iter()
+            .map(|path| ctx.score(&path))
+            .fold(f64::MIN, f64::max);
+        assert!((score - best).abs() < 1e-9);
+        assert!((ctx.score(&labels) - best).abs() < 1e-9);
+    }
 }
@@ -0,0 +1,106 @@
+use std::{fmt, io};
+
+use crate::packed::FormatVersion;
+
+/// Errors that can occur while loading a model or tagging with it
+#[derive(Debug)]
+pub enum Error {
+    /// The packed model could not be deserialized
+    Deserialize(bincode2::Error),
+    /// The attribute vocabulary FST is corrupt
+    Vocab(fst::Error),
+    /// The packed model is internally inconsistent
+    InvalidModel(String),
+    /// The packed model was written in a format version this crate can't read
+    IncompatibleVersion {
+        found: FormatVersion,
+        supported: FormatVersion,
+    },
+    /// A section of the packed model doesn't match its checksum
+    ChecksumMismatch { section: u32 },
+    /// A label id that the model does not have
+    UnknownLabelId(u32),
+    /// A label string that the model does not have
+    UnknownLabel(String),
+    /// An attribute id that the model does not have
+    UnknownAttributeId(u32),
+    /// A feature id that the model does not have
+    UnknownFeature(u32),
+    /// A feature points at a label outside of the model's label set
+    LabelOutOfRange { target: u32, num_labels: u32 },
+    /// A method that needs an instance was called before `Tagger::set`
+    NoInstance,
+    /// The arguments passed to a method don't fit the current instance
+    InvalidInput(String),
+    /// No label sequence satisfies the decoding constraints
+    Unsatisfiable,
+    /// An I/O error
+    Io(io::Error),
+}
+
+/// Result type for fallible operations in this crate
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Deserialize(err) => write!(f, "failed to deserialize packed model: {}", err),
+            Error::Vocab(err) => write!(f, "invalid attribute vocabulary: {}", err),
+            Error::InvalidModel(reason) => write!(f, "invalid model: {}", reason),
+            Error::IncompatibleVersion { found, supported } => write!(
+                f,
+                "model format version {} is incompatible with supported version {}",
+                found, supported
+            ),
+            Error::ChecksumMismatch { section } => {
+                write!(
+                    f,
+                    "section {} of the model is corrupt, checksum mismatch",
+                    section
+                )
+            }
+            Error::UnknownLabelId(id) => write!(f, "unknown label id {}", id),
+            Error::UnknownLabel(label) => write!(f, "unknown label `{}`", label),
+            Error::UnknownAttributeId(id) => write!(f, "unknown attribute id {}", id),
+            Error::UnknownFeature(id) => write!(f, "unknown feature id {}", id),
+            Error::LabelOutOfRange { target, num_labels } => write!(
+                f,
+                "feature targets label {} but the model only has {} labels",
+                target, num_labels
+            ),
+            Error::NoInstance => write!(f, "no instance has been set for the tagger"),
+            Error::InvalidInput(reason) => write!(f, "invalid input: {}", reason),
+            Error::Unsatisfiable => write!(f, "no label sequence satisfies the constraints"),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Deserialize(err) => Some(err),
+            Error::Vocab(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<bincode2::Error> for Error {
+    fn from(err: bincode2::Error) -> Self {
+        Error::Deserialize(err)
+    }
+}
+
+impl From<fst::Error> for Error {
+    fn from(err: fst::Error) -> Self {
+        Error::Vocab(err)
+    }
+}
@@ -0,0 +1,310 @@
+use std::{fmt, io::Write, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dataset::Attribute,
+    template::{BaseKey, FeatureTemplates, TemplateAttribute},
+    tokenizer::{Token, Tokenizer},
+};
+
+/// An attribute of a token, as produced by a `FeatureExtractor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeKey<'a> {
+    /// An attribute the tokenizer has already resolved to an id, such as a
+    /// word piece or the digit-count feature `D:n`
+    Id(u32),
+    /// A template attribute, which is looked up by name
+    Template(TemplateAttribute<'a>),
+}
+
+/// How the value of a token's own attributes is chosen
+///
+/// A state feature contributes its weight times the attribute's value, so the
+/// value decides how strongly each attribute speaks for the token. Digit
+/// counts and template attributes are always valued at one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AttributeValues {
+    /// Value each attribute by its id. Models packed before the scheme was
+    /// recorded were trained this way, which made a piece's influence depend
+    /// on where it sorts in the vocabulary.
+    Id,
+    /// Value every attribute at one
+    Binary,
+    /// Value each piece by the fraction of the token's text it covers
+    PieceLength,
+    /// Value each piece by its share of the probability of the token's best
+    /// segmentations
+    SegmentationProbability,
+}
+
+impl AttributeValues {
+    /// Value of an attribute known only by its id
+    pub fn id_value(&self, id: u32) -> f64 {
+        match self {
+            AttributeValues::Id => id as f64,
+            _ => 1.0,
+        }
+    }
+
+    /// Value of the token's piece at `index`
+    fn piece_value(&self, token: &Token, index: usize) -> f64 {
+        match self {
+            AttributeValues::Id => token.features[index] as f64,
+            AttributeValues::Binary => 1.0,
+            AttributeValues::PieceLength => {
+                token.piece_spans[index].len() as f64 / token.text.len() as f64
+            }
+            AttributeValues::SegmentationProbability => token.piece_probabilities[index],
+        }
+    }
+}
+
+impl fmt::Display for AttributeValues {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AttributeValues::Id => "id",
+            AttributeValues::Binary => "binary",
+            AttributeValues::PieceLength => "piece-length",
+            AttributeValues::SegmentationProbability => "segmentation-probability",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for AttributeValues {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(AttributeValues::Id),
+            "binary" => Ok(AttributeValues::Binary),
+            "piece-length" => Ok(AttributeValues::PieceLength),
+            "segmentation-probability" => Ok(AttributeValues::SegmentationProbability),
+            _ => Err(format!(
+                "unknown attribute values {:?}, expected binary, piece-length, \
+                 segmentation-probability or id (legacy)",
+                s
+            )),
+        }
+    }
+}
+
+/// Turns text into CRF attributes
+///
+/// Training and inference both go through an extractor, so that the model
+/// sees the same attributes for the same text in both.
+pub trait FeatureExtractor {
+    /// Split a query into the tokens to tag.
+    fn tokenize(&self, tokenizer: &Tokenizer, query: &str) -> Vec<Token>;
+
+    /// The token for one labeled word of training data, or `None` if the word
+    /// doesn't make a single token and should be left out.
+    fn labeled_token(&self, tokenizer: &Tokenizer, word: &str) -> Option<Token>;
+
+    /// Call `emit` with the token position, key and value of every attribute
+    /// of every token.
+    fn extract<'a>(&self, tokens: &'a [Token], emit: &mut dyn FnMut(usize, AttributeKey<'a>, f64));
+
+    /// Attributes of every token, resolved to ids of the tokenizer's
+    /// vocabulary. Attributes that aren't in the vocabulary are left out.
+    fn attributes(&self, tokenizer: &Tokenizer, tokens: &[Token]) -> Vec<Vec<Attribute>> {
+        let mut attributes = vec![vec![]; tokens.len()];
+        let mut key = Vec::new();
+        self.extract(tokens, &mut |position, attribute, value| {
+            let id = match attribute {
+                AttributeKey::Id(id) => Some(id),
+                AttributeKey::Template(template_attribute) => {
+                    key.clear();
+                    write!(key, "{}", template_attribute).unwrap();
+                    tokenizer.attribute_id(&key)
+                }
+            };
+            if let Some(id) = id {
+                attributes[position].push(Attribute::new(id, value));
+            }
+        });
+        attributes
+    }
+}
+
+/// The standard feature extractor, which is stored in packed models
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtractorConfig {
+    /// Give numeric tokens only their digit-count attribute and drop their
+    /// word pieces
+    pub digits_only: bool,
+    /// How the token's own attributes are valued
+    pub values: AttributeValues,
+    /// Number of best segmentations of each word that pieces are taken from
+    pub max_segmentations: u32,
+    /// Templates for attributes describing each token's context
+    pub templates: FeatureTemplates,
+}
+
+impl Default for ExtractorConfig {
+    /// Models packed before the extractor was recorded were trained with
+    /// `digits_only`, `Id` values and no templates.
+    fn default() -> Self {
+        ExtractorConfig {
+            digits_only: true,
+            values: AttributeValues::Id,
+            max_segmentations: 1,
+            templates: FeatureTemplates::default(),
+        }
+    }
+}
+
+impl ExtractorConfig {
+    /// Number of the token's word pieces that make attributes
+    fn piece_count(&self, token: &Token) -> usize {
+        if self.digits_only && token.digit_count.is_some() {
+            0
+        } else {
+            token.piece_spans.len()
+        }
+    }
+}
+
+impl FeatureExtractor for ExtractorConfig {
+    fn tokenize(&self, tokenizer: &Tokenizer, query: &str) -> Vec<Token> {
+        tokenizer.tokenize_with_spans(query)
+    }
+
+    fn labeled_token(&self, tokenizer: &Tokenizer, word: &str) -> Option<Token> {
+        let word: String = word.split_ascii_whitespace().collect();
+        let mut tokens = tokenizer.tokenize_with_spans(&word);
+        if tokens.len() != 1 {
+            return None;
+        }
+        tokens.pop()
+    }
+
+    fn extract<'a>(&self, tokens: &'a [Token], emit: &mut dyn FnMut(usize, AttributeKey<'a>, f64)) {
+        for (position, token) in tokens.iter().enumerate() {
+            // The tokenizer lists the piece ids first and the digit-count id
+            // last.
+            for index in 0..self.piece_count(token) {
+                let id = token.features[index];
+                let value = self.values.piece_value(token, index);
+                emit(position, AttributeKey::Id(id), value);
+            }
+            for id in &token.features[token.piece_spans.len()..] {
+                emit(position, AttributeKey::Id(*id), self.values.id_value(*id));
+            }
+        }
+        if self.templates.is_empty() {
+            return;
+        }
+        let base_keys: Vec<Vec<BaseKey>> = tokens
+            .iter()
+            .map(|token| {
+                let mut keys = token.base_keys();
+                keys.drain(..token.piece_spans.len() - self.piece_count(token));
+                keys
+            })
+            .collect();
+        for position in 0..tokens.len() {
+            self.templates
+                .apply(&base_keys, position, |template_attribute| {
+                    emit(position, AttributeKey::Template(template_attribute), 1.0)
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fst::SetBuilder;
+
+    #[test]
+    fn test_training_and_inference_agree() {
+        let mut keys = vec!["12", "main", "st"];
+        keys.sort();
+        let mut builder = SetBuilder::memory();
+        builder.extend_iter(keys).unwrap();
+        let tokenizer = Tokenizer::new(builder.into_set().as_fst());
+        let extractor = ExtractorConfig {
+            templates: FeatureTemplates {
+                neighbors: vec![-1],
+                ..FeatureTemplates::default()
+            },
+            ..ExtractorConfig::default()
+        };
+
+        let names = |tokens: &[Token]| {
+            let mut names = vec![vec![]; tokens.len()];
+            extractor.extract(tokens, &mut |position, attribute, _value| {
+                names[position].push(match attribute {
+                    AttributeKey::Id(id) => tokenizer.stringify_feature(id),
+                    AttributeKey::Template(attribute) => attribute.to_string(),
+                })
+            });
+            names
+        };
+        let query = extractor.tokenize(&tokenizer, "12 main st");
+        let labeled: Vec<Token> = ["12", "main st"]
+            .iter()
+            .filter_map(|word| extractor.labeled_token(&tokenizer, word))
+            .collect();
+        assert_eq!(
+            names(&query),
+            vec![vec!["D:2"], vec!["main", "W-1:D:2"], vec!["st", "W-1:main"]]
+        );
+        // Training words are joined before they're tokenized.
+        assert_eq!(
+            names(&labeled),
+            vec![vec!["D:2"], vec!["main", "st", "W-1:D:2"]]
+        );
+    }
+
+    #[test]
+    fn test_attribute_values() {
+        let mut builder = SetBuilder::memory();
+        builder.extend_iter(["main", "st"]).unwrap();
+        let tokenizer = Tokenizer::new(builder.into_set().as_fst());
+        let tokens = tokenizer.tokenize_with_spans("mainst 12");
+        let values = |values: AttributeValues| {
+            let extractor = ExtractorConfig {
+                values,
+                ..ExtractorConfig::default()
+            };
+            let mut attributes = vec![];
+            extractor.extract(&tokens, &mut |_position, attribute, value| {
+                attributes.push((attribute, value))
+            });
+            attributes
+        };
+        let digits = AttributeKey::Id(tokenizer.digit_feature(2));
+        assert_eq!(
+            values(AttributeValues::Binary),
+            vec![
+                (AttributeKey::Id(0), 1.0),
+                (AttributeKey::Id(1), 1.0),
+                (digits, 1.0)
+            ]
+        );
+        assert_eq!(
+            values(AttributeValues::PieceLength),
+            vec![
+                (AttributeKey::Id(0), 4.0 / 6.0),
+                (AttributeKey::Id(1), 2.0 / 6.0),
+                (digits, 1.0)
+            ]
+        );
+        assert_eq!(values(AttributeValues::Id)[1], (AttributeKey::Id(1), 1.0));
+        for values in [
+            AttributeValues::Id,
+            AttributeValues::Binary,
+            AttributeValues::PieceLength,
+            AttributeValues::SegmentationProbability,
+        ] {
+            assert_eq!(values.to_string().parse(), Ok(values));
+        }
+        assert!("sparse"
+            .parse::<AttributeValues>()
+            .unwrap_err()
+            .contains("id (legacy)"));
+    }
+}
@@ -1,16 +1,97 @@
-#[derive(Debug, Clone)]
+use crate::packed::WordFormat;
+use crate::quantize::Codebook;
+
+/// A single weight in a feature table
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Feature {
-    pub r#type: u32,
-    pub source: u32,
+    /// Label the feature points at
     pub target: u32,
-    pub weight: f64,
+    /// Weight of the feature
+    pub weight: f32,
 }
 
 /// Feature references
 ///
-/// This is a collection of feature ids used for faster accesses.
-#[derive(Debug, Clone)]
-pub struct FeatureRefs {
-    pub num_features: u32,
-    pub feature_ids: Vec<u32>,
+/// This is a borrowed run of the features sharing a single source, which is a
+/// label for transition features and an attribute for state features. The
+/// features are decoded from the packed model's bytes as they're iterated.
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureRefs<'a> {
+    repr: Repr<'a>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Repr<'a> {
+    /// One little-endian `f32` weight per target label, in label order
+    Dense(&'a [u8]),
+    /// Quantized words in the given format, along with the codebook that
+    /// decodes their weights
+    Quantized {
+        words: &'a [u8],
+        format: WordFormat,
+        codebook: &'a Codebook,
+    },
+}
+
+impl<'a> FeatureRefs<'a> {
+    pub(crate) fn dense(weights: &'a [u8]) -> FeatureRefs<'a> {
+        FeatureRefs {
+            repr: Repr::Dense(weights),
+        }
+    }
+
+    pub(crate) fn quantized(
+        words: &'a [u8],
+        format: WordFormat,
+        codebook: &'a Codebook,
+    ) -> FeatureRefs<'a> {
+        FeatureRefs {
+            repr: Repr::Quantized {
+                words,
+                format,
+                codebook,
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.repr {
+            Repr::Dense(weights) => weights.len() / 4,
+            Repr::Quantized { words, format, .. } => words.len() / format.bytes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the feature at `index`
+    pub fn get(&self, index: usize) -> Option<Feature> {
+        if index >= self.len() {
+            return None;
+        }
+        Some(match self.repr {
+            Repr::Dense(weights) => Feature {
+                target: index as u32,
+                weight: f32::from_le_bytes(weights[index * 4..index * 4 + 4].try_into().unwrap()),
+            },
+            Repr::Quantized {
+                words,
+                format,
+                codebook,
+            } => {
+                let word = format.read(words, index);
+                let target = format.target(word);
+                Feature {
+                    target,
+                    weight: codebook.weight(target, format.weight_index(word)),
+                }
+            }
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + 'a {
+        let refs = *self;
+        (0..refs.len()).filter_map(move |index| refs.get(index))
+    }
 }
@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+
+/// Limited-memory BFGS minimizer
+///
+/// With a nonzero `c1` this is OWL-QN, which minimizes `f(x) + c1 * |x|_1` by
+/// restricting each step to a single orthant, so that weights can reach and
+/// stay at exactly zero.
+#[derive(Debug, Clone)]
+pub(crate) struct Lbfgs {
+    /// Number of corrections kept to approximate the inverse Hessian
+    pub memory: usize,
+    /// Coefficient of the L1 penalty
+    pub c1: f64,
+    pub max_iterations: usize,
+    /// Stop once the norm of the gradient is this small relative to the norm
+    /// of `x`
+    pub epsilon: f64,
+    /// Stop once the objective has improved by less than `delta`, relative to
+    /// its value, over the last `past` iterations. Zero disables the test.
+    pub past: usize,
+    pub delta: f64,
+    /// Number of backtracking steps to try before giving up on a line search
+    pub max_linesearch: usize,
+}
+
+/// Why `Lbfgs::minimize` stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stop {
+    Converged,
+    MaxIterations,
+    /// The callback asked to stop
+    Cancelled,
+    /// No step along the search direction decreased the objective
+    LineSearchFailed,
+}
+
+/// A correction pair, `s = x' - x` and `y = g' - g`
+struct Correction {
+    s: Vec<f64>,
+    y: Vec<f64>,
+    rho: f64,
+}
+
+impl Lbfgs {
+    /// Minimize the objective whose value and gradient at `x` are computed by
+    /// `evaluate`, starting from and updating `x` in place.
+    ///
+    /// `progress` is called after every iteration with the iteration number,
+    /// the current `x` and the objective including the L1 penalty. Returning
+    /// `false` stops the search.
+    pub fn minimize(
+        &self,
+        x: &mut Vec<f64>,
+        mut evaluate: impl FnMut(&[f64], &mut [f64]) -> f64,
+        mut progress: impl FnMut(usize, &[f64], f64) -> bool,
+    ) -> Stop {
+        let n = x.len();
+        let mut g = vec![0.0; n];
+        let mut fx = evaluate(x, &mut g) + self.l1_penalty(x);
+        let mut pg = self.pseudo_gradient(x, &g);
+
+        let mut history: VecDeque<Correction> = VecDeque::with_capacity(self.memory);
+        let mut past_fx: VecDeque<f64> = VecDeque::with_capacity(self.past + 1);
+        past_fx.push_back(fx);
+
+        let mut d: Vec<f64> = pg.iter().map(|value| -value).collect();
+        let mut step = 1.0 / norm(&d).max(f64::MIN_POSITIVE);
+        let mut x_next = vec![0.0; n];
+        let mut g_next = vec![0.0; n];
+
+        if norm(&pg) / norm(x).max(1.0) <= self.epsilon {
+            return Stop::Converged;
+        }
+
+        for iteration in 1..=self.max_iterations {
+            if self.c1 > 0.0 {
+                // Don't move against the pseudo-gradient in any coordinate.
+                for (d_i, pg_i) in d.iter_mut().zip(&pg) {
+                    if *d_i * pg_i >= 0.0 {
+                        *d_i = 0.0;
+                    }
+                }
+            }
+            if dot(&pg, &d) >= 0.0 {
+                // The quasi-Newton direction isn't a descent direction, so
+                // start over from steepest descent.
+                history.clear();
+                d = pg.iter().map(|value| -value).collect();
+                step = 1.0 / norm(&d).max(f64::MIN_POSITIVE);
+                if dot(&pg, &d) >= 0.0 {
+                    return Stop::Converged;
+                }
+            }
+
+            let orthant: Vec<f64> = x
+                .iter()
+                .zip(&pg)
+                .map(|(x_i, pg_i)| {
+                    if *x_i != 0.0 {
+                        x_i.signum()
+                    } else {
+                        -pg_i.signum()
+                    }
+                })
+                .collect();
+            let mut accepted = None;
+            for _ in 0..self.max_linesearch {
+                for i in 0..n {
+                    x_next[i] = x[i] + step * d[i];
+                    if self.c1 > 0.0 && x_next[i] * orthant[i] <= 0.0 {
+                        x_next[i] = 0.0;
+                    }
+                }
+                let f_next = evaluate(&x_next, &mut g_next) + self.l1_penalty(&x_next);
+                let decrease: f64 = (0..n).map(|i| pg[i] * (x_next[i] - x[i])).sum();
+                if f_next.is_finite() && f_next <= fx + 1e-4 * decrease {
+                    accepted = Some(f_next);
+                    break;
+                }
+                step *= 0.5;
+            }
+            let f_next = match accepted {
+                Some(f_next) => f_next,
+                None => return Stop::LineSearchFailed,
+            };
+
+            let s: Vec<f64> = (0..n).map(|i| x_next[i] - x[i]).collect();
+            let y: Vec<f64> = (0..n).map(|i| g_next[i] - g[i]).collect();
+            std::mem::swap(x, &mut x_next);
+            std::mem::swap(&mut g, &mut g_next);
+            fx = f_next;
+            pg = self.pseudo_gradient(x, &g);
+
+            if !progress(iteration, x, fx) {
+                return Stop::Cancelled;
+            }
+            if norm(&pg) / norm(x).max(1.0) <= self.epsilon {
+                return Stop::Converged;
+            }
+            if self.past > 0 {
+                if past_fx.len() > self.past {
+                    let previous = past_fx.pop_front().unwrap();
+                    if (previous - fx) / fx.abs().max(f64::MIN_POSITIVE) < self.delta {
+                        return Stop::Converged;
+                    }
+                }
+                past_fx.push_back(fx);
+            }
+
+            let ys = dot(&y, &s);
+            if ys > 1e-10 {
+                if history.len() == self.memory {
+                    history.pop_front();
+                }
+                history.push_back(Correction {
+                    s,
+                    y,
+                    rho: 1.0 / ys,
+                });
+            }
+
+            // Two-loop recursion for d = -H * pg.
+            let mut q = pg.clone();
+            let mut alphas = Vec::with_capacity(history.len());
+            for correction in history.iter().rev() {
+                let alpha = correction.rho * dot(&correction.s, &q);
+                for (q_i, y_i) in q.iter_mut().zip(&correction.y) {
+                    *q_i -= alpha * y_i;
+                }
+                alphas.push(alpha);
+            }
+            if let Some(last) = history.back() {
+                let gamma = 1.0 / (last.rho * dot(&last.y, &last.y));
+                q.iter_mut().for_each(|q_i| *q_i *= gamma);
+            }
+            for (correction, alpha) in history.iter().zip(alphas.iter().rev()) {
+                let beta = correction.rho * dot(&correction.y, &q);
+                for (q_i, s_i) in q.iter_mut().zip(&correction.s) {
+                    *q_i += (alpha - beta) * s_i;
+                }
+            }
+            d = q.iter().map(|value| -value).collect();
+            step = 1.0;
+        }
+        Stop::MaxIterations
+    }
+
+    fn l1_penalty(&self, x: &[f64]) -> f64 {
+        if self.c1 > 0.0 {
+            self.c1 * x.iter().map(|value| value.abs()).sum::<f64>()
+        } else {
+            0.0
+        }
+    }
+
+    /// The gradient of the smooth part plus the L1 penalty, taking the
+    /// one-sided derivative that decreases the objective where `x` is zero
+    fn pseudo_gradient(&self, x: &[f64], g: &[f64]) -> Vec<f64> {
+        if self.c1 == 0.0 {
+            return g.to_vec();
+        }
+        x.iter()
+            .zip(g)
+            .map(|(x_i, g_i)| {
+                if *x_i > 0.0 {
+                    g_i + self.c1
+                } else if *x_i < 0.0 {
+                    g_i - self.c1
+                } else if g_i + self.c1 < 0.0 {
+                    g_i + self.c1
+                } else if g_i - self.c1 > 0.0 {
+                    g_i - self.c1
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a_i, b_i)| a_i * b_i).sum()
+}
+
+fn norm(a: &[f64]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lbfgs(c1: f64) -> Lbfgs {
+        Lbfgs {
+            memory: 6,
+            c1,
+            max_iterations: 200,
+            epsilon: 1e-8,
+            past: 0,
+            delta: 0.0,
+            max_linesearch: 40,
+        }
+    }
+
+    /// `sum_i (i + 1) * (x_i - 1)^2`
+    fn quadratic(x: &[f64], g: &mut [f64]) -> f64 {
+        let mut f = 0.0;
+        for (i, (x_i, g_i)) in x.iter().zip(g.iter_mut()).enumerate() {
+            let scale = (i + 1) as f64;
+            f += scale * (x_i - 1.0).powi(2);
+            *g_i = 2.0 * scale * (x_i - 1.0);
+        }
+        f
+    }
+
+    #[test]
+    fn test_minimize_quadratic() {
+        let mut x = vec![0.0; 5];
+        let stop = lbfgs(0.0).minimize(&mut x, quadratic, |_, _, _| true);
+        assert_eq!(stop, Stop::Converged);
+        assert!(x.iter().all(|x_i| (x_i - 1.0).abs() < 1e-5), "{:?}", x);
+    }
+
+    #[test]
+    fn test_l1_zeroes_weak_coordinates() {
+        // The L1 penalty outweighs the pull towards one of the first
+        // coordinate, whose curvature is smallest, but not the others.
+        let mut x = vec![0.5; 3];
+        lbfgs(3.0).minimize(&mut x, quadratic, |_, _, _| true);
+        assert_eq!(x[0], 0.0);
+        assert!((x[1] - 0.25).abs() < 1e-4, "{:?}", x);
+        assert!((x[2] - 0.5).abs() < 1e-4, "{:?}", x);
+    }
+}
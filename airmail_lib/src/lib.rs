@@ -1,8 +1,26 @@
+pub mod address;
+#[cfg(feature = "corpus")]
+pub mod augment;
 pub mod context;
 pub mod dataset;
+pub mod error;
+pub mod extractor;
 pub mod feature;
+#[cfg(feature = "train")]
+mod lbfgs;
+#[cfg(feature = "corpus")]
 pub mod lp_file_stream;
 pub mod model;
+pub mod packed;
 pub mod parser;
+pub mod quantize;
+pub mod schema;
 pub mod tagger;
+pub mod template;
 pub mod tokenizer;
+#[cfg(feature = "train")]
+pub mod trainer;
+#[cfg(feature = "corpus")]
+pub mod training_source;
+
+pub use error::{Error, Result};
@@ -2,14 +2,69 @@ extern crate deunicode;
 
 use std::{
     error::Error,
+    fmt,
     fs::File,
-    io::{BufRead, BufReader, Lines},
+    io::{self, BufRead, BufReader},
+    sync::{Arc, Mutex},
+    vec,
 };
 
 use deunicode::deunicode;
+use serde::Serialize;
 
-pub struct LpFileStream {
-    lines: Lines<BufReader<File>>,
+/// Malformed lines past this many are counted but not listed
+const MAX_LISTED_MALFORMED: usize = 100;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Reads a corpus line by line, turning lines into entries with a
+/// `LineFormat`.
+///
+/// Inputs can be files, glob patterns or `-` for stdin, and can be gzip, zstd
+/// or xz compressed. Malformed lines and inputs that can't be read are
+/// reported on stderr and skipped, and `stats` counts them.
+pub struct LineStream<F> {
+    format: F,
+    inputs: vec::IntoIter<String>,
+    current: Option<Input>,
+    buffer: Vec<u8>,
+    stats: ReadStats,
+}
+
+/// Reads libpostal training data, one entry per line with the language,
+/// country and labeled tokens separated by tabs
+pub type LpFileStream = LineStream<Libpostal>;
+
+/// How the lines of a corpus make up entries
+pub trait LineFormat: Send {
+    /// Start reading a new input.
+    fn start(&mut self) {}
+
+    /// Read a line without its line ending, returning the entry it completes
+    /// if any, or what's wrong with it.
+    fn line(&mut self, line: &str) -> Result<Option<LpFileEntry>, String>;
+
+    /// Finish reading an input, returning the entry its last lines make up
+    /// if any.
+    fn finish(&mut self) -> Option<LpFileEntry> {
+        None
+    }
+}
+
+/// libpostal's format: the language, country and space-separated
+/// `word/label` tokens of an entry, separated by tabs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Libpostal;
+
+/// The input being read and how far into it the stream is
+struct Input {
+    name: String,
+    reader: Box<dyn BufRead + Send>,
+    line: usize,
+    /// Checksum of the lines read so far
+    hasher: crc32fast::Hasher,
 }
 
 #[derive(Debug, Clone)]
@@ -26,54 +81,413 @@ pub struct LpEntryToken {
     pub label: String,
 }
 
+impl LpEntryToken {
+    /// A token for `word`, transliterated the way the tokenizer sees it
+    pub fn new(word: &str, label: &str) -> LpEntryToken {
+        let transliterated = deunicode(word).to_ascii_lowercase();
+        let transliterated_tokens: Vec<&str> = transliterated.split_ascii_whitespace().collect();
+        LpEntryToken {
+            word: word.to_lowercase(),
+            label: label.to_string(),
+            transliterated: transliterated_tokens.join(""),
+        }
+    }
+}
+
+/// A line that was skipped, and why
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MalformedLine {
+    pub input: String,
+    /// One-based line number within the input
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for MalformedLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.input, self.line, self.reason)
+    }
+}
+
+/// An input that was read to the end
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InputDigest {
+    pub input: String,
+    pub lines: usize,
+    /// CRC-32 of the input's contents, after decompression
+    pub crc32: u32,
+}
+
+/// What an `LpFileStream` has read so far
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReadCounts {
+    pub inputs: usize,
+    pub lines: usize,
+    pub entries: usize,
+    pub malformed: usize,
+    /// The first malformed lines, up to a hundred of them
+    pub malformed_lines: Vec<MalformedLine>,
+    /// Inputs that couldn't be read to the end, and why
+    pub failed_inputs: Vec<(String, String)>,
+    /// Inputs that were read to the end, in the order they were read
+    pub digests: Vec<InputDigest>,
+}
+
+impl fmt::Display for ReadCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Read {} lines from {} inputs: {} entries, {} malformed lines skipped",
+            self.lines, self.inputs, self.entries, self.malformed
+        )?;
+        for (input, error) in &self.failed_inputs {
+            writeln!(f, "Couldn't read all of {}: {}", input, error)?;
+        }
+        Ok(())
+    }
+}
+
+/// A stream's counts, which stay available after the stream is consumed
+#[derive(Debug, Clone, Default)]
+pub struct ReadStats(Arc<Mutex<ReadCounts>>);
+
+impl ReadStats {
+    pub fn counts(&self) -> ReadCounts {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn update<F: FnOnce(&mut ReadCounts)>(&self, f: F) {
+        f(&mut self.0.lock().unwrap())
+    }
+}
+
 impl LpFileStream {
+    /// Read one input, which may also be a glob pattern or `-` for stdin.
     pub fn new(filename: String) -> Result<LpFileStream, Box<dyn Error>> {
-        let reader = BufReader::new(File::open(filename)?);
+        LpFileStream::open(&[filename])
+    }
+
+    /// Read several inputs as one stream.
+    pub fn open<S: AsRef<str>>(patterns: &[S]) -> Result<LpFileStream, Box<dyn Error>> {
+        LineStream::with_format(patterns, Libpostal)
+    }
+}
 
-        Ok(LpFileStream {
-            lines: reader.lines(),
+impl<F: LineFormat> LineStream<F> {
+    /// Read several inputs in `format` as one stream, in order. Glob patterns
+    /// expand to the files they match in sorted order, and must match at
+    /// least one.
+    pub fn with_format<S: AsRef<str>>(
+        patterns: &[S],
+        format: F,
+    ) -> Result<LineStream<F>, Box<dyn Error>> {
+        let mut inputs = vec![];
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            if pattern == "-" {
+                inputs.push(pattern.to_string());
+            } else if pattern.contains(['*', '?', '[']) {
+                let matches = glob::glob(pattern)?
+                    .map(|path| path.map(|path| path.to_string_lossy().into_owned()))
+                    .collect::<Result<Vec<String>, _>>()?;
+                if matches.is_empty() {
+                    return Err(format!("no files match `{}`", pattern).into());
+                }
+                inputs.extend(matches);
+            } else {
+                // Catch typos before reading everything else.
+                std::fs::metadata(pattern)
+                    .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", pattern, err)))?;
+                inputs.push(pattern.to_string());
+            }
+        }
+        Ok(LineStream {
+            format,
+            inputs: inputs.into_iter(),
+            current: None,
+            buffer: vec![],
+            stats: ReadStats::default(),
         })
     }
+
+    pub fn stats(&self) -> ReadStats {
+        self.stats.clone()
+    }
+
+    fn fail(&self, input: String, err: io::Error) {
+        eprintln!("Couldn't read all of {}: {}", input, err);
+        self.stats
+            .update(|counts| counts.failed_inputs.push((input, err.to_string())));
+    }
+}
+
+/// Open an input, decompressing it if it starts like a compressed stream
+fn open_input(name: &str) -> io::Result<Box<dyn BufRead + Send>> {
+    let mut reader: Box<dyn BufRead + Send> = if name == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(name)?))
+    };
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(GZIP_MAGIC) {
+        gzip(reader)
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        zstd(reader)
+    } else if magic.starts_with(XZ_MAGIC) {
+        xz(reader)
+    } else {
+        Ok(reader)
+    }
+}
+
+#[cfg(feature = "compression")]
+fn gzip(reader: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    Ok(Box::new(BufReader::new(
+        flate2::bufread::MultiGzDecoder::new(reader),
+    )))
+}
+
+#[cfg(feature = "compression")]
+fn zstd(reader: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    Ok(Box::new(BufReader::new(
+        zstd::stream::read::Decoder::with_buffer(reader)?,
+    )))
+}
+
+#[cfg(feature = "compression")]
+fn xz(reader: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    Ok(Box::new(BufReader::new(
+        xz2::bufread::XzDecoder::new_multi_decoder(reader),
+    )))
+}
+
+#[cfg(not(feature = "compression"))]
+fn gzip(_: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "gzip input needs the `compression` feature",
+    ))
+}
+
+#[cfg(not(feature = "compression"))]
+fn zstd(_: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "zstd input needs the `compression` feature",
+    ))
+}
+
+#[cfg(not(feature = "compression"))]
+fn xz(_: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "xz input needs the `compression` feature",
+    ))
+}
+
+impl LineFormat for Libpostal {
+    fn line(&mut self, line: &str) -> Result<Option<LpFileEntry>, String> {
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let line_cells: Vec<&str> = line.split('\t').collect();
+        if line_cells.len() != 3 {
+            return Err(format!(
+                "expected 3 tab-separated cells but found {}",
+                line_cells.len()
+            ));
+        }
+        let tokens: Vec<LpEntryToken> = line_cells[2]
+            .split(' ')
+            .filter_map(|token| {
+                let (word, label) = token.rsplit_once('/')?;
+                Some(LpEntryToken::new(word, label))
+            })
+            .collect();
+        Ok(Some(LpFileEntry {
+            lang: line_cells[0].to_string(),
+            country: line_cells[1].to_string(),
+            tokens,
+        }))
+    }
 }
 
-impl Iterator for LpFileStream {
+impl<F: LineFormat> Iterator for LineStream<F> {
     type Item = LpFileEntry;
 
     fn next(&mut self) -> Option<LpFileEntry> {
-        if let Some(Ok(next_line)) = self.lines.next() {
-            let line_cells: Vec<&str> = next_line.split('\t').collect();
-            if line_cells.len() != 3 {
-                dbg!(
-                    "Expected 3 cells in libpostal data but found {}",
-                    line_cells.len()
-                );
-                return None;
-            }
-            let token_strings: Vec<&str> = line_cells[2].split(' ').collect();
-            let tokens: Vec<LpEntryToken> = token_strings
-                .iter()
-                .filter_map(|token| {
-                    if let Some((word, label)) = token.rsplit_once('/') {
-                        let transliterated = deunicode(&word).to_ascii_lowercase();
-                        let transliterated_tokens: Vec<&str> =
-                            transliterated.split_ascii_whitespace().collect();
-                        Some(LpEntryToken {
-                            word: word.to_lowercase(),
-                            label: label.to_string(),
-                            transliterated: transliterated_tokens.join(""),
-                        })
-                    } else {
-                        None
+        loop {
+            let input = match &mut self.current {
+                Some(input) => input,
+                None => {
+                    let name = self.inputs.next()?;
+                    match open_input(&name) {
+                        Ok(reader) => {
+                            self.stats.update(|counts| counts.inputs += 1);
+                            self.format.start();
+                            self.current.insert(Input {
+                                name,
+                                reader,
+                                line: 0,
+                                hasher: crc32fast::Hasher::new(),
+                            })
+                        }
+                        Err(err) => {
+                            self.fail(name, err);
+                            continue;
+                        }
                     }
-                })
-                .collect();
-            Some(LpFileEntry {
-                lang: line_cells[0].to_string(),
-                country: line_cells[1].to_string(),
-                tokens,
-            })
-        } else {
-            None
+                }
+            };
+
+            self.buffer.clear();
+            match input.reader.read_until(b'\n', &mut self.buffer) {
+                Ok(0) => {
+                    let input = self.current.take().unwrap();
+                    let digest = InputDigest {
+                        input: input.name,
+                        lines: input.line,
+                        crc32: input.hasher.finalize(),
+                    };
+                    self.stats.update(|counts| counts.digests.push(digest));
+                    if let Some(entry) = self.format.finish() {
+                        self.stats.update(|counts| counts.entries += 1);
+                        return Some(entry);
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    // An entry the input was partway through is incomplete.
+                    self.format.finish();
+                    let input = self.current.take().unwrap();
+                    self.fail(input.name, err);
+                    continue;
+                }
+            }
+            input.line += 1;
+            input.hasher.update(&self.buffer);
+            let mut line = self.buffer.as_slice();
+            line = line.strip_suffix(b"\n").unwrap_or(line);
+            line = line.strip_suffix(b"\r").unwrap_or(line);
+            self.stats.update(|counts| counts.lines += 1);
+            let parsed = std::str::from_utf8(line)
+                .map_err(|err| format!("invalid UTF-8: {}", err))
+                .and_then(|line| self.format.line(line));
+            match parsed {
+                Ok(Some(entry)) => {
+                    self.stats.update(|counts| counts.entries += 1);
+                    return Some(entry);
+                }
+                Ok(None) => {}
+                Err(reason) => {
+                    let malformed = MalformedLine {
+                        input: input.name.clone(),
+                        line: input.line,
+                        reason,
+                    };
+                    self.stats.update(|counts| {
+                        counts.malformed += 1;
+                        if counts.malformed <= MAX_LISTED_MALFORMED {
+                            eprintln!("Skipping malformed line {}", malformed);
+                            counts.malformed_lines.push(malformed);
+                        } else if counts.malformed == MAX_LISTED_MALFORMED + 1 {
+                            eprintln!("Not listing further malformed lines");
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_input(name: &str, data: &[u8]) -> String {
+        let dir = std::env::temp_dir().join(format!("airmail-lp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        let path = write_input(
+            "malformed.tsv",
+            b"en\tus\t5/house_number main/road\r\n\
+              en\tus main/road\n\
+              \n\
+              en\tus\t\xff/road\n\
+              en\tus\tseattle/city\n",
+        );
+        let mut stream = LpFileStream::new(path.clone()).unwrap();
+        let stats = stream.stats();
+        let entries: Vec<LpFileEntry> = stream.by_ref().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].tokens[1].transliterated, "main");
+        assert_eq!(entries[1].tokens[0].label, "city");
+
+        let counts = stats.counts();
+        assert_eq!((counts.lines, counts.entries, counts.malformed), (5, 2, 2));
+        let lines: Vec<usize> = counts.malformed_lines.iter().map(|m| m.line).collect();
+        assert_eq!(lines, [2, 4]);
+        assert_eq!(counts.malformed_lines[0].input, path);
+        assert!(counts.failed_inputs.is_empty());
+    }
+
+    #[test]
+    fn test_multiple_inputs() {
+        write_input("multi-a.tsv", b"en\tus\tmain/road\n");
+        write_input("multi-b.tsv", b"en\tus\tseattle/city\nen\tus\twa/state\n");
+        let pattern = std::env::temp_dir()
+            .join(format!("airmail-lp-{}", std::process::id()))
+            .join("multi-*.tsv");
+        let stream = LpFileStream::open(&[pattern.to_string_lossy()]).unwrap();
+        let stats = stream.stats();
+        let labels: Vec<String> = stream
+            .flat_map(|entry| entry.tokens.into_iter().map(|token| token.label))
+            .collect();
+        assert_eq!(labels, ["road", "city", "state"]);
+        let counts = stats.counts();
+        assert_eq!(counts.inputs, 2);
+        assert_eq!(counts.digests.len(), 2);
+        assert!(counts.digests[1].input.ends_with("multi-b.tsv"));
+        assert_eq!(counts.digests[1].lines, 2);
+        assert_eq!(
+            counts.digests[1].crc32,
+            crc32fast::hash(b"en\tus\tseattle/city\nen\tus\twa/state\n")
+        );
+
+        assert!(LpFileStream::open(&["/nonexistent/airmail-*.tsv"]).is_err());
+        assert!(LpFileStream::new("/nonexistent/airmail.tsv".to_string()).is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed() {
+        use std::io::Write;
+
+        let data = b"en\tus\tmain/road\nbad line\nen\tus\tseattle/city\n";
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(data).unwrap();
+        let gzip = write_input("compressed.tsv.gz", &gzip.finish().unwrap());
+        let zstd = write_input(
+            "compressed.tsv.zst",
+            &zstd::stream::encode_all(&data[..], 0).unwrap(),
+        );
+        let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+        xz.write_all(data).unwrap();
+        let xz = write_input("compressed.tsv.xz", &xz.finish().unwrap());
+        for path in [gzip, zstd, xz] {
+            let stream = LpFileStream::new(path).unwrap();
+            let stats = stream.stats();
+            assert_eq!(stream.count(), 2);
+            assert_eq!(stats.counts().malformed, 1);
+            assert_eq!(stats.counts().digests[0].crc32, crc32fast::hash(data));
         }
     }
 }
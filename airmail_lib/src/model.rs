@@ -1,14 +1,19 @@
-use std::{
-    collections::{HashMap, HashSet},
-    f64::consts::PI,
-    fmt, io,
-};
+use std::{fmt, ops::Range};
 
-use fst::raw::Fst;
+use fst::{raw::Fst, Streamer};
 use serde::{Deserialize, Serialize};
 
-use crate::feature::{Feature, FeatureRefs};
+use crate::error::{Error, Result};
+use crate::extractor::ExtractorConfig;
+use crate::feature::FeatureRefs;
+use crate::packed::{
+    read_header, read_labels, read_u32, AttrWords, FormatVersion, Layout, ModelBytes,
+    ModelMetadata, PackedModel, WordFormat,
+};
+use crate::quantize::{Codebook, CURVE_BITS};
+use crate::schema::LabelSchema;
 use crate::tagger::Tagger;
+use crate::tokenizer::VocabOutput;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[repr(C)]
@@ -27,303 +32,654 @@ pub struct Header {
     pub off_attr_refs: u32,
 }
 
-#[derive(Debug, Clone)]
-#[repr(C)]
-struct FeatureRefHeader {
-    chunk: [u8; 4],
-    size: u32,
-    num: u32,
-    offsets: [u32; 1],
-}
-
-#[derive(Debug, Clone)]
-#[repr(C)]
-struct FeatureHeader {
-    chunk: [u8; 4],
-    size: u32,
-    num: u32,
-}
-
 /// The CRF model
+///
+/// A model is a view over the bytes of a packed model (see `PackedModel`),
+/// which it reads in place without copying its features or vocabulary.
+/// Labels and attributes each have their own dense id space starting at zero.
+/// Attribute ids follow the order of the attribute vocabulary FST.
 #[derive(Clone)]
 pub struct Model {
     header: Header,
-    attr_vocab_fst: Fst<Vec<u8>>,
-    attr_vocab: HashMap<String, u32>,
-    label_vocab: HashMap<String, u32>,
-    attr_vocab_reverse: HashMap<u32, String>,
-    label_vocab_reverse: HashMap<u32, String>,
-    feature_weights: HashMap<u32, (u32, u32, f32)>,
-    feature_indices_for_source: HashMap<u32, Vec<u32>>,
-    label_features: HashSet<u32>,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct PackedModel {
-    pub header: Header,
-    pub attr_vocab_fst: Vec<u8>,
-    pub labels: Vec<String>,
-    pub unquantized_label_weights: Vec<(u8, u8, f32)>,
-    pub packed_attr_weights: Vec<u16>,
+    data: ModelBytes,
+    attr_vocab_fst: Fst<ModelBytes>,
+    labels: Vec<String>,
+    /// Byte ranges of the transition matrix, attribute offsets and attribute
+    /// weights within `data`
+    transitions: Range<usize>,
+    attr_offsets: Range<usize>,
+    attr_weights: Range<usize>,
+    /// Width and layout of the attribute weight words
+    attr_word_format: WordFormat,
+    /// Weights the attribute weight words decode to
+    codebook: Codebook,
+    extractor: ExtractorConfig,
+    label_schema: LabelSchema,
+    version: FormatVersion,
+    metadata: ModelMetadata,
+    warnings: Vec<String>,
 }
 
 impl fmt::Debug for Model {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Model")
+            .field("version", &self.version)
             .field("header", &self.header)
+            .field("metadata", &self.metadata)
             .finish()
     }
 }
 
-impl From<PackedModel> for Model {
-    fn from(packed: PackedModel) -> Self {
-        let (label_vocab, attr_vocab, attr_vocab_fst) = {
-            let attr_vocab_fst = Fst::new(packed.attr_vocab_fst).unwrap();
-
-            let mut vocab_idx = 0u32;
-            let label_vocab: HashMap<String, u32> = packed
-                .labels
-                .iter()
-                .map(|key| {
-                    let pair = (key.clone(), vocab_idx);
-                    vocab_idx += 1;
-                    pair
-                })
-                .collect();
-            let attr_vocab: HashMap<String, u32> = attr_vocab_fst
-                .stream()
-                .into_str_keys()
-                .unwrap()
-                .iter()
-                .map(|key| {
-                    let pair = (key.clone(), vocab_idx);
-                    vocab_idx += 1;
-                    pair
-                })
-                .collect();
-            (label_vocab, attr_vocab, attr_vocab_fst)
+impl TryFrom<PackedModel> for Model {
+    type Error = Error;
+
+    fn try_from(packed: PackedModel) -> Result<Self> {
+        Model::from_bytes(packed.to_bytes()?)
+    }
+}
+
+impl<'a> Model {
+    /// Load a model from the bytes of a packed model, such as an
+    /// `include_bytes!` slice or a memory-mapped file.
+    ///
+    /// The bytes are validated up front but otherwise used in place.
+    pub fn from_bytes<D: AsRef<[u8]> + Send + Sync + 'static>(data: D) -> Result<Model> {
+        Model::from_model_bytes(ModelBytes::new(data))
+    }
+
+    fn from_model_bytes(data: ModelBytes) -> Result<Model> {
+        let bytes = data.as_ref();
+        let layout = Layout::read(bytes)?;
+        let header = read_header(&bytes[layout.header.clone()])?;
+        let labels = read_labels(&bytes[layout.labels.clone()])?;
+        let num_labels = labels.len() as u32;
+
+        let attr_vocab_fst = Fst::new(data.slice(layout.vocab.clone()))?;
+        let num_attrs = attr_vocab_fst.len();
+
+        if layout.transitions.len() != labels.len() * labels.len() * 4 {
+            return Err(Error::InvalidModel(format!(
+                "transition matrix is {} bytes but there are {} labels",
+                layout.transitions.len(),
+                num_labels
+            )));
+        }
+
+        let (format, codebook) = match (layout.attr_words, &layout.codebook) {
+            (AttrWords::Narrow, _) => (WordFormat::NARROW, Codebook::curve(CURVE_BITS)),
+            (AttrWords::Wide, _) => (WordFormat::WIDE, Codebook::curve(CURVE_BITS)),
+            (AttrWords::Coded, Some(range)) => {
+                let codebook: Codebook = bincode2::deserialize(&bytes[range.clone()])?;
+                codebook.validate(labels.len())?;
+                (
+                    WordFormat::for_labels(labels.len(), codebook.bits),
+                    codebook,
+                )
+            }
+            (AttrWords::Coded, None) => {
+                return Err(Error::InvalidModel("missing codebook".to_string()))
+            }
         };
-        let label_vocab_reverse: HashMap<u32, String> = label_vocab
-            .iter()
-            .map(|(key, id)| (*id, key.clone()))
-            .collect();
-        let attr_vocab_reverse: HashMap<u32, String> = attr_vocab
-            .iter()
-            .map(|(key, id)| (*id, key.clone()))
-            .collect();
+        if layout.attr_offsets.len() != (num_attrs + 1) * 4
+            || layout.attr_weights.len() % format.bytes != 0
+        {
+            return Err(Error::InvalidModel(format!(
+                "packed weights don't cover the {} attributes in the vocabulary",
+                num_attrs
+            )));
+        }
+        let offsets = &bytes[layout.attr_offsets.clone()];
+        let mut previous = 0;
+        for attr in 0..=num_attrs {
+            let offset = read_u32(offsets, attr * 4);
+            if (attr == 0 && offset != 0) || offset < previous {
+                return Err(Error::InvalidModel(format!(
+                    "attribute offsets are not increasing at attribute {}",
+                    attr
+                )));
+            }
+            previous = offset;
+        }
+        let num_words = layout.attr_weights.len() / format.bytes;
+        if previous as usize != num_words {
+            return Err(Error::InvalidModel(format!(
+                "attribute offsets cover {} weights but there are {}",
+                previous, num_words
+            )));
+        }
+        let words = &bytes[layout.attr_weights.clone()];
+        for index in 0..num_words {
+            let word = format.read(words, index);
+            let target = format.target(word);
+            if target >= num_labels {
+                return Err(Error::LabelOutOfRange { target, num_labels });
+            }
+            if format.weight_index(word) as usize >= codebook.table(target).len() {
+                return Err(Error::InvalidModel(format!(
+                    "state feature word {} indexes past the end of its codebook",
+                    index
+                )));
+            }
+        }
 
-        println!("{:?}", label_vocab_reverse);
-
-        let (feature_weights, feature_indices_for_source, label_features) = {
-            let mut feature_weight_id = 0u32;
-            let mut feature_weights: HashMap<u32, (u32, u32, f32)> = HashMap::new();
-            let mut feature_indices_for_source: HashMap<u32, Vec<u32>> = HashMap::new();
-            let mut label_features = HashSet::new();
-            packed
-                .unquantized_label_weights
-                .iter()
-                .for_each(|(source, target, weight)| {
-                    feature_weights
-                        .insert(feature_weight_id, (*source as u32, *target as u32, *weight));
-                    if let Some(v) = feature_indices_for_source.get_mut(&(*source as u32)) {
-                        v.push(feature_weight_id);
-                    } else {
-                        feature_indices_for_source.insert(*source as u32, vec![feature_weight_id]);
-                    }
-                    label_features.insert(feature_weight_id);
-                    feature_weight_id += 1;
-                });
-            // The length of the labels is the index of the first attr.
-            let mut source = label_vocab.len() as u32;
-            packed
-                .packed_attr_weights
-                .iter()
-                .for_each(|packed_feature| {
-                    let (has_more, target, weight) = Model::unpack_feature(*packed_feature);
-                    feature_weights.insert(feature_weight_id, (source, target, weight));
-                    if let Some(v) = feature_indices_for_source.get_mut(&source) {
-                        v.push(feature_weight_id);
-                    } else {
-                        feature_indices_for_source.insert(source, vec![feature_weight_id]);
-                    }
-                    if !has_more {
-                        source += 1;
-                    }
-                    feature_weight_id += 1;
-                });
-            (feature_weights, feature_indices_for_source, label_features)
+        let extractor = match &layout.extractor {
+            Some(range) => bincode2::deserialize(&bytes[range.clone()])?,
+            None => ExtractorConfig::default(),
+        };
+        let label_schema = match &layout.label_schema {
+            Some(range) => bincode2::deserialize(&bytes[range.clone()])?,
+            None => LabelSchema::default(),
+        };
+        let metadata = match &layout.metadata {
+            Some(range) => bincode2::deserialize(&bytes[range.clone()])?,
+            None => ModelMetadata::default(),
         };
+        let mut warnings = vec![];
+        if num_attrs > 0 && !has_piece_scores(&attr_vocab_fst) {
+            warnings.push(
+                "the vocabulary has no piece log-probabilities, so words are segmented as if \
+                 every piece were equally likely"
+                    .to_string(),
+            );
+        }
 
-        Model {
-            header: packed.header.clone(),
+        Ok(Model {
+            header,
             attr_vocab_fst,
-            attr_vocab,
-            label_vocab,
-            attr_vocab_reverse,
-            label_vocab_reverse,
-            feature_weights,
-            feature_indices_for_source,
-            label_features,
-        }
+            labels,
+            transitions: layout.transitions,
+            attr_offsets: layout.attr_offsets,
+            attr_weights: layout.attr_weights,
+            attr_word_format: format,
+            codebook,
+            extractor,
+            label_schema,
+            version: layout.version,
+            metadata,
+            warnings,
+            data,
+        })
     }
-}
 
-impl<'a> Model {
-    // /// Create an instance of a model object from a model in memory
-    // pub fn new(buf: &'a [u8]) -> io::Result<Self> {
-    //     let size = buf.len();
-    //     if size <= mem::size_of::<Header>() {
-    //         return Err(io::Error::new(io::ErrorKind::Other, "invalid model format"));
-    //     }
-    //     let magic = &buf[0..4];
-    //     if magic != b"lCRF" {
-    //         return Err(io::Error::new(
-    //             io::ErrorKind::Other,
-    //             "invalid file format, magic mismatch",
-    //         ));
-    //     }
-    //     let mut index = 4;
-    //     let header_size = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let header_type = &buf[index..index + 4];
-    //     index += 4;
-    //     let version = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let num_features = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let num_labels = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let num_attrs = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let off_features = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let off_labels = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let off_attrs = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let off_label_refs = unpack_u32(&buf[index..])?;
-    //     index += 4;
-    //     let off_attr_refs = unpack_u32(&buf[index..])?;
-    //     let header = Header {
-    //         magic: magic.try_into().unwrap(),
-    //         size: header_size,
-    //         r#type: header_type.try_into().unwrap(),
-    //         version,
-    //         num_features,
-    //         num_labels,
-    //         num_attrs,
-    //         off_features,
-    //         off_labels,
-    //         off_attrs,
-    //         off_label_refs,
-    //         off_attr_refs,
-    //     };
-    //     let labels_start = off_labels as usize;
-    //     let labels = CQDB::new(&buf[labels_start..size])?;
-    //     let attrs_start = off_attrs as usize;
-    //     let attrs = CQDB::new(&buf[attrs_start..size])?;
-    //     Ok(Self {
-    //         buffer: buf,
-    //         size: size as u32,
-    //         header,
-    //         labels,
-    //         attrs,
-    //     })
-    // }
-
-    fn unpack_feature(packed_feature: u16) -> (bool, u32, f32) {
-        let has_more = packed_feature & 0x8000 != 0;
-        let target = ((packed_feature >> 11) & 0xF) as u32;
-        let raw_packed_weight = (packed_feature & 0x7FF) as f64;
-        let weight_curved = PI * (raw_packed_weight / 2047.0 - 0.5);
-        let uncurved_weight = f64::tan(f64::powf(weight_curved, 7.0)) / 5.0;
-        (has_more, target, uncurved_weight as f32)
-
-        // let curved_weight = f64::signum(*weight)
-        // * f64::powf(f64::atan(5.0 * f64::abs(*weight)), 1.0 / 13.0)
-        // / PI
-        // + 0.5;
+    /// The attribute vocabulary, mapping each attribute to its id
+    pub fn get_vocab(&self) -> Fst<ModelBytes> {
+        self.attr_vocab_fst.clone()
+    }
+
+    /// Feature extractor the model was trained with
+    pub fn extractor(&self) -> &ExtractorConfig {
+        &self.extractor
     }
 
-    pub fn get_vocab(&self) -> Fst<Vec<u8>> {
-        return self.attr_vocab_fst.clone();
+    /// Label schema the model was trained with
+    pub fn label_schema(&self) -> &LabelSchema {
+        &self.label_schema
+    }
+
+    /// Format version the model was written in
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// Where the model came from
+    pub fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+
+    /// Weights the model's state features are quantized to
+    pub fn codebook(&self) -> &Codebook {
+        &self.codebook
+    }
+
+    /// Problems found while loading the model that didn't stop it from
+    /// loading, such as a vocabulary without piece log-probabilities
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
     }
 
     /// Number of attributes
     pub fn num_attrs(&self) -> u32 {
-        self.attr_vocab.len() as u32
+        self.attr_vocab_fst.len() as u32
     }
 
     /// Number of labels
     pub fn num_labels(&self) -> u32 {
-        self.label_vocab.len() as u32
+        self.labels.len() as u32
     }
 
     /// Convert a label ID to label string
     pub fn to_label(&self, lid: u32) -> Option<&str> {
-        match self.label_vocab_reverse.get(&lid) {
-            Some(s) => Some(s.as_str()),
-            None => None,
-        }
+        self.labels.get(lid as usize).map(|s| s.as_str())
     }
 
     /// Convert a label string to label ID
     pub fn to_label_id(&self, value: &str) -> Option<u32> {
-        match self.label_vocab.get(value) {
-            Some(id) => Some(*id),
-            None => None,
-        }
+        self.labels
+            .iter()
+            .position(|label| label == value)
+            .map(|id| id as u32)
     }
 
     /// Convert a attribute ID to attribute string
-    pub fn to_attr(&self, aid: u32) -> Option<&str> {
-        match self.attr_vocab_reverse.get(&aid) {
-            Some(s) => Some(s.as_str()),
-            None => None,
+    ///
+    /// This walks the vocabulary, so it is linear in the number of attributes.
+    pub fn to_attr(&self, aid: u32) -> Option<String> {
+        let mut stream = self.attr_vocab_fst.stream();
+        while let Some((key, output)) = stream.next() {
+            if VocabOutput::decode(output.value()).id == aid {
+                return String::from_utf8(key.to_vec()).ok();
+            }
         }
+        None
     }
 
     /// Convert a attribute string to attribute ID
     pub fn to_attr_id(&self, value: &str) -> Option<u32> {
-        match self.attr_vocab.get(value) {
-            Some(id) => Some(*id),
-            None => None,
+        self.attr_vocab_fst
+            .get(value)
+            .map(|output| VocabOutput::decode(output.value()).id)
+    }
+
+    pub(crate) fn label_ref(&self, lid: u32) -> Result<FeatureRefs<'_>> {
+        if lid >= self.num_labels() {
+            return Err(Error::UnknownLabelId(lid));
         }
+        let row_len = self.labels.len() * 4;
+        let start = self.transitions.start + lid as usize * row_len;
+        Ok(FeatureRefs::dense(
+            &self.data.as_ref()[start..start + row_len],
+        ))
     }
 
-    pub(crate) fn label_ref(&self, lid: u32) -> io::Result<FeatureRefs> {
-        Ok(FeatureRefs {
-            num_features: self.feature_indices_for_source.get(&lid).unwrap().len() as u32,
-            feature_ids: self.feature_indices_for_source.get(&lid).unwrap().clone(),
-        })
+    pub(crate) fn attr_ref(&self, aid: u32) -> Result<FeatureRefs<'_>> {
+        if aid >= self.num_attrs() {
+            return Err(Error::UnknownAttributeId(aid));
+        }
+        let bytes = self.data.as_ref();
+        let offsets = &bytes[self.attr_offsets.clone()];
+        let start = read_u32(offsets, aid as usize * 4) as usize;
+        let end = read_u32(offsets, aid as usize * 4 + 4) as usize;
+        let width = self.attr_word_format.bytes;
+        let words =
+            &bytes[self.attr_weights.start + start * width..self.attr_weights.start + end * width];
+        Ok(FeatureRefs::quantized(
+            words,
+            self.attr_word_format,
+            &self.codebook,
+        ))
     }
 
-    pub(crate) fn attr_ref(&self, aid: u32) -> io::Result<FeatureRefs> {
-        Ok(FeatureRefs {
-            num_features: self.feature_indices_for_source.get(&aid).unwrap().len() as u32,
-            feature_ids: self.feature_indices_for_source.get(&aid).unwrap().clone(),
-        })
+    /// Get a new tagger
+    pub fn tagger(&'a self) -> Result<Tagger<'a>> {
+        Tagger::new(self)
     }
+}
 
-    pub(crate) fn feature(&self, fid: u32) -> io::Result<Feature> {
-        if let Some((source, target, weight)) = self.feature_weights.get(&fid) {
-            Ok(Feature {
-                r#type: if self.label_features.contains(&fid) {
-                    1
-                } else {
-                    0
-                },
-                source: *source,
-                target: *target,
-                weight: *weight as f64,
-            })
-        } else {
-            panic!();
+/// Whether any key of the vocabulary has a log-probability as a word piece
+fn has_piece_scores(vocab: &Fst<ModelBytes>) -> bool {
+    let mut stream = vocab.stream();
+    while let Some((_, output)) = stream.next() {
+        if VocabOutput::decode(output.value()).log_prob.is_some() {
+            return true;
         }
     }
+    false
+}
 
-    /// Get a new tagger
-    pub fn tagger(&'a self) -> io::Result<Tagger<'a>> {
-        Tagger::new(self)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        extractor::AttributeValues, packed::FORMAT_VERSION, quantize::Quantization,
+        template::FeatureTemplates, tokenizer::Tokenizer,
+    };
+    use fst::MapBuilder;
+    use std::collections::BTreeMap;
+
+    fn header() -> Header {
+        Header {
+            magic: *b"lCRF",
+            size: 0,
+            r#type: *b"FOMC",
+            version: 100,
+            num_features: 0,
+            num_labels: 2,
+            num_attrs: 3,
+            off_features: 0,
+            off_labels: 0,
+            off_attrs: 0,
+            off_label_refs: 0,
+            off_attr_refs: 0,
+        }
+    }
+
+    fn packed_model(packed_attr_weights: Vec<u32>) -> PackedModel {
+        let mut builder = MapBuilder::memory();
+        builder
+            .extend_iter([("main", 0), ("seattle", 1), ("street", 2)])
+            .unwrap();
+        PackedModel {
+            header: header(),
+            attr_vocab_fst: builder.into_inner().unwrap(),
+            labels: vec!["road".to_string(), "locality".to_string()],
+            unquantized_label_weights: vec![(1, 0, -0.5), (0, 0, 1.0), (0, 1, 0.25)],
+            packed_attr_weights,
+            codebook: Codebook::curve(CURVE_BITS),
+            extractor: ExtractorConfig::default(),
+            label_schema: LabelSchema::default(),
+            metadata: ModelMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn test_dense_layout() {
+        // "main" -> road, "seattle" -> locality, "street" -> road and locality
+        let model = Model::try_from(packed_model(vec![
+            0x7FF,
+            (1 << 11) | 0x7FF,
+            0x8000 | 0x7FF,
+            (1 << 11) | 0x400,
+        ]))
+        .unwrap();
+        assert_eq!(model.num_labels(), 2);
+        assert_eq!(model.num_attrs(), 3);
+        assert_eq!(model.to_attr_id("seattle"), Some(1));
+        assert_eq!(model.to_attr_id("nowhere"), None);
+        assert_eq!(model.to_attr(2).as_deref(), Some("street"));
+        assert_eq!(model.to_attr(3), None);
+        assert_eq!(model.to_label_id("locality"), Some(1));
+
+        let targets: Vec<u32> = model
+            .attr_ref(2)
+            .unwrap()
+            .iter()
+            .map(|feature| feature.target)
+            .collect();
+        assert_eq!(targets, vec![0, 1]);
+        assert!(model.attr_ref(3).is_err());
+
+        let road_transitions: Vec<(u32, f32)> = model
+            .label_ref(0)
+            .unwrap()
+            .iter()
+            .map(|feature| (feature.target, feature.weight))
+            .collect();
+        assert_eq!(road_transitions, vec![(0, 1.0), (1, 0.25)]);
+        assert_eq!(model.label_ref(1).unwrap().len(), 2);
+        assert!(model.label_ref(2).is_err());
+    }
+
+    #[test]
+    fn test_empty_instance() {
+        let model = Model::try_from(packed_model(vec![0x7FF, 0x7FF, 0x7FF])).unwrap();
+        let mut tagger = model.tagger().unwrap();
+        let empty: [Vec<crate::tagger::Attribute>; 0] = [];
+        tagger
+            .set(&[vec![crate::tagger::Attribute::new("main", 1.0)]])
+            .unwrap();
+        assert!(tagger.lognorm().unwrap() != 0.0);
+        tagger.set(&empty).unwrap();
+        assert!(tagger.viterbi().unwrap().is_empty());
+        assert!(tagger.marginals().unwrap().is_empty());
+        assert_eq!(tagger.probability(&[]).unwrap(), 1.0);
+        assert_eq!(tagger.lognorm().unwrap(), 0.0);
+
+        // Tagging an empty sequence replaces the previous instance too.
+        let main = [vec![crate::tagger::Attribute::new("main", 1.0)]];
+        tagger.tag(&main).unwrap();
+        assert!(tagger.tag(&empty).unwrap().is_empty());
+        assert!(tagger.is_empty());
+        tagger.tag(&main).unwrap();
+        assert_eq!(tagger.tag_with_probability(&empty).unwrap(), (vec![], 1.0));
+        assert_eq!(tagger.lognorm().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_mismatched_weights_rejected() {
+        // Only two attributes' worth of weights for a three attribute vocab.
+        assert!(Model::try_from(packed_model(vec![0x7FF, 0x7FF])).is_err());
+        // A weight pointing at a label that doesn't exist.
+        assert!(Model::try_from(packed_model(vec![0x7FF, 0x7FF, 5 << 11])).is_err());
+    }
+
+    #[test]
+    fn test_pack_and_load_in_place() {
+        let mut pieces = MapBuilder::memory();
+        for (id, (piece, log_prob)) in [("seattle", -4.0), ("street", -2.5)].iter().enumerate() {
+            let output = VocabOutput {
+                id: id as u32,
+                log_prob: Some(*log_prob),
+            };
+            pieces.insert(piece, output.encode()).unwrap();
+        }
+        let pieces = Tokenizer::new(&Fst::new(pieces.into_inner().unwrap()).unwrap());
+        let packed = PackedModel::pack(
+            header(),
+            vec!["road".to_string(), "locality".to_string()],
+            &[(0, 1, 0.5)],
+            &[
+                ("street".to_string(), 0, 2.0),
+                ("seattle".to_string(), 1, 1.5),
+                ("street".to_string(), 1, -0.5),
+            ],
+            Some(&pieces),
+            Quantization::default(),
+        )
+        .unwrap();
+        let extractor = ExtractorConfig {
+            digits_only: false,
+            values: AttributeValues::SegmentationProbability,
+            max_segmentations: 2,
+            templates: FeatureTemplates {
+                neighbors: vec![-1, 1],
+                boundaries: true,
+                ..FeatureTemplates::default()
+            },
+        };
+        let mut packed = packed;
+        packed.extractor = extractor.clone();
+        packed.label_schema.coarse = BTreeMap::from([("road".to_string(), "street".to_string())]);
+        packed.metadata.corpus = Some("libpostal".to_string());
+        let bytes: &'static [u8] = Box::leak(packed.to_bytes().unwrap().into_boxed_slice());
+        let model = Model::from_bytes(bytes).unwrap();
+        assert_eq!(model.num_attrs(), 2);
+        assert_eq!(model.to_attr_id("street"), Some(1));
+        assert_eq!(model.to_label(1), Some("locality"));
+        assert_eq!(model.extractor(), &extractor);
+        assert_eq!(model.label_schema().coarse("road"), "street");
+        assert_eq!(model.metadata().corpus.as_deref(), Some("libpostal"));
+        assert_eq!(model.version(), FormatVersion { major: 1, minor: 0 });
+        assert!(model.warnings().is_empty());
+        // The header describes the packed model rather than the crfsuite one.
+        assert_eq!(model.header.num_features, 4);
+        assert_eq!(model.header.num_attrs, 2);
+        assert_eq!(model.header.off_features, 0);
+        assert_eq!(
+            Tokenizer::for_model(&model).piece_log_prob("street"),
+            Some(-2.5)
+        );
+
+        let street: Vec<(u32, f32)> = model
+            .attr_ref(1)
+            .unwrap()
+            .iter()
+            .map(|feature| (feature.target, feature.weight))
+            .collect();
+        assert_eq!(street.len(), 2);
+        assert_eq!(street[0].0, 0);
+        assert!((street[0].1 - 2.0).abs() < 0.2);
+        assert!((street[1].1 + 0.5).abs() < 0.05);
+
+        // Without the vocabulary's scores every piece costs the same.
+        let unscored = PackedModel::pack(
+            header(),
+            vec!["road".to_string()],
+            &[],
+            &[("street".to_string(), 0, 2.0)],
+            None,
+            Quantization::default(),
+        )
+        .unwrap()
+        .to_bytes()
+        .unwrap();
+        assert_eq!(Model::from_bytes(unscored).unwrap().warnings().len(), 1);
+
+        // Truncating the model anywhere must be caught when loading.
+        for len in (0..bytes.len()).step_by(7) {
+            assert!(Model::from_bytes(bytes[..len].to_vec()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_wide_targets() {
+        let labels: Vec<String> = (0..40).map(|label| format!("label{}", label)).collect();
+        let state_features = [
+            ("main".to_string(), 3, 1.0),
+            ("main".to_string(), 17, -1.0),
+            ("street".to_string(), 39, 2.0),
+        ];
+        let pack = |labels: &[String]| {
+            PackedModel::pack(
+                header(),
+                labels.to_vec(),
+                &[(39, 0, 0.5)],
+                &state_features,
+                None,
+                Quantization::default(),
+            )
+        };
+        let wide = pack(&labels).unwrap().to_bytes().unwrap();
+        let model = Model::from_bytes(wide.clone()).unwrap();
+        assert_eq!(model.num_labels(), 40);
+        let main: Vec<u32> = model
+            .attr_ref(0)
+            .unwrap()
+            .iter()
+            .map(|feature| feature.target)
+            .collect();
+        assert_eq!(main, vec![3, 17]);
+        let street = model.attr_ref(1).unwrap().get(0).unwrap();
+        assert_eq!(street.target, 39);
+        assert!((street.weight - 2.0).abs() < 0.2);
+        assert_eq!(model.label_ref(39).unwrap().get(0).unwrap().weight, 0.5);
+
+        // Models with few labels keep their two byte words.
+        let few: Vec<String> = labels[..16].to_vec();
+        let state_features = [
+            ("main".to_string(), 3, 1.0),
+            ("street".to_string(), 15, 2.0),
+        ];
+        let narrow = PackedModel::pack(
+            header(),
+            few,
+            &[],
+            &state_features,
+            None,
+            Quantization::default(),
+        )
+        .unwrap()
+        .to_bytes()
+        .unwrap();
+        let layout = Layout::read(&narrow).unwrap();
+        assert_eq!(layout.attr_words, AttrWords::Narrow);
+        assert_eq!(layout.attr_weights.len(), 4);
+        assert_eq!(Layout::read(&wide).unwrap().attr_words, AttrWords::Wide);
+
+        // Readers from before wide words load the narrow model but refuse the
+        // wide one instead of missing its state features.
+        let reader = FormatVersion { major: 1, minor: 0 };
+        assert!(Layout::read_as(&narrow, reader).is_ok());
+        assert!(matches!(
+            Layout::read_as(&wide, reader),
+            Err(Error::IncompatibleVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn test_codebooks() {
+        let labels = vec!["road".to_string(), "locality".to_string()];
+        let state_features = [
+            ("main".to_string(), 0, 1.25),
+            ("main".to_string(), 1, -0.75),
+            ("seattle".to_string(), 1, 3.5),
+            ("street".to_string(), 0, 0.125),
+        ];
+        let pack = |quantization: Quantization| {
+            let packed = PackedModel::pack(
+                header(),
+                labels.clone(),
+                &[(0, 1, 0.5)],
+                &state_features,
+                None,
+                quantization,
+            )
+            .unwrap();
+            packed.to_bytes().unwrap()
+        };
+
+        // Two labels with at most two weights each fit a two bit codebook per
+        // label exactly, in two byte words.
+        let bytes = pack(Quantization::PerLabel { bits: 2 });
+        let layout = Layout::read(&bytes).unwrap();
+        assert_eq!(layout.attr_words, AttrWords::Coded);
+        assert!(layout.codebook.is_some());
+        assert_eq!(layout.attr_weights.len(), 8);
+        let model = Model::from_bytes(bytes).unwrap();
+        assert_eq!(model.codebook().tables.len(), 2);
+        let weights: Vec<(u32, f32)> = (0..3)
+            .flat_map(|aid| model.attr_ref(aid).unwrap().iter().collect::<Vec<_>>())
+            .map(|feature| (feature.target, feature.weight))
+            .collect();
+        assert_eq!(weights, vec![(0, 1.25), (1, -0.75), (1, 3.5), (0, 0.125)]);
+
+        // Sixteen bit weights and a label bit don't fit in two bytes.
+        let bytes = pack(Quantization::KMeans { bits: 16 });
+        assert_eq!(Layout::read(&bytes).unwrap().attr_weights.len(), 16);
+        let model = Model::from_bytes(bytes).unwrap();
+        assert_eq!(model.attr_ref(1).unwrap().get(0).unwrap().weight, 3.5);
+
+        // The 11 bit curve is stored the way it was before codebooks.
+        let bytes = pack(Quantization::default());
+        assert_eq!(Layout::read(&bytes).unwrap().codebook, None);
+        let reader = FormatVersion { major: 1, minor: 1 };
+        assert!(Layout::read_as(&bytes, reader).is_ok());
+        let bytes = pack(Quantization::Curve { bits: 6 });
+        assert!(Layout::read(&bytes).unwrap().codebook.is_some());
+        // Readers from before codebooks refuse the model by its version.
+        assert!(matches!(
+            Layout::read_as(&bytes, reader),
+            Err(Error::IncompatibleVersion { .. })
+        ));
+        let model = Model::from_bytes(bytes).unwrap();
+        assert!((model.attr_ref(2).unwrap().get(0).unwrap().weight - 0.125).abs() < 0.01);
+        assert!(model.attr_ref(1).unwrap().get(0).unwrap().weight > 0.0);
+    }
+
+    #[test]
+    fn test_integrity_checks() {
+        let bytes = packed_model(vec![0x7FF, 0x7FF, 0x8000 | 0x7FF, 0x400])
+            .to_bytes()
+            .unwrap();
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Model::from_bytes(corrupt),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+
+        let mut newer = bytes.clone();
+        newer[6..8].copy_from_slice(&(FORMAT_VERSION.minor + 1).to_le_bytes());
+        assert!(matches!(
+            Model::from_bytes(newer),
+            Err(Error::IncompatibleVersion { .. })
+        ));
+
+        let mut older = bytes;
+        older[4..6].copy_from_slice(&(FORMAT_VERSION.major - 1).to_le_bytes());
+        assert!(matches!(
+            Model::from_bytes(older),
+            Err(Error::IncompatibleVersion { .. })
+        ));
     }
 }
@@ -0,0 +1,727 @@
+use std::{f64::consts::PI, fmt, io::Write, ops::Range, sync::Arc};
+
+use fst::MapBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::extractor::ExtractorConfig;
+use crate::model::Header;
+use crate::quantize::{Codebook, Quantization, CURVE_BITS};
+use crate::schema::LabelSchema;
+use crate::tokenizer::{Tokenizer, VocabOutput};
+
+/// Magic bytes at the start of every packed model
+pub const MAGIC: [u8; 4] = *b"AIRM";
+
+/// Newest version of the packed format this crate reads and writes
+///
+/// Each minor version adds a way of storing the model that older readers
+/// can't load, so models are written with the oldest version that covers how
+/// they're stored. Models with a different major version or a newer minor
+/// version can't be read.
+pub const FORMAT_VERSION: FormatVersion = FormatVersion { major: 1, minor: 2 };
+
+/// Version of the packed model format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FormatVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl fmt::Display for FormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Where a model came from
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    /// When the model was packed, in seconds since the Unix epoch
+    pub created_at: Option<u64>,
+    /// Free-form description of the training corpus
+    pub corpus: Option<String>,
+    /// CRC-32 of the vocabulary file the model was trained with
+    pub vocab_checksum: Option<u32>,
+}
+
+/// Section ids in the packed model's section table
+pub(crate) mod section {
+    /// The crfsuite header of the model the packed model was converted from
+    pub const HEADER: u32 = 1;
+    /// Label strings, in label id order
+    pub const LABELS: u32 = 2;
+    /// Attribute vocabulary FST, mapping each attribute to its `VocabOutput`
+    pub const VOCAB: u32 = 3;
+    /// Dense `num_labels × num_labels` matrix of `f32` transition weights
+    pub const TRANSITIONS: u32 = 4;
+    /// `num_attrs + 1` `u32` offsets into the attribute weights
+    pub const ATTR_OFFSETS: u32 = 5;
+    /// Quantized `u16` state feature words, grouped by attribute, for models
+    /// with up to 16 labels
+    pub const ATTR_WEIGHTS: u32 = 6;
+    /// Serialized `ExtractorConfig`
+    pub const EXTRACTOR: u32 = 7;
+    /// Serialized `LabelSchema`
+    pub const LABEL_SCHEMA: u32 = 8;
+    /// Serialized `ModelMetadata`
+    pub const METADATA: u32 = 9;
+    /// Quantized `u32` state feature words, grouped by attribute, for models
+    /// with more than 16 labels. Added in 1.1.
+    pub const ATTR_WEIGHTS_WIDE: u32 = 10;
+    /// Serialized `Codebook` that state feature words index, for models
+    /// quantized other than with the 11 bit curve. Added in 1.2.
+    pub const CODEBOOK: u32 = 11;
+    /// State feature words indexing the codebook, in the narrowest width
+    /// that fits the labels and the codebook's bits. Added in 1.2, and kept
+    /// apart from the curve's words so older readers don't misread them.
+    pub const ATTR_CODES: u32 = 12;
+}
+
+/// Bit layout of the quantized state feature words, which is as narrow as the
+/// number of labels and weight bits allow
+///
+/// The top bit of a word is set if the next word belongs to the same
+/// attribute, the bits below it hold the target label and the low bits hold
+/// the quantized weight, 11 of them for the curve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WordFormat {
+    /// Size of a word in bytes
+    pub bytes: usize,
+    target_bits: u32,
+    weight_bits: u32,
+}
+
+impl WordFormat {
+    /// `u16` words with 4 bit targets and 11 bit curved weights
+    pub const NARROW: WordFormat = WordFormat {
+        bytes: 2,
+        target_bits: 4,
+        weight_bits: CURVE_BITS,
+    };
+    /// `u32` words with 20 bit targets and 11 bit curved weights
+    pub const WIDE: WordFormat = WordFormat {
+        bytes: 4,
+        target_bits: 20,
+        weight_bits: CURVE_BITS,
+    };
+
+    /// The narrowest format that can address `num_labels` labels with
+    /// `weight_bits` bit weights
+    pub fn for_labels(num_labels: usize, weight_bits: u32) -> WordFormat {
+        let label_bits = usize::BITS - num_labels.saturating_sub(1).leading_zeros();
+        let bytes = if 1 + label_bits + weight_bits <= 16 {
+            2
+        } else {
+            4
+        };
+        WordFormat {
+            bytes,
+            target_bits: bytes as u32 * 8 - 1 - weight_bits,
+            weight_bits,
+        }
+    }
+
+    pub fn max_labels(&self) -> u32 {
+        1 << self.target_bits
+    }
+
+    fn has_more_bit(&self) -> u32 {
+        1 << (self.bytes * 8 - 1)
+    }
+
+    fn weight_mask(&self) -> u32 {
+        (1 << self.weight_bits) - 1
+    }
+
+    pub fn encode(&self, has_more: bool, target: u32, quantized_weight: u32) -> u32 {
+        debug_assert!(target < self.max_labels());
+        let has_more = if has_more { self.has_more_bit() } else { 0 };
+        has_more | (target << self.weight_bits) | (quantized_weight & self.weight_mask())
+    }
+
+    pub fn has_more(&self, word: u32) -> bool {
+        word & self.has_more_bit() != 0
+    }
+
+    pub fn target(&self, word: u32) -> u32 {
+        (word >> self.weight_bits) & (self.max_labels() - 1)
+    }
+
+    /// Index of the word's weight in its codebook table
+    pub fn weight_index(&self, word: u32) -> u32 {
+        word & self.weight_mask()
+    }
+
+    /// Read the word at `index` of little-endian words
+    pub fn read(&self, words: &[u8], index: usize) -> u32 {
+        let at = index * self.bytes;
+        match self.bytes {
+            2 => u16::from_le_bytes([words[at], words[at + 1]]) as u32,
+            _ => read_u32(words, at),
+        }
+    }
+}
+
+const HEADER_SIZE: usize = 48;
+const PREAMBLE_SIZE: usize = 12;
+const SECTION_ENTRY_SIZE: usize = 16;
+
+/// A model in the form it is written to disk
+///
+/// The serialized form (see `to_bytes`) is laid out so that `Model` can use it
+/// in place. All integers are little-endian:
+///
+/// ```text
+/// magic          b"AIRM"
+/// major_version  u16
+/// minor_version  u16
+/// num_sections   u32
+/// sections       num_sections × (id: u32, offset: u32, len: u32, crc32: u32)
+/// ...section data, each section starting on a 4 byte boundary
+/// ```
+///
+/// Every section is checked against its CRC-32 when the model is loaded.
+///
+/// Each quantized state feature is a word in the narrowest `WordFormat` that
+/// fits the labels: a `u16` with bit 15 set if the next word belongs to the
+/// same attribute, bits 11-14 holding the target label and the low 11 bits
+/// holding the curved weight, or a `u32` with a 20 bit target for models with
+/// more than 16 labels. Models quantized with a learned codebook, or with a
+/// curve of other than 11 bits, store the codebook and size their words to
+/// its bits instead.
+#[derive(Clone)]
+pub struct PackedModel {
+    pub header: Header,
+    pub attr_vocab_fst: Vec<u8>,
+    pub labels: Vec<String>,
+    pub unquantized_label_weights: Vec<(u32, u32, f32)>,
+    /// Quantized state feature words in the `WordFormat` for the number of
+    /// labels and the codebook's bits
+    pub packed_attr_weights: Vec<u32>,
+    /// Weights the state feature words decode to
+    pub codebook: Codebook,
+    /// Feature extractor the model was trained with
+    pub extractor: ExtractorConfig,
+    /// Label schema the model was trained with
+    pub label_schema: LabelSchema,
+    /// Where the model came from
+    pub metadata: ModelMetadata,
+}
+
+impl fmt::Debug for PackedModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PackedModel")
+            .field("header", &self.header)
+            .field("labels", &self.labels)
+            .field("num_attr_weights", &self.packed_attr_weights.len())
+            .finish()
+    }
+}
+
+impl PackedModel {
+    /// Quantize a model's weights into the packed representation.
+    ///
+    /// `transitions` are `(source, target, weight)` triples of label ids and
+    /// `state_features` are `(attribute, target, weight)` triples. Attributes
+    /// are numbered in bytewise order. If `pieces` is given, attributes that
+    /// are word pieces in its vocabulary keep their log-probabilities so that
+    /// the model's tokenizer segments words the same way. State feature
+    /// weights are quantized with `quantization`.
+    ///
+    /// The counts in `header` are replaced with those of the packed model, and
+    /// its offsets, which point into the crfsuite model, are cleared.
+    pub fn pack(
+        header: Header,
+        labels: Vec<String>,
+        transitions: &[(u32, u32, f64)],
+        state_features: &[(String, u32, f64)],
+        pieces: Option<&Tokenizer>,
+        quantization: Quantization,
+    ) -> Result<PackedModel> {
+        let num_labels = labels.len() as u32;
+        let codebook = quantization.codebook(labels.len(), state_features)?;
+        let format = WordFormat::for_labels(labels.len(), codebook.bits);
+        let check_label = |label: u32| {
+            if label >= num_labels || label >= format.max_labels() {
+                Err(Error::LabelOutOfRange {
+                    target: label,
+                    num_labels: num_labels.min(format.max_labels()),
+                })
+            } else {
+                Ok(())
+            }
+        };
+
+        let mut unquantized_label_weights = Vec::with_capacity(transitions.len());
+        for (source, target, weight) in transitions {
+            check_label(*source)?;
+            check_label(*target)?;
+            unquantized_label_weights.push((*source, *target, *weight as f32));
+        }
+
+        let mut sorted: Vec<&(String, u32, f64)> = state_features.iter().collect();
+        sorted.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()).then(a.1.cmp(&b.1)));
+
+        let mut vocab_builder = MapBuilder::memory();
+        let mut packed_attr_weights = Vec::with_capacity(sorted.len());
+        let mut num_attrs = 0u32;
+        for (index, (attr, target, weight)) in sorted.iter().enumerate() {
+            check_label(*target)?;
+            if index == 0 || sorted[index - 1].0 != *attr {
+                let output = VocabOutput {
+                    id: num_attrs,
+                    log_prob: pieces.and_then(|pieces| pieces.piece_log_prob(attr)),
+                };
+                vocab_builder.insert(attr, output.encode())?;
+                num_attrs += 1;
+            }
+            let has_more = matches!(sorted.get(index + 1), Some(next) if &next.0 == attr);
+            let quantized_weight = quantization.quantize(&codebook, *target, *weight);
+            packed_attr_weights.push(format.encode(has_more, *target, quantized_weight));
+        }
+
+        Ok(PackedModel {
+            header: Header {
+                size: 0,
+                num_features: (unquantized_label_weights.len() + packed_attr_weights.len()) as u32,
+                num_labels,
+                num_attrs,
+                off_features: 0,
+                off_labels: 0,
+                off_attrs: 0,
+                off_label_refs: 0,
+                off_attr_refs: 0,
+                ..header
+            },
+            attr_vocab_fst: vocab_builder.into_inner()?,
+            labels,
+            unquantized_label_weights,
+            packed_attr_weights,
+            codebook,
+            extractor: ExtractorConfig::default(),
+            label_schema: LabelSchema::default(),
+            metadata: ModelMetadata::default(),
+        })
+    }
+
+    /// Serialize the model into the packed format that `Model::from_bytes`
+    /// reads in place.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let num_labels = self.labels.len();
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&self.header.magic);
+        header.extend_from_slice(&self.header.size.to_le_bytes());
+        header.extend_from_slice(&self.header.r#type);
+        for value in [
+            self.header.version,
+            self.header.num_features,
+            self.header.num_labels,
+            self.header.num_attrs,
+            self.header.off_features,
+            self.header.off_labels,
+            self.header.off_attrs,
+            self.header.off_label_refs,
+            self.header.off_attr_refs,
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut labels = Vec::new();
+        labels.extend_from_slice(&(num_labels as u32).to_le_bytes());
+        let mut label_offset = 0u32;
+        labels.extend_from_slice(&label_offset.to_le_bytes());
+        for label in &self.labels {
+            label_offset += label.len() as u32;
+            labels.extend_from_slice(&label_offset.to_le_bytes());
+        }
+        for label in &self.labels {
+            labels.extend_from_slice(label.as_bytes());
+        }
+
+        let mut transition_matrix = vec![0f32; num_labels * num_labels];
+        for (source, target, weight) in &self.unquantized_label_weights {
+            for label in [*source, *target] {
+                if label as usize >= num_labels {
+                    return Err(Error::LabelOutOfRange {
+                        target: label,
+                        num_labels: num_labels as u32,
+                    });
+                }
+            }
+            transition_matrix[*source as usize * num_labels + *target as usize] = *weight;
+        }
+        let transitions: Vec<u8> = transition_matrix
+            .iter()
+            .flat_map(|weight| weight.to_le_bytes())
+            .collect();
+
+        // The runtime model finds each attribute's words through an offsets
+        // table rather than by scanning the has_more flags.
+        let format = WordFormat::for_labels(num_labels, self.codebook.bits);
+        let mut attr_offsets = Vec::new();
+        attr_offsets.extend_from_slice(&0u32.to_le_bytes());
+        let mut attr_weights = Vec::with_capacity(self.packed_attr_weights.len() * format.bytes);
+        for (index, word) in self.packed_attr_weights.iter().enumerate() {
+            if !format.has_more(*word) {
+                attr_offsets.extend_from_slice(&(index as u32 + 1).to_le_bytes());
+            }
+            if format.bytes == 2 {
+                let word = u16::try_from(*word).map_err(|_| {
+                    Error::InvalidModel(format!(
+                        "state feature word {:#x} doesn't fit the 16 bit format",
+                        word
+                    ))
+                })?;
+                attr_weights.extend_from_slice(&word.to_le_bytes());
+            } else {
+                attr_weights.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        let extractor = bincode2::serialize(&self.extractor)?;
+        let label_schema = bincode2::serialize(&self.label_schema)?;
+        let metadata = bincode2::serialize(&self.metadata)?;
+
+        // Models quantized with the 11 bit curve are laid out as before
+        // codebooks existed, so older readers can still load them.
+        self.codebook.validate(num_labels)?;
+        let codebook = bincode2::serialize(&self.codebook)?;
+        let (attr_weights_section, version) = match (self.codebook.is_legacy(), format.bytes) {
+            (true, 2) => (section::ATTR_WEIGHTS, FormatVersion { major: 1, minor: 0 }),
+            (true, _) => (
+                section::ATTR_WEIGHTS_WIDE,
+                FormatVersion { major: 1, minor: 1 },
+            ),
+            (false, _) => (section::ATTR_CODES, FORMAT_VERSION),
+        };
+
+        let mut sections: Vec<(u32, &[u8])> = vec![
+            (section::HEADER, &header),
+            (section::LABELS, &labels),
+            (section::VOCAB, &self.attr_vocab_fst),
+            (section::TRANSITIONS, &transitions),
+            (section::ATTR_OFFSETS, &attr_offsets),
+            (attr_weights_section, &attr_weights),
+            (section::EXTRACTOR, &extractor),
+            (section::LABEL_SCHEMA, &label_schema),
+            (section::METADATA, &metadata),
+        ];
+        if !self.codebook.is_legacy() {
+            sections.push((section::CODEBOOK, &codebook));
+        }
+        let table_end = PREAMBLE_SIZE + SECTION_ENTRY_SIZE * sections.len();
+        let mut bytes = Vec::with_capacity(
+            table_end
+                + sections
+                    .iter()
+                    .map(|(_, data)| data.len() + 3)
+                    .sum::<usize>(),
+        );
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&version.major.to_le_bytes());
+        bytes.extend_from_slice(&version.minor.to_le_bytes());
+        bytes.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+        let mut offset = table_end;
+        for (id, data) in &sections {
+            offset = align(offset);
+            let checksum = crc32fast::hash(data);
+            for value in [*id, offset as u32, data.len() as u32, checksum] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            offset += data.len();
+        }
+        for (_, data) in &sections {
+            bytes.resize(align(bytes.len()), 0);
+            bytes.extend_from_slice(data);
+        }
+        Ok(bytes)
+    }
+
+    /// Write the model in the packed format.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.to_bytes()?)?;
+        Ok(())
+    }
+}
+
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Map a weight onto the `bits` bit curve used by packed state features.
+pub(crate) fn quantize_weight(weight: f64, bits: u32) -> u32 {
+    let mask = (1u32 << bits) - 1;
+    let curved_weight =
+        f64::signum(weight) * f64::powf(f64::atan(5.0 * f64::abs(weight)), 1.0 / 7.0) / PI + 0.5;
+    // Rounding can step past the largest curved weight, where the inverse
+    // wraps around. That only happens with fewer bits than the usual 11.
+    let largest = f64::floor((f64::powf(PI / 2.0, 1.0 / 7.0) / PI + 0.5) * mask as f64) as u32;
+    (f64::round(curved_weight * mask as f64) as u32 & mask).clamp(mask - largest, largest)
+}
+
+/// Invert `quantize_weight`.
+pub(crate) fn dequantize_weight(raw_packed_weight: u32, bits: u32) -> f32 {
+    let mask = (1u32 << bits) - 1;
+    let weight_curved = PI * ((raw_packed_weight & mask) as f64 / mask as f64 - 0.5);
+    (f64::tan(f64::powf(weight_curved, 7.0)) / 5.0) as f32
+}
+
+/// Bytes backing a loaded model
+///
+/// Cloning is cheap: every clone shares the buffer the model was loaded from,
+/// whether that is a `&'static [u8]`, a `Vec<u8>` or a memory map.
+#[derive(Clone)]
+pub struct ModelBytes {
+    data: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    range: Range<usize>,
+}
+
+impl ModelBytes {
+    pub fn new<D: AsRef<[u8]> + Send + Sync + 'static>(data: D) -> ModelBytes {
+        let len = data.as_ref().len();
+        ModelBytes {
+            data: Arc::new(data),
+            range: 0..len,
+        }
+    }
+
+    /// A view of a sub-range of these bytes, sharing the same buffer
+    pub(crate) fn slice(&self, range: Range<usize>) -> ModelBytes {
+        debug_assert!(range.end <= self.range.len());
+        ModelBytes {
+            data: self.data.clone(),
+            range: self.range.start + range.start..self.range.start + range.end,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ModelBytes {
+    fn as_ref(&self) -> &[u8] {
+        &(*self.data).as_ref()[self.range.clone()]
+    }
+}
+
+impl fmt::Debug for ModelBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModelBytes")
+            .field("len", &self.range.len())
+            .finish()
+    }
+}
+
+/// Read a little-endian `u32` that the caller has already bounds checked.
+pub(crate) fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// Which section a model's state feature words are in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AttrWords {
+    /// `WordFormat::NARROW` words on the 11 bit curve
+    Narrow,
+    /// `WordFormat::WIDE` words on the 11 bit curve
+    Wide,
+    /// Words indexing the model's codebook
+    Coded,
+}
+
+/// Byte ranges of each section of a packed model
+#[derive(Debug, Clone)]
+pub(crate) struct Layout {
+    pub version: FormatVersion,
+    pub header: Range<usize>,
+    pub labels: Range<usize>,
+    pub vocab: Range<usize>,
+    pub transitions: Range<usize>,
+    pub attr_offsets: Range<usize>,
+    pub attr_weights: Range<usize>,
+    pub attr_words: AttrWords,
+    pub codebook: Option<Range<usize>>,
+    pub extractor: Option<Range<usize>>,
+    pub label_schema: Option<Range<usize>>,
+    pub metadata: Option<Range<usize>>,
+}
+
+impl Layout {
+    pub fn read(bytes: &[u8]) -> Result<Layout> {
+        Layout::read_as(bytes, FORMAT_VERSION)
+    }
+
+    /// Read the layout as a reader of version `supported` would
+    pub(crate) fn read_as(bytes: &[u8], supported: FormatVersion) -> Result<Layout> {
+        if bytes.len() < PREAMBLE_SIZE || bytes[0..4] != MAGIC {
+            return Err(Error::InvalidModel(
+                "not a packed model, magic mismatch".to_string(),
+            ));
+        }
+        let version = FormatVersion {
+            major: u16::from_le_bytes([bytes[4], bytes[5]]),
+            minor: u16::from_le_bytes([bytes[6], bytes[7]]),
+        };
+        if version.major != supported.major || version.minor > supported.minor {
+            return Err(Error::IncompatibleVersion {
+                found: version,
+                supported,
+            });
+        }
+        let num_sections = read_u32(bytes, 8) as usize;
+        let table_end = num_sections
+            .checked_mul(SECTION_ENTRY_SIZE)
+            .and_then(|size| size.checked_add(PREAMBLE_SIZE))
+            .filter(|end| *end <= bytes.len())
+            .ok_or_else(|| Error::InvalidModel("section table is truncated".to_string()))?;
+        let mut sections = Vec::with_capacity(num_sections);
+        for entry in (PREAMBLE_SIZE..table_end).step_by(SECTION_ENTRY_SIZE) {
+            let id = read_u32(bytes, entry);
+            let offset = read_u32(bytes, entry + 4) as usize;
+            let len = read_u32(bytes, entry + 8) as usize;
+            let data = offset
+                .checked_add(len)
+                .filter(|end| offset >= table_end && *end <= bytes.len())
+                .map(|end| offset..end)
+                .ok_or_else(|| {
+                    Error::InvalidModel(format!("section {} lies outside of the model", id))
+                })?;
+            if crc32fast::hash(&bytes[data.clone()]) != read_u32(bytes, entry + 12) {
+                return Err(Error::ChecksumMismatch { section: id });
+            }
+            sections.push((id, data));
+        }
+        let find_optional = |id: u32| -> Option<Range<usize>> {
+            sections
+                .iter()
+                .find(|(section, _)| *section == id)
+                .map(|(_, data)| data.clone())
+        };
+        let find = |id: u32| -> Result<Range<usize>> {
+            find_optional(id).ok_or_else(|| Error::InvalidModel(format!("missing section {}", id)))
+        };
+        let words: Vec<(Range<usize>, AttrWords)> = [
+            (section::ATTR_WEIGHTS, AttrWords::Narrow),
+            (section::ATTR_WEIGHTS_WIDE, AttrWords::Wide),
+            (section::ATTR_CODES, AttrWords::Coded),
+        ]
+        .into_iter()
+        .filter_map(|(id, words)| find_optional(id).map(|data| (data, words)))
+        .collect();
+        let (attr_weights, attr_words) = match words.as_slice() {
+            [words] => words.clone(),
+            [] => return Err(Error::InvalidModel("missing state features".to_string())),
+            _ => {
+                return Err(Error::InvalidModel(
+                    "model has more than one section of state features".to_string(),
+                ))
+            }
+        };
+        let codebook = find_optional(section::CODEBOOK);
+        if codebook.is_some() != (attr_words == AttrWords::Coded) {
+            return Err(Error::InvalidModel(
+                "state features must index a codebook exactly when the model has one".to_string(),
+            ));
+        }
+        Ok(Layout {
+            version,
+            header: find(section::HEADER)?,
+            labels: find(section::LABELS)?,
+            vocab: find(section::VOCAB)?,
+            transitions: find(section::TRANSITIONS)?,
+            attr_offsets: find(section::ATTR_OFFSETS)?,
+            attr_weights,
+            attr_words,
+            codebook,
+            extractor: find_optional(section::EXTRACTOR),
+            label_schema: find_optional(section::LABEL_SCHEMA),
+            metadata: find_optional(section::METADATA),
+        })
+    }
+}
+
+pub(crate) fn read_header(bytes: &[u8]) -> Result<Header> {
+    if bytes.len() != HEADER_SIZE {
+        return Err(Error::InvalidModel(format!(
+            "header is {} bytes, expected {}",
+            bytes.len(),
+            HEADER_SIZE
+        )));
+    }
+    let field = |index: usize| read_u32(bytes, 12 + 4 * index);
+    Ok(Header {
+        magic: bytes[0..4].try_into().unwrap(),
+        size: read_u32(bytes, 4),
+        r#type: bytes[8..12].try_into().unwrap(),
+        version: field(0),
+        num_features: field(1),
+        num_labels: field(2),
+        num_attrs: field(3),
+        off_features: field(4),
+        off_labels: field(5),
+        off_attrs: field(6),
+        off_label_refs: field(7),
+        off_attr_refs: field(8),
+    })
+}
+
+pub(crate) fn read_labels(bytes: &[u8]) -> Result<Vec<String>> {
+    let truncated = || Error::InvalidModel("label section is truncated".to_string());
+    if bytes.len() < 4 {
+        return Err(truncated());
+    }
+    let num_labels = read_u32(bytes, 0) as usize;
+    let text_start = num_labels
+        .checked_add(2)
+        .and_then(|count| count.checked_mul(4))
+        .filter(|start| *start <= bytes.len())
+        .ok_or_else(truncated)?;
+    let text = &bytes[text_start..];
+    (0..num_labels)
+        .map(|label| {
+            let start = read_u32(bytes, 4 + 4 * label) as usize;
+            let end = read_u32(bytes, 8 + 4 * label) as usize;
+            let label = text.get(start..end).ok_or_else(truncated)?;
+            String::from_utf8(label.to_vec())
+                .map_err(|_| Error::InvalidModel(format!("label {:?} is not valid UTF-8", label)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantization_roundtrip() {
+        for weight in [-3.0, -0.5, -0.01, 0.0, 0.01, 0.5, 3.0] {
+            let restored = dequantize_weight(quantize_weight(weight, 11), 11) as f64;
+            assert!(
+                (restored - weight).abs() <= 0.05 * weight.abs().max(0.1),
+                "{} came back as {}",
+                weight,
+                restored
+            );
+        }
+    }
+
+    #[test]
+    fn test_layout_rejects_truncated_table() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.major.to_le_bytes());
+        bytes.extend_from_slice(&FORMAT_VERSION.minor.to_le_bytes());
+        bytes.extend_from_slice(&100u32.to_le_bytes());
+        assert!(Layout::read(&bytes).is_err());
+        assert!(Layout::read(b"lCRF\0\0\0\0\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn test_layout_checks_version() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(FORMAT_VERSION.major + 1).to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            Layout::read(&bytes),
+            Err(Error::IncompatibleVersion { .. })
+        ));
+    }
+}
@@ -1,43 +1,209 @@
-use std::u8;
-
 use crate::{
-    model::{Model, PackedModel},
-    tagger::Attribute,
-    tokenizer::Tokenizer,
+    address::{ParsedAddress, ParsedToken},
+    error::{Error, Result},
+    extractor::FeatureExtractor,
+    model::Model,
+    tagger::{Constraints, Hypothesis, Tagger},
+    tokenizer::{Token, Tokenizer},
 };
 pub struct Parser {
     tokenizer: Tokenizer,
     model: Model,
 }
 
-impl<'a> Parser {
-    pub fn new(packed_model_data: &[u8]) -> Parser {
-        let packed_model: PackedModel = bincode2::deserialize(packed_model_data).unwrap();
-        let model = Model::from(packed_model);
-        let tokenizer = Tokenizer::new(&model.get_vocab());
-        Parser { tokenizer, model }
+impl Parser {
+    /// Load a parser from a packed model.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the model is corrupt. Use `try_new` to handle that case.
+    pub fn new<D: AsRef<[u8]> + Send + Sync + 'static>(packed_model_data: D) -> Parser {
+        Parser::try_new(packed_model_data).expect("Failed to load packed model")
     }
 
-    pub fn parse(&self, query: &str) -> Vec<String> {
-        let mut tagger = self.model.tagger().unwrap();
-        let features = self.tokenizer.tokenize(query);
-        let attributes: Vec<Vec<Attribute>> = features
-            .iter()
-            .map(|token_attribs| {
-                let attrib_vec: Vec<Attribute> = token_attribs
-                    .iter()
-                    .map(|id| Attribute::new(self.tokenizer.stringify_feature(*id), *id as f64))
-                    .collect();
-                attrib_vec
-            })
-            .collect();
+    /// Load a parser from a packed model.
+    ///
+    /// Models written in an incompatible format version, including a newer
+    /// minor version, are refused. Models that load with problems, such as a
+    /// vocabulary without piece log-probabilities, are accepted and the
+    /// problems listed by `warnings`.
+    ///
+    /// The model is used in place, so `packed_model_data` can be an
+    /// `include_bytes!` slice or a memory-mapped file as well as a `Vec<u8>`.
+    pub fn try_new<D: AsRef<[u8]> + Send + Sync + 'static>(packed_model_data: D) -> Result<Parser> {
+        let model = Model::from_bytes(packed_model_data)?;
+        let tokenizer = Tokenizer::for_model(&model);
+        Ok(Parser { tokenizer, model })
+    }
+
+    /// Problems found while loading the model that didn't stop it from
+    /// loading
+    pub fn warnings(&self) -> &[String] {
+        self.model.warnings()
+    }
 
+    pub fn parse(&self, query: &str) -> Result<Vec<String>> {
+        let (_tokens, mut tagger) = self.tagger(query)?;
         let tags: Vec<String> = tagger
-            .tag(&attributes)
-            .unwrap()
+            .viterbi()?
             .iter()
             .map(|tag| tag.to_string())
             .collect();
-        tags
+        Ok(tags)
+    }
+
+    /// Parse a query, grouping its tokens into labeled components that point
+    /// back into the original query.
+    pub fn parse_address(&self, query: &str) -> Result<ParsedAddress> {
+        Ok(self
+            .parse_nbest(query, 1)?
+            .pop()
+            .unwrap_or_else(|| ParsedAddress::new(query, vec![], 0.0, 1.0)))
+    }
+
+    /// Parse a query into its `k` most likely distinct interpretations, best
+    /// first.
+    pub fn parse_nbest(&self, query: &str, k: usize) -> Result<Vec<ParsedAddress>> {
+        let (tokens, mut tagger) = self.tagger(query)?;
+        let hypotheses = tagger.nbest(k)?;
+        if hypotheses.is_empty() {
+            return Ok(vec![]);
+        }
+        let marginals = tagger.marginals()?;
+        hypotheses
+            .into_iter()
+            .map(|hypothesis| self.to_address(query, &tokens, hypothesis, &marginals))
+            .collect()
+    }
+
+    /// Parse a query, only considering interpretations that satisfy
+    /// `constraints`. Constraint positions are indices into the tokens of the
+    /// parsed address.
+    pub fn parse_with_constraints(
+        &self,
+        query: &str,
+        constraints: &Constraints,
+    ) -> Result<ParsedAddress> {
+        let (tokens, mut tagger) = self.tagger(query)?;
+        if tokens.is_empty() {
+            return Ok(ParsedAddress::new(query, vec![], 0.0, 1.0));
+        }
+        let hypothesis = tagger.constrained(constraints)?;
+        let marginals = tagger.marginals()?;
+        self.to_address(query, &tokens, hypothesis, &marginals)
+    }
+
+    fn to_address(
+        &self,
+        query: &str,
+        tokens: &[Token],
+        hypothesis: Hypothesis,
+        marginals: &[Vec<f64>],
+    ) -> Result<ParsedAddress> {
+        let parsed_tokens = tokens
+            .iter()
+            .zip(hypothesis.labels)
+            .zip(marginals)
+            .map(|((token, label), marginals)| {
+                let label_id = self
+                    .model
+                    .to_label_id(label)
+                    .ok_or_else(|| Error::UnknownLabel(label.to_string()))?;
+                Ok(ParsedToken {
+                    span: token.span.clone(),
+                    text: token.text.clone(),
+                    label: label.to_string(),
+                    coarse_label: self.model.label_schema().coarse(label).to_string(),
+                    probability: marginals[label_id as usize],
+                })
+            })
+            .collect::<Result<Vec<ParsedToken>>>()?;
+        Ok(ParsedAddress::new(
+            query,
+            parsed_tokens,
+            hypothesis.score,
+            hypothesis.probability.unwrap_or(f64::NAN),
+        ))
+    }
+
+    /// Tokenize a query and set a tagger to its tokens.
+    fn tagger(&self, query: &str) -> Result<(Vec<Token>, Tagger<'_>)> {
+        let extractor = self.model.extractor();
+        let tokens = extractor.tokenize(&self.tokenizer, query);
+        let mut tagger = self.model.tagger()?;
+        tagger.set_attributes(&extractor.attributes(&self.tokenizer, &tokens))?;
+        Ok((tokens, tagger))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::Header, packed::PackedModel, quantize::Quantization, template::FeatureTemplates,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_try_new_rejects_corrupt_model() {
+        assert!(Parser::try_new(b"").is_err());
+        assert!(Parser::try_new(b"definitely not a packed model").is_err());
+    }
+
+    fn parser(templates: FeatureTemplates) -> Parser {
+        let header = Header {
+            magic: *b"lCRF",
+            size: 0,
+            r#type: *b"FOMC",
+            version: 100,
+            num_features: 0,
+            num_labels: 2,
+            num_attrs: 2,
+            off_features: 0,
+            off_labels: 0,
+            off_attrs: 0,
+            off_label_refs: 0,
+            off_attr_refs: 0,
+        };
+        let mut packed = PackedModel::pack(
+            header,
+            vec!["road".to_string(), "locality".to_string()],
+            &[],
+            &[("main".to_string(), 0, 0.05), ("BOS".to_string(), 1, 2.0)],
+            None,
+            Quantization::default(),
+        )
+        .unwrap();
+        packed.extractor.templates = templates;
+        packed.label_schema.coarse = BTreeMap::from([("road".to_string(), "street".to_string())]);
+        Parser::try_new(packed.to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_templates_applied() {
+        assert_eq!(
+            parser(FeatureTemplates::default())
+                .parse("main main")
+                .unwrap(),
+            vec!["road", "road"]
+        );
+        let templates = FeatureTemplates {
+            boundaries: true,
+            ..FeatureTemplates::default()
+        };
+        assert_eq!(
+            parser(templates).parse("main main").unwrap(),
+            vec!["locality", "road"]
+        );
+    }
+
+    #[test]
+    fn test_coarse_labels() {
+        let parsed = parser(FeatureTemplates::default())
+            .parse_address("main main")
+            .unwrap();
+        assert_eq!(parsed.labels(), vec!["road", "road"]);
+        assert_eq!(parsed.coarse_labels(), vec!["street", "street"]);
+        assert_eq!(parsed.components[0].coarse_label, "street");
     }
 }
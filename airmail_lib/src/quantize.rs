@@ -0,0 +1,553 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::context::{Context, Flag, Reset};
+use crate::error::{Error, Result};
+use crate::model::Model;
+use crate::packed::{dequantize_weight, quantize_weight};
+use crate::tagger::Attribute;
+
+/// Largest number of bits a state feature word can spend on its weight
+pub const MAX_BITS: u32 = 16;
+
+/// Fewest bits per weight the curve works with. With one bit, its only two
+/// levels are the extremes it never uses.
+pub const MIN_CURVE_BITS: u32 = 2;
+
+/// Bits per weight of the curve packed models have always used
+pub const CURVE_BITS: u32 = 11;
+
+/// How state feature weights are mapped onto the bits each packed word has
+/// for them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantization {
+    /// A fixed curve that spends most of its levels on small weights
+    Curve { bits: u32 },
+    /// One codebook for every label, learned with k-means
+    KMeans { bits: u32 },
+    /// A codebook for each label, learned with k-means
+    PerLabel { bits: u32 },
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Quantization::Curve { bits: CURVE_BITS }
+    }
+}
+
+impl Quantization {
+    /// The same kind of quantization with a different bit width
+    pub fn with_bits(self, bits: u32) -> Quantization {
+        match self {
+            Quantization::Curve { .. } => Quantization::Curve { bits },
+            Quantization::KMeans { .. } => Quantization::KMeans { bits },
+            Quantization::PerLabel { .. } => Quantization::PerLabel { bits },
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        match self {
+            Quantization::Curve { bits }
+            | Quantization::KMeans { bits }
+            | Quantization::PerLabel { bits } => *bits,
+        }
+    }
+
+    /// Build the codebook for a model's `(attribute, target, weight)` state
+    /// features.
+    pub fn codebook(
+        &self,
+        num_labels: usize,
+        state_features: &[(String, u32, f64)],
+    ) -> Result<Codebook> {
+        let bits = self.bits();
+        if bits == 0 || bits > MAX_BITS {
+            return Err(Error::InvalidInput(format!(
+                "weights must be quantized to between 1 and {} bits, not {}",
+                MAX_BITS, bits
+            )));
+        }
+        let levels = 1usize << bits;
+        let tables = match self {
+            Quantization::Curve { .. } if bits < MIN_CURVE_BITS => {
+                return Err(Error::InvalidInput(format!(
+                    "the curve needs at least {} bits per weight, not {}",
+                    MIN_CURVE_BITS, bits
+                )));
+            }
+            Quantization::Curve { .. } => return Ok(Codebook::curve(bits)),
+            Quantization::KMeans { .. } => {
+                let weights: Vec<f64> = state_features.iter().map(|feature| feature.2).collect();
+                vec![kmeans(weights, levels)]
+            }
+            Quantization::PerLabel { .. } => {
+                let mut weights = vec![Vec::new(); num_labels];
+                for (_, target, weight) in state_features {
+                    if let Some(label_weights) = weights.get_mut(*target as usize) {
+                        label_weights.push(*weight);
+                    }
+                }
+                weights
+                    .into_iter()
+                    .map(|weights| kmeans(weights, levels))
+                    .collect()
+            }
+        };
+        Ok(Codebook { bits, tables })
+    }
+
+    /// Quantize a weight of a state feature targeting `target` to an index
+    /// into `codebook`, which this quantization built
+    pub fn quantize(&self, codebook: &Codebook, target: u32, weight: f64) -> u32 {
+        match self {
+            // The curve's table isn't ordered at its extremes, which are
+            // never used, so weights go through the curve itself.
+            Quantization::Curve { bits } => quantize_weight(weight, *bits),
+            _ => codebook.nearest(target, weight),
+        }
+    }
+
+    /// How far quantizing with `codebook`, which this quantization built,
+    /// moves each of a model's state feature weights
+    pub fn reconstruction_error(
+        &self,
+        codebook: &Codebook,
+        state_features: &[(String, u32, f64)],
+    ) -> ReconstructionError {
+        let mut error = ReconstructionError {
+            features: state_features.len(),
+            ..ReconstructionError::default()
+        };
+        let mut squared = 0.0;
+        for (_, target, weight) in state_features {
+            let index = self.quantize(codebook, *target, *weight);
+            let difference = (codebook.weight(*target, index) as f64 - weight).abs();
+            squared += difference * difference;
+            error.mean_abs += difference;
+            error.max_abs = error.max_abs.max(difference);
+        }
+        if !state_features.is_empty() {
+            error.rmse = (squared / state_features.len() as f64).sqrt();
+            error.mean_abs /= state_features.len() as f64;
+        }
+        error
+    }
+}
+
+impl fmt::Display for Quantization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Quantization::Curve { .. } => "curve",
+            Quantization::KMeans { .. } => "kmeans",
+            Quantization::PerLabel { .. } => "per-label",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Quantization {
+    type Err = String;
+
+    /// Parse the name of a quantization, which gets the curve's bit width
+    /// until `with_bits` says otherwise
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bits = CURVE_BITS;
+        match s {
+            "curve" => Ok(Quantization::Curve { bits }),
+            "kmeans" => Ok(Quantization::KMeans { bits }),
+            "per-label" => Ok(Quantization::PerLabel { bits }),
+            _ => Err(format!(
+                "unknown quantization `{}`, expected `curve`, `kmeans` or `per-label`",
+                s
+            )),
+        }
+    }
+}
+
+/// Weights that quantized state features decode to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Codebook {
+    /// Number of bits of each state feature word that index the codebook
+    pub bits: u32,
+    /// One table shared by every label, or one table per label. Each holds
+    /// at most `2^bits` weights, in increasing order for learned tables.
+    pub tables: Vec<Vec<f32>>,
+}
+
+impl Codebook {
+    /// The fixed curve, which models without a codebook use with 11 bits
+    pub fn curve(bits: u32) -> Codebook {
+        let max = (1u32 << bits) - 1;
+        let table = (0..=max)
+            .map(|index| dequantize_weight(index, bits))
+            .collect();
+        Codebook {
+            bits,
+            tables: vec![table],
+        }
+    }
+
+    /// Whether this is the codebook of models that don't store one
+    pub fn is_legacy(&self) -> bool {
+        *self == Codebook::curve(CURVE_BITS)
+    }
+
+    /// The table that state features targeting `target` are decoded with
+    pub fn table(&self, target: u32) -> &[f32] {
+        match self.tables.len() {
+            1 => &self.tables[0],
+            _ => self.tables.get(target as usize).map_or(&[], Vec::as_slice),
+        }
+    }
+
+    /// Index of the table entry closest to `weight`, for tables in increasing
+    /// order
+    pub fn nearest(&self, target: u32, weight: f64) -> u32 {
+        let table = self.table(target);
+        let index = table.partition_point(|entry| (*entry as f64) < weight);
+        if index == table.len() {
+            return index.saturating_sub(1) as u32;
+        }
+        if index > 0 && weight - table[index - 1] as f64 <= table[index] as f64 - weight {
+            return (index - 1) as u32;
+        }
+        index as u32
+    }
+
+    /// Weight of the quantized state feature `index` targeting `target`
+    pub fn weight(&self, target: u32, index: u32) -> f32 {
+        self.table(target)
+            .get(index as usize)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// Check that the codebook fits its bit width and `num_labels` labels
+    pub(crate) fn validate(&self, num_labels: usize) -> Result<()> {
+        let invalid = |reason: String| Err(Error::InvalidModel(reason));
+        if self.bits == 0 || self.bits > MAX_BITS {
+            return invalid(format!("codebook has {} bit weights", self.bits));
+        }
+        if self.tables.len() != 1 && self.tables.len() != num_labels {
+            return invalid(format!(
+                "codebook has {} tables but there are {} labels",
+                self.tables.len(),
+                num_labels
+            ));
+        }
+        if self
+            .tables
+            .iter()
+            .any(|table| table.is_empty() || table.len() > 1 << self.bits)
+        {
+            return invalid(format!(
+                "codebook tables must hold between 1 and {} weights",
+                1u32 << self.bits
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Cluster `weights` into at most `levels` centroids with Lloyd's algorithm,
+/// starting from evenly spaced quantiles so the result is deterministic
+fn kmeans(mut weights: Vec<f64>, levels: usize) -> Vec<f32> {
+    weights.retain(|weight| weight.is_finite());
+    weights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut distinct = weights.clone();
+    distinct.dedup();
+    if distinct.len() <= levels {
+        if distinct.is_empty() {
+            return vec![0.0];
+        }
+        return distinct.iter().map(|weight| *weight as f32).collect();
+    }
+
+    let mut centroids: Vec<f64> = (0..levels)
+        .map(|level| weights[(2 * level + 1) * weights.len() / (2 * levels)])
+        .collect();
+    centroids.dedup();
+    for _ in 0..100 {
+        // With sorted weights and centroids, each cluster is the run of
+        // weights between the midpoints of neighboring centroids.
+        let mut sums = vec![0.0; centroids.len()];
+        let mut counts = vec![0usize; centroids.len()];
+        let mut cluster = 0;
+        for weight in weights.iter() {
+            while cluster + 1 < centroids.len()
+                && *weight > (centroids[cluster] + centroids[cluster + 1]) / 2.0
+            {
+                cluster += 1;
+            }
+            sums[cluster] += weight;
+            counts[cluster] += 1;
+        }
+        let mut updated: Vec<f64> = centroids
+            .iter()
+            .zip(sums.iter().zip(&counts))
+            .map(|(centroid, (sum, count))| {
+                if *count > 0 {
+                    sum / *count as f64
+                } else {
+                    *centroid
+                }
+            })
+            .collect();
+        updated.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        updated.dedup();
+        if updated == centroids {
+            break;
+        }
+        centroids = updated;
+    }
+    centroids.iter().map(|centroid| *centroid as f32).collect()
+}
+
+/// Difference between state feature weights and their quantized values
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReconstructionError {
+    pub features: usize,
+    pub rmse: f64,
+    pub mean_abs: f64,
+    pub max_abs: f64,
+}
+
+impl fmt::Display for ReconstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} state features: rmse {:.5}, mean absolute error {:.5}, max absolute error {:.5}",
+            self.features, self.rmse, self.mean_abs, self.max_abs
+        )
+    }
+}
+
+/// How many Viterbi decisions a packed model makes differently from the
+/// unquantized weights it was packed from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecisionChanges {
+    pub sequences: usize,
+    pub changed_sequences: usize,
+    pub tokens: usize,
+    pub changed_tokens: usize,
+}
+
+impl fmt::Display for DecisionChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} tokens and {} of {} sequences labeled differently",
+            self.changed_tokens, self.tokens, self.changed_sequences, self.sequences
+        )
+    }
+}
+
+/// Compare the Viterbi labels of `model` with those of the unquantized
+/// `transitions` and `state_features` it was packed from, on item sequences
+/// given as the attributes and values of each item.
+pub fn decision_changes<A: AsRef<str>>(
+    model: &Model,
+    transitions: &[(u32, u32, f64)],
+    state_features: &[(String, u32, f64)],
+    sequences: &[Vec<Vec<(A, f64)>>],
+) -> Result<DecisionChanges> {
+    let l = model.num_labels() as usize;
+    let mut trans = vec![0.0; l * l];
+    for (source, target, weight) in transitions {
+        trans[*source as usize * l + *target as usize] = *weight;
+    }
+    let mut attributes: HashMap<&str, Vec<(u32, f64)>> = HashMap::new();
+    for (attribute, target, weight) in state_features {
+        attributes
+            .entry(attribute.as_str())
+            .or_default()
+            .push((*target, *weight));
+    }
+
+    let mut changes = DecisionChanges::default();
+    let mut context = Context::new(Flag::VITERBI, l as u32, 1);
+    let mut tagger = model.tagger()?;
+    for items in sequences {
+        if items.is_empty() {
+            continue;
+        }
+        context.set_num_items(items.len() as u32);
+        context.reset(Reset::ALL);
+        context.trans.copy_from_slice(&trans);
+        for (t, item) in items.iter().enumerate() {
+            let state = &mut context.state[l * t..l * (t + 1)];
+            for (name, value) in item {
+                for (target, weight) in attributes.get(name.as_ref()).into_iter().flatten() {
+                    state[*target as usize] += weight * value;
+                }
+            }
+        }
+        let (exact, _) = context.viterbi();
+
+        let xseq: Vec<Vec<Attribute>> = items
+            .iter()
+            .map(|item| {
+                item.iter()
+                    .map(|(name, value)| Attribute::new(name.as_ref(), *value))
+                    .collect()
+            })
+            .collect();
+        let quantized = tagger.tag(&xseq)?;
+        let changed = exact
+            .iter()
+            .zip(quantized)
+            .filter(|(exact, quantized)| model.to_label(**exact) != Some(*quantized))
+            .count();
+        changes.sequences += 1;
+        changes.changed_sequences += (changed > 0) as usize;
+        changes.tokens += items.len();
+        changes.changed_tokens += changed;
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_features() -> Vec<(String, u32, f64)> {
+        [-2.0, -1.9, -0.1, 0.0, 0.05, 1.0, 1.1, 3.0]
+            .iter()
+            .enumerate()
+            .map(|(index, weight)| (format!("a{}", index), (index % 2) as u32, *weight))
+            .collect()
+    }
+
+    #[test]
+    fn test_codebooks() {
+        let features = state_features();
+        let curve = Quantization::default().codebook(2, &features).unwrap();
+        assert!(curve.is_legacy());
+        let curve_error = Quantization::default().reconstruction_error(&curve, &features);
+        assert!(curve_error.max_abs < 0.2);
+
+        // Four levels can't represent eight weights exactly, but k-means puts
+        // them where the weights are.
+        let quantization = Quantization::KMeans { bits: 2 };
+        let kmeans = quantization.codebook(2, &features).unwrap();
+        assert_eq!(kmeans.tables.len(), 1);
+        assert_eq!(kmeans.tables[0].len(), 4);
+        let kmeans_error = quantization.reconstruction_error(&kmeans, &features);
+        let coarse = Quantization::Curve { bits: 2 };
+        let coarse_curve = coarse.codebook(2, &features).unwrap();
+        assert!(kmeans_error.rmse < coarse.reconstruction_error(&coarse_curve, &features).rmse);
+        assert!(kmeans_error.max_abs < 1.0);
+
+        // With a codebook per label, each label's four weights are exact.
+        let quantization = Quantization::PerLabel { bits: 2 };
+        let per_label = quantization.codebook(2, &features).unwrap();
+        assert_eq!(per_label.tables.len(), 2);
+        assert!(
+            quantization
+                .reconstruction_error(&per_label, &features)
+                .max_abs
+                < 1e-6
+        );
+        assert_eq!(per_label.weight(1, per_label.nearest(1, 3.0)), 3.0);
+        assert!(per_label.validate(2).is_ok());
+        assert!(per_label.validate(3).is_err());
+
+        assert!(Quantization::KMeans { bits: 0 }
+            .codebook(2, &features)
+            .is_err());
+        assert_eq!(
+            "per-label".parse::<Quantization>().unwrap().with_bits(4),
+            Quantization::PerLabel { bits: 4 }
+        );
+    }
+
+    #[test]
+    fn test_bit_widths() {
+        let mut features = state_features();
+        features.push(("huge".to_string(), 0, 1e6));
+        features.push(("tiny".to_string(), 1, -1e6));
+        for bits in 1..=MAX_BITS {
+            for quantization in [
+                Quantization::Curve { bits },
+                Quantization::KMeans { bits },
+                Quantization::PerLabel { bits },
+            ] {
+                let codebook = match quantization.codebook(2, &features) {
+                    Ok(codebook) => codebook,
+                    Err(_) => {
+                        assert!(matches!(quantization, Quantization::Curve { .. }));
+                        assert!(bits < MIN_CURVE_BITS);
+                        continue;
+                    }
+                };
+                assert!(codebook.validate(2).is_ok());
+                for (_, target, weight) in &features {
+                    let index = quantization.quantize(&codebook, *target, *weight);
+                    assert!(index < 1 << bits);
+                    // The curve keeps the sign of every weight, however
+                    // coarse it is.
+                    if let Quantization::Curve { .. } = quantization {
+                        if *weight != 0.0 {
+                            assert_eq!(
+                                codebook.weight(*target, index).signum(),
+                                (*weight as f32).signum()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decision_changes() {
+        let header = crate::model::Header {
+            magic: *b"lCRF",
+            size: 0,
+            r#type: *b"FOMC",
+            version: 100,
+            num_features: 0,
+            num_labels: 0,
+            num_attrs: 0,
+            off_features: 0,
+            off_labels: 0,
+            off_attrs: 0,
+            off_label_refs: 0,
+            off_attr_refs: 0,
+        };
+        let labels = vec!["road".to_string(), "locality".to_string()];
+        let transitions = [(0, 1, 0.5), (1, 0, -0.5)];
+        let state_features = [
+            ("main".to_string(), 0, 1.5),
+            ("seattle".to_string(), 1, 2.0),
+            ("street".to_string(), 0, 1.0),
+            ("street".to_string(), 1, 0.25),
+        ];
+        let packed = crate::packed::PackedModel::pack(
+            header,
+            labels,
+            &transitions,
+            &state_features,
+            None,
+            Quantization::default(),
+        )
+        .unwrap();
+        let model = Model::try_from(packed).unwrap();
+        let sequences = vec![
+            vec![vec![("main", 1.0)], vec![("street", 1.0)]],
+            vec![vec![("seattle", 1.0)]],
+            vec![],
+        ];
+        let changes = decision_changes(&model, &transitions, &state_features, &sequences).unwrap();
+        assert_eq!(
+            changes,
+            DecisionChanges {
+                sequences: 2,
+                changed_sequences: 0,
+                tokens: 3,
+                changed_tokens: 0,
+            }
+        );
+    }
+}
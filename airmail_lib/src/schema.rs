@@ -0,0 +1,108 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Policy for turning the labels of training data into the labels a model
+/// predicts, and for grouping those into coarser ones
+///
+/// The default schema keeps every label as it is and makes each label its own
+/// coarse label.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LabelSchema {
+    /// Source labels that are renamed to another label for training
+    pub map: BTreeMap<String, String>,
+    /// Source labels whose tokens are left out of training
+    pub drop: Vec<String>,
+    /// Source labels that cause every example containing them to be skipped
+    pub skip_examples: Vec<String>,
+    /// Share of examples in which tokens with a given target label are kept.
+    /// Labels that aren't listed are always kept.
+    pub sample: BTreeMap<String, f64>,
+    /// Coarse label of each target label. Labels that aren't listed are their
+    /// own coarse label.
+    pub coarse: BTreeMap<String, String>,
+}
+
+impl LabelSchema {
+    /// The label a token with the source label `label` is trained as, or
+    /// `None` if such tokens are left out
+    pub fn target<'a>(&'a self, label: &'a str) -> Option<&'a str> {
+        if self.drop.iter().any(|dropped| dropped == label) {
+            return None;
+        }
+        Some(self.map.get(label).map_or(label, String::as_str))
+    }
+
+    /// The coarse label of a target label
+    pub fn coarse<'a>(&'a self, label: &'a str) -> &'a str {
+        self.coarse.get(label).map_or(label, String::as_str)
+    }
+
+    /// Share of examples in which tokens with the target label `label` are
+    /// kept
+    pub fn keep_rate(&self, label: &str) -> f64 {
+        self.sample.get(label).copied().unwrap_or(1.0)
+    }
+
+    /// Apply the schema to the source labels of one training example.
+    ///
+    /// Returns the target label of each token, or `None` for tokens that are
+    /// left out. Whether a sampled label is kept is decided once for the whole
+    /// example. Returns `None` if the example is skipped.
+    pub fn label_example<'a, R: Rng>(
+        &'a self,
+        labels: &[&'a str],
+        rng: &mut R,
+    ) -> Option<Vec<Option<&'a str>>> {
+        if labels
+            .iter()
+            .any(|label| self.skip_examples.iter().any(|skipped| skipped == label))
+        {
+            return None;
+        }
+        let mut kept: HashMap<&str, bool> = HashMap::new();
+        let targets = labels
+            .iter()
+            .map(|label| {
+                let target = self.target(label)?;
+                let keep = *kept.entry(target).or_insert_with(|| {
+                    let rate = self.keep_rate(target);
+                    rate >= 1.0 || rng.gen::<f64>() < rate
+                });
+                keep.then_some(target)
+            })
+            .collect();
+        Some(targets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_label_example() {
+        let schema = LabelSchema {
+            map: BTreeMap::from([("city".to_string(), "locality".to_string())]),
+            drop: vec!["level".to_string()],
+            skip_examples: vec!["po_box".to_string()],
+            sample: BTreeMap::from([("postcode".to_string(), 0.0)]),
+            coarse: BTreeMap::from([("road".to_string(), "street".to_string())]),
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            schema.label_example(&["road", "level", "city", "postcode"], &mut rng),
+            Some(vec![Some("road"), None, Some("locality"), None])
+        );
+        assert_eq!(schema.label_example(&["road", "po_box"], &mut rng), None);
+        assert_eq!(schema.coarse("road"), "street");
+        assert_eq!(schema.coarse("locality"), "locality");
+        assert_eq!(
+            LabelSchema::default().label_example(&["city"], &mut rng),
+            Some(vec![Some("city")])
+        );
+    }
+}
@@ -1,14 +1,12 @@
-use std::io;
-
 use crate::context::{Context, Flag, Reset};
 use crate::dataset::{self, Instance, Item};
+use crate::error::{Error, Result};
 use crate::model::Model;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum Level {
     None,
     Set,
-    #[allow(dead_code)]
     AlphaBeta,
 }
 
@@ -21,6 +19,92 @@ pub struct Attribute {
     pub value: f64,
 }
 
+/// One of the n-best label sequences for an item sequence
+#[derive(Debug, Clone)]
+pub struct Hypothesis<'a> {
+    /// Predicted label for each item
+    pub labels: Vec<&'a str>,
+    /// Unnormalized score of the label sequence
+    pub score: f64,
+    /// Probability of the label sequence, if the tagger computes marginals
+    pub probability: Option<f64>,
+}
+
+/// Restrictions on the labels a tagger may assign to an item sequence
+///
+/// Positions are indices into the item sequence. Positions without any
+/// restriction may take any label.
+#[derive(Debug, Clone, Default)]
+pub struct Constraints {
+    positions: Vec<PositionConstraint>,
+    transitions: Option<Vec<(String, String)>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PositionConstraint {
+    allowed: Option<Vec<String>>,
+    forbidden: Vec<String>,
+}
+
+impl Constraints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn position_mut(&mut self, position: usize) -> &mut PositionConstraint {
+        if self.positions.len() <= position {
+            self.positions
+                .resize_with(position + 1, PositionConstraint::default);
+        }
+        &mut self.positions[position]
+    }
+
+    /// Require the item at `position` to take exactly `label`.
+    pub fn pin(&mut self, position: usize, label: &str) -> &mut Self {
+        self.allow(position, [label])
+    }
+
+    /// Require the item at `position` to take one of `labels`. Repeated calls
+    /// for the same position narrow the allowed set further.
+    pub fn allow<I, S>(&mut self, position: usize, labels: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let labels: Vec<String> = labels.into_iter().map(Into::into).collect();
+        let constraint = self.position_mut(position);
+        constraint.allowed = Some(match constraint.allowed.take() {
+            Some(allowed) => allowed
+                .into_iter()
+                .filter(|label| labels.contains(label))
+                .collect(),
+            None => labels,
+        });
+        self
+    }
+
+    /// Forbid the item at `position` from taking any of `labels`.
+    pub fn forbid<I, S>(&mut self, position: usize, labels: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.position_mut(position)
+            .forbidden
+            .extend(labels.into_iter().map(Into::into));
+        self
+    }
+
+    /// Allow `to` to follow `from`. Once any transition has been allowed,
+    /// only explicitly allowed transitions are searched.
+    pub fn allow_transition(&mut self, from: &str, to: &str) -> &mut Self {
+        self.transitions
+            .get_or_insert_with(Vec::new)
+            .push((from.to_string(), to.to_string()));
+        self
+    }
+}
+
 /// The tagger provides the functionality for predicting label sequences for input sequences using a model
 #[derive(Debug, Clone)]
 pub struct Tagger<'a> {
@@ -45,7 +129,7 @@ impl Attribute {
 }
 
 impl<'a> Tagger<'a> {
-    pub(crate) fn new(model: &'a Model) -> io::Result<Self> {
+    pub(crate) fn new(model: &'a Model) -> Result<Self> {
         let num_labels = model.num_labels();
         let num_attrs = model.num_attrs();
         let mut context = Context::new(Flag::VITERBI | Flag::MARGINALS, num_labels, 0);
@@ -71,22 +155,119 @@ impl<'a> Tagger<'a> {
     }
 
     /// Predict the label sequence for the item sequence.
-    pub fn tag<T: AsRef<[Attribute]>>(&mut self, xseq: &[T]) -> io::Result<Vec<&str>> {
-        if xseq.is_empty() {
-            return Ok(Vec::new());
+    pub fn tag<T: AsRef<[Attribute]>>(&mut self, xseq: &[T]) -> Result<Vec<&str>> {
+        self.set(xseq)?;
+        self.viterbi()
+    }
+
+    /// Predict the label sequence for the item sequence along with the
+    /// probability of that sequence under the model.
+    pub fn tag_with_probability<T: AsRef<[Attribute]>>(
+        &mut self,
+        xseq: &[T],
+    ) -> Result<(Vec<&str>, f64)> {
+        self.set(xseq)?;
+        if self.is_empty() {
+            return Ok((Vec::new(), 1.0));
         }
+        let (label_ids, score) = self.best_path();
+        self.compute_alpha_beta();
+        let probability = (score - self.context.lognorm()).exp();
+        Ok((self.to_labels(label_ids)?, probability))
+    }
+
+    /// Predict the `k` best distinct label sequences for the item sequence,
+    /// best first.
+    pub fn tag_nbest<T: AsRef<[Attribute]>>(
+        &mut self,
+        xseq: &[T],
+        k: usize,
+    ) -> Result<Vec<Hypothesis<'a>>> {
+        self.set(xseq)?;
+        self.nbest(k)
+    }
+
+    /// Predict the best label sequence for the item sequence among those that
+    /// satisfy `constraints`. The probability of the returned sequence is
+    /// computed under the unconstrained model.
+    pub fn tag_constrained<T: AsRef<[Attribute]>>(
+        &mut self,
+        xseq: &[T],
+        constraints: &Constraints,
+    ) -> Result<Hypothesis<'a>> {
         self.set(xseq)?;
-        let (label_ids, _score) = self.viterbi();
-        let mut labels = Vec::with_capacity(label_ids.len());
-        for id in label_ids {
-            let label = self.model.to_label(id).unwrap();
-            labels.push(label);
+        self.constrained(constraints)
+    }
+
+    /// Predict the label sequence for the instance passed to the last call of
+    /// `set` or `set_ids`.
+    pub fn viterbi(&mut self) -> Result<Vec<&'a str>> {
+        self.ensure_set()?;
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (label_ids, _score) = self.best_path();
+        self.to_labels(label_ids)
+    }
+
+    /// Predict the `k` best distinct label sequences for the instance passed
+    /// to the last call of `set` or `set_ids`, best first.
+    pub fn nbest(&mut self, k: usize) -> Result<Vec<Hypothesis<'a>>> {
+        self.ensure_set()?;
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        let nbest = self.context.viterbi_nbest(k);
+        let lognorm = if self.context.has_marginals() {
+            self.compute_alpha_beta();
+            Some(self.context.lognorm())
+        } else {
+            None
+        };
+        nbest
+            .into_iter()
+            .map(|(label_ids, score)| {
+                Ok(Hypothesis {
+                    labels: self.to_labels(label_ids)?,
+                    score,
+                    probability: lognorm.map(|lognorm| (score - lognorm).exp()),
+                })
+            })
+            .collect()
+    }
+
+    /// Predict the best label sequence for the instance passed to the last
+    /// call of `set` or `set_ids` among those that satisfy `constraints`.
+    pub fn constrained(&mut self, constraints: &Constraints) -> Result<Hypothesis<'a>> {
+        self.ensure_set()?;
+        if self.is_empty() {
+            return Ok(Hypothesis {
+                labels: Vec::new(),
+                score: 0.0,
+                probability: Some(1.0),
+            });
+        }
+        self.apply_constraints(constraints)?;
+        let (label_ids, score) = self.best_path();
+        self.context.clear_constraints();
+        if score == f64::NEG_INFINITY {
+            return Err(Error::Unsatisfiable);
         }
-        Ok(labels)
+        let probability = if self.context.has_marginals() {
+            self.compute_alpha_beta();
+            Some((score - self.context.lognorm()).exp())
+        } else {
+            None
+        };
+        Ok(Hypothesis {
+            labels: self.to_labels(label_ids)?,
+            score,
+            probability,
+        })
     }
 
     /// Set an instance (item sequence) for future calls of `tag`, `probability` and `marginal` methods
-    pub fn set<T: AsRef<[Attribute]>>(&mut self, xseq: &[T]) -> io::Result<()> {
+    pub fn set<T: AsRef<[Attribute]>>(&mut self, xseq: &[T]) -> Result<()> {
         let mut instance = Instance::with_capacity(xseq.len());
         for item in xseq {
             let item: Item = item
@@ -100,29 +281,205 @@ impl<'a> Tagger<'a> {
                 .collect();
             instance.push(item, 0);
         }
+        self.set_instance(&instance)
+    }
+
+    /// Set an instance from model attribute ids, such as those produced by
+    /// `Tokenizer`, valued by the model's attribute value scheme. Ids the
+    /// model has no weights for, including reserved digit-count ids, are
+    /// ignored.
+    ///
+    /// Schemes that value pieces by their place in the token can't be applied
+    /// to bare ids and value them at one. Use `FeatureExtractor::attributes`
+    /// and `set_attributes` for those.
+    pub fn set_ids<T: AsRef<[u32]>>(&mut self, xseq: &[T]) -> Result<()> {
+        let values = self.model.extractor().values;
+        let xseq: Vec<Item> = xseq
+            .iter()
+            .map(|item| {
+                item.as_ref()
+                    .iter()
+                    .map(|id| dataset::Attribute::new(*id, values.id_value(*id)))
+                    .collect()
+            })
+            .collect();
+        self.set_attributes(&xseq)
+    }
+
+    /// Set an instance from model attribute ids along with their values. Ids
+    /// the model has no weights for are ignored.
+    pub fn set_attributes<T: AsRef<[dataset::Attribute]>>(&mut self, xseq: &[T]) -> Result<()> {
+        let num_attrs = self.model.num_attrs();
+        let mut instance = Instance::with_capacity(xseq.len());
+        for item in xseq {
+            let item: Item = item
+                .as_ref()
+                .iter()
+                .filter(|attr| attr.id < num_attrs)
+                .copied()
+                .collect();
+            instance.push(item, 0);
+        }
+        self.set_instance(&instance)
+    }
+
+    fn set_instance(&mut self, instance: &Instance) -> Result<()> {
         self.context.set_num_items(instance.num_items);
         self.context.reset(Reset::STATE);
-        self.state_score(&instance)?;
+        self.state_score(instance)?;
         self.level = Level::Set;
         Ok(())
     }
 
-    fn transition_score(&mut self) -> io::Result<()> {
+    /// Compute the probability of a label sequence for the instance passed
+    /// to the last call of `set`.
+    pub fn probability(&mut self, yseq: &[&str]) -> Result<f64> {
+        self.ensure_set()?;
+        if yseq.len() != self.len() {
+            return Err(Error::InvalidInput(format!(
+                "label sequence length {} does not match instance length {}",
+                yseq.len(),
+                self.len()
+            )));
+        }
+        if self.is_empty() {
+            return Ok(1.0);
+        }
+        let label_ids = yseq
+            .iter()
+            .map(|label| self.label_id(label))
+            .collect::<Result<Vec<u32>>>()?;
+        self.compute_alpha_beta();
+        let score = self.context.score(&label_ids);
+        Ok((score - self.context.lognorm()).exp())
+    }
+
+    /// Compute the marginal probability of a label at a position for the
+    /// instance passed to the last call of `set`.
+    pub fn marginal(&mut self, label: &str, position: usize) -> Result<f64> {
+        self.ensure_set()?;
+        if position >= self.len() {
+            return Err(Error::InvalidInput(format!(
+                "position {} is out of range for instance length {}",
+                position,
+                self.len()
+            )));
+        }
+        let label_id = self.label_id(label)?;
+        self.compute_alpha_beta();
+        Ok(self.context.marginal_point(label_id, position as u32))
+    }
+
+    /// Marginal probabilities of every label at every position of the
+    /// instance passed to the last call of `set`, indexed `[position][label id]`.
+    pub fn marginals(&mut self) -> Result<Vec<Vec<f64>>> {
+        self.ensure_set()?;
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.compute_alpha_beta();
+        self.context.marginals();
+        Ok((0..self.context.num_items)
+            .map(|t| self.context.state_marginals(t).to_vec())
+            .collect())
+    }
+
+    /// Logarithm of the partition function for the instance passed to the
+    /// last call of `set`.
+    pub fn lognorm(&mut self) -> Result<f64> {
+        self.ensure_set()?;
+        if self.is_empty() {
+            return Ok(0.0);
+        }
+        self.compute_alpha_beta();
+        Ok(self.context.lognorm())
+    }
+
+    fn apply_constraints(&mut self, constraints: &Constraints) -> Result<()> {
+        let l = self.num_labels as usize;
+        let mut state_mask = vec![true; self.len() * l];
+        for (position, constraint) in constraints.positions.iter().enumerate() {
+            if constraint.allowed.is_none() && constraint.forbidden.is_empty() {
+                continue;
+            }
+            if position >= self.len() {
+                return Err(Error::InvalidInput(format!(
+                    "constrained position {} is out of range for instance length {}",
+                    position,
+                    self.len()
+                )));
+            }
+            let row = &mut state_mask[l * position..l * (position + 1)];
+            if let Some(allowed) = &constraint.allowed {
+                row.fill(false);
+                for label in allowed {
+                    row[self.label_id(label)? as usize] = true;
+                }
+            }
+            for label in &constraint.forbidden {
+                row[self.label_id(label)? as usize] = false;
+            }
+        }
+        let trans_mask = match &constraints.transitions {
+            Some(transitions) => {
+                let mut trans_mask = vec![false; l * l];
+                for (from, to) in transitions {
+                    trans_mask[l * self.label_id(from)? as usize + self.label_id(to)? as usize] =
+                        true;
+                }
+                trans_mask
+            }
+            None => Vec::new(),
+        };
+        self.context.set_constraints(state_mask, trans_mask);
+        Ok(())
+    }
+
+    fn ensure_set(&self) -> Result<()> {
+        if self.level < Level::Set {
+            return Err(Error::NoInstance);
+        }
+        Ok(())
+    }
+
+    fn to_labels(&self, label_ids: Vec<u32>) -> Result<Vec<&'a str>> {
+        let model = self.model;
+        label_ids
+            .into_iter()
+            .map(|id| model.to_label(id).ok_or(Error::UnknownLabelId(id)))
+            .collect()
+    }
+
+    fn label_id(&self, label: &str) -> Result<u32> {
+        self.model
+            .to_label_id(label)
+            .ok_or_else(|| Error::UnknownLabel(label.to_string()))
+    }
+
+    fn compute_alpha_beta(&mut self) {
+        if self.level < Level::AlphaBeta && !self.is_empty() {
+            self.context.exp_state();
+            self.context.alpha_score();
+            self.context.beta_score();
+            self.level = Level::AlphaBeta;
+        }
+    }
+
+    fn transition_score(&mut self) -> Result<()> {
         // Compute transition scores between two labels
         let l = self.num_labels as usize;
         for i in 0..l {
             let trans = &mut self.context.trans[l * i..];
             let edge = self.model.label_ref(i as u32)?;
-            for fid in edge.feature_ids {
+            for feature in edge.iter() {
                 // Transition feature from #i to #(feature.target)
-                let feature = self.model.feature(fid)?;
-                trans[feature.target as usize] = feature.weight;
+                trans[feature.target as usize] = feature.weight as f64;
             }
         }
         Ok(())
     }
 
-    fn state_score(&mut self, instance: &Instance) -> io::Result<()> {
+    fn state_score(&mut self, instance: &Instance) -> Result<()> {
         // Loop over the items in the sequence
         for t in 0..instance.num_items as usize {
             let item = &instance.items[t];
@@ -132,23 +489,24 @@ impl<'a> Tagger<'a> {
                 // Access the list of state features associated with the attribute
                 let id = attr.id;
                 let attr_ref = self.model.attr_ref(id)?;
-                // A scale usually represents the attribute frequency in the item
+                // The value scheme the model was trained with sets the scale
                 let value = attr.value;
                 // Loop over the state features associated with the attribue
-                for fid in attr_ref.feature_ids {
-                    let feature = self.model.feature(fid)?;
-                    if feature.target > 13 {
-                        println!("{:?}", feature);
-                        panic!();
+                for feature in attr_ref.iter() {
+                    if feature.target >= self.num_labels {
+                        return Err(Error::LabelOutOfRange {
+                            target: feature.target,
+                            num_labels: self.num_labels,
+                        });
                     }
-                    state[feature.target as usize] += feature.weight * value;
+                    state[feature.target as usize] += feature.weight as f64 * value;
                 }
             }
         }
         Ok(())
     }
 
-    fn viterbi(&mut self) -> (Vec<u32>, f64) {
+    fn best_path(&mut self) -> (Vec<u32>, f64) {
         self.context.viterbi()
     }
 }
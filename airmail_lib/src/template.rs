@@ -0,0 +1,161 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Templates for attributes that describe a token's context in the query
+///
+/// Template attributes are ordinary attributes with an uppercase prefix, so
+/// they share the attribute vocabulary with the lowercase word pieces without
+/// colliding with them. With the default configuration no templates apply.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeatureTemplates {
+    /// Offsets of the neighboring tokens whose own attributes are copied onto
+    /// each token, such as `[-2, -1, 1, 2]`
+    pub neighbors: Vec<i32>,
+    /// Mark the first and last token of the query
+    pub boundaries: bool,
+    /// Number of buckets the token's relative position in the query is split
+    /// into, or zero to leave position out
+    pub position_buckets: u32,
+    /// Largest query length, in tokens, that gets an attribute of its own.
+    /// Longer queries share one. Zero leaves query length out.
+    pub max_token_count: u32,
+}
+
+/// One of a token's own attributes, as produced by the tokenizer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseKey<'a> {
+    /// A word piece, or any other attribute given by name
+    Text(&'a str),
+    /// The digit-count feature `D:n`
+    Digits(u32),
+}
+
+/// An attribute produced by one of the feature templates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateAttribute<'a> {
+    /// An attribute of the token `offset` positions away, `W-1:main`
+    Neighbor { offset: i32, key: BaseKey<'a> },
+    /// The token is the first in the query, `BOS`
+    First,
+    /// The token is the last in the query, `EOS`
+    Last,
+    /// Bucketed relative position of the token, `POS:2`
+    Position { bucket: u32 },
+    /// Number of tokens in the query, `LEN:3`, or `LEN:8+` once clamped
+    TokenCount { count: u32, clamped: bool },
+}
+
+impl fmt::Display for BaseKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BaseKey::Text(text) => write!(f, "{}", text),
+            BaseKey::Digits(count) => write!(f, "D:{}", count),
+        }
+    }
+}
+
+impl fmt::Display for TemplateAttribute<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateAttribute::Neighbor { offset, key } => write!(f, "W{:+}:{}", offset, key),
+            TemplateAttribute::First => write!(f, "BOS"),
+            TemplateAttribute::Last => write!(f, "EOS"),
+            TemplateAttribute::Position { bucket } => write!(f, "POS:{}", bucket),
+            TemplateAttribute::TokenCount { count, clamped } => {
+                write!(f, "LEN:{}{}", count, if *clamped { "+" } else { "" })
+            }
+        }
+    }
+}
+
+impl FeatureTemplates {
+    /// Whether any template is enabled
+    pub fn is_empty(&self) -> bool {
+        self == &FeatureTemplates::default()
+    }
+
+    /// Call `emit` with every template attribute of the token at `position`,
+    /// given each token's own attributes.
+    pub fn apply<'a>(
+        &self,
+        tokens: &[Vec<BaseKey<'a>>],
+        position: usize,
+        mut emit: impl FnMut(TemplateAttribute<'a>),
+    ) {
+        let len = tokens.len();
+        for offset in &self.neighbors {
+            let neighbor = position as i64 + *offset as i64;
+            if *offset == 0 || neighbor < 0 || neighbor >= len as i64 {
+                continue;
+            }
+            for key in &tokens[neighbor as usize] {
+                emit(TemplateAttribute::Neighbor {
+                    offset: *offset,
+                    key: *key,
+                });
+            }
+        }
+        if self.boundaries {
+            if position == 0 {
+                emit(TemplateAttribute::First);
+            }
+            if position + 1 == len {
+                emit(TemplateAttribute::Last);
+            }
+        }
+        if self.position_buckets > 0 {
+            emit(TemplateAttribute::Position {
+                bucket: (position * self.position_buckets as usize / len) as u32,
+            });
+        }
+        if self.max_token_count > 0 {
+            emit(TemplateAttribute::TokenCount {
+                count: (len as u32).min(self.max_token_count),
+                clamped: len as u32 > self.max_token_count,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let templates = FeatureTemplates {
+            neighbors: vec![-2, -1, 1],
+            boundaries: true,
+            position_buckets: 2,
+            max_token_count: 3,
+        };
+        let tokens = vec![
+            vec![BaseKey::Digits(3)],
+            vec![BaseKey::Text("main")],
+            vec![BaseKey::Text("st"), BaseKey::Text("reet")],
+        ];
+        let attributes = |position| {
+            let mut attributes = vec![];
+            templates.apply(&tokens, position, |attribute| {
+                attributes.push(attribute.to_string())
+            });
+            attributes
+        };
+        assert_eq!(attributes(0), vec!["W+1:main", "BOS", "POS:0", "LEN:3"]);
+        assert_eq!(
+            attributes(2),
+            vec!["W-2:D:3", "W-1:main", "EOS", "POS:1", "LEN:3"]
+        );
+
+        let mut longer = tokens.clone();
+        longer.push(vec![BaseKey::Text("seattle")]);
+        let mut attributes = vec![];
+        templates.apply(&longer, 3, |attribute| {
+            attributes.push(attribute.to_string())
+        });
+        assert_eq!(attributes.last().unwrap(), "LEN:3+");
+        assert!(FeatureTemplates::default().is_empty());
+    }
+}
@@ -1,108 +1,624 @@
-use std::collections::{HashMap, HashSet};
+use std::{fmt, ops::Range, sync::OnceLock};
 
-use deunicode::deunicode;
-use fst::{raw::Fst, Streamer};
+use deunicode::deunicode_char;
+use fst::{
+    raw::{Fst, Output},
+    MapBuilder, Streamer,
+};
 
+use crate::error::{Error, Result};
+use crate::model::Model;
+use crate::packed::ModelBytes;
+use crate::template::BaseKey;
+
+/// Longest piece, in bytes, that segmentation looks up in the vocabulary
+pub const MAX_PIECE_LEN: usize = 32;
+
+/// Cost, in nats, of a piece whose vocabulary entry has no log-probability
+const UNSCORED_PIECE_COST: f64 = 10.0;
+
+/// Cost, in nats, of skipping a byte that no vocabulary piece covers
+const UNKNOWN_BYTE_COST: f64 = 30.0;
+
+/// Resolution of the piece costs stored in vocabulary outputs
+const COST_SCALE: f64 = 1000.0;
+
+/// Splits queries into words and finds the attribute ids of each word
+///
+/// Attribute ids are stored in the vocabulary FST's outputs (see
+/// `VocabOutput`), so a tokenizer built from a model emits that model's
+/// attribute ids. Ids from `vocab.len()` up are reserved: `vocab.len() + n` is
+/// the digit-count feature `D:n`, unless the vocabulary has an entry for `D:n`
+/// of its own.
+///
+/// Each word is split into vocabulary pieces by finding its most likely
+/// segmentations under the pieces' log-probabilities, and the word's features
+/// are the pieces of its best `max_segmentations` segmentations. Segmenting a
+/// word looks at no more than `MAX_PIECE_LEN` bytes from each position, so its
+/// cost is linear in the length of the word.
+#[derive(Clone)]
 pub struct Tokenizer {
-    feature_ids: HashMap<String, u32>,
-    feature_strings: HashMap<u32, String>,
-    feature_count: u32,
+    vocab: Fst<ModelBytes>,
+    max_segmentations: usize,
+    /// Attribute strings by id, only built if something asks for them
+    names: OnceLock<Names>,
 }
 
-impl Tokenizer {
-    pub fn new(vocab: &Fst<Vec<u8>>) -> Tokenizer {
-        let mut vocab_stream = vocab.stream();
-        let mut feature_ids = HashMap::new();
-        let mut feature_strings = HashMap::new();
-        let mut feature_id = 0u32;
-        while let Some((key, _out)) = vocab_stream.next() {
-            if let Ok(vocab_item) = String::from_utf8(key.to_vec()) {
-                if feature_ids.contains_key(&vocab_item) {
-                    continue;
-                }
-                feature_ids.insert(vocab_item.to_string(), feature_id);
-                feature_strings.insert(feature_id, vocab_item.to_string());
-                feature_id += 1;
+/// Every attribute string in the vocabulary concatenated in id order. The
+/// string for attribute #a is `text[offsets[a]..offsets[a + 1]]`.
+#[derive(Debug, Clone)]
+struct Names {
+    text: String,
+    offsets: Vec<u32>,
+}
+
+/// The output stored for each key of a vocabulary FST
+///
+/// The low 32 bits hold the attribute id. The high 32 bits hold the piece's
+/// negated log-probability in thousandths of a nat, plus one, with zero meaning
+/// the piece has no log-probability.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VocabOutput {
+    /// Attribute id of the key
+    pub id: u32,
+    /// Natural log-probability of the key as a word piece
+    pub log_prob: Option<f64>,
+}
+
+impl VocabOutput {
+    pub fn encode(&self) -> u64 {
+        let cost = match self.log_prob {
+            Some(log_prob) => {
+                (f64::round(-log_prob.min(0.0) * COST_SCALE) as u64).min(u32::MAX as u64 - 1) + 1
+            }
+            None => 0,
+        };
+        (cost << 32) | self.id as u64
+    }
+
+    pub fn decode(output: u64) -> VocabOutput {
+        let cost = output >> 32;
+        VocabOutput {
+            id: output as u32,
+            log_prob: if cost == 0 {
+                None
             } else {
-                println!("Error");
-                panic!()
+                Some(-((cost - 1) as f64) / COST_SCALE)
+            },
+        }
+    }
+}
+
+/// A vocabulary piece found in a word
+#[derive(Debug, Clone, PartialEq)]
+pub struct Piece {
+    /// Byte range of the piece within the word
+    pub span: Range<usize>,
+    /// Attribute id of the piece
+    pub id: u32,
+    /// Log-probability the segmentation assigned to the piece
+    pub log_prob: f64,
+}
+
+/// One way of splitting a word into vocabulary pieces
+///
+/// Bytes that no piece of the vocabulary covers are skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segmentation {
+    pub pieces: Vec<Piece>,
+    /// Total log-probability of the pieces, less a penalty for skipped bytes
+    pub log_prob: f64,
+}
+
+/// A partial segmentation in the k-best lattice search
+#[derive(Debug, Clone, Copy)]
+struct LatticePath {
+    cost: f64,
+    /// Position and rank of the path this one extends
+    from: usize,
+    rank: usize,
+    /// Attribute id and cost of the piece that got here from `from`, or
+    /// `None` if a byte was skipped
+    piece: Option<(u32, f64)>,
+}
+
+/// A single word of the transliterated query
+#[derive(Debug, Clone)]
+pub struct Token {
+    /// Byte range of the original, untransliterated text this token came from
+    pub span: Range<usize>,
+    /// Transliterated, lowercased text of the token
+    pub text: String,
+    /// Feature ids for the token: its pieces, then its digit count
+    pub features: Vec<u32>,
+    /// Byte range within `text` of each piece in `features`, in order
+    pub piece_spans: Vec<Range<usize>>,
+    /// Share of the probability of the word's best segmentations held by
+    /// those containing each piece, in order
+    pub piece_probabilities: Vec<f64>,
+    /// Number of digits, if the token is numeric
+    pub digit_count: Option<u32>,
+}
+
+impl Token {
+    /// The token's own attributes, in the same order as `features`
+    pub fn base_keys(&self) -> Vec<BaseKey<'_>> {
+        self.piece_spans
+            .iter()
+            .map(|span| BaseKey::Text(&self.text[span.clone()]))
+            .chain(self.digit_count.map(BaseKey::Digits))
+            .collect()
+    }
+}
+
+impl fmt::Debug for Tokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tokenizer")
+            .field("vocab_len", &self.vocab.len())
+            .finish()
+    }
+}
+
+impl Tokenizer {
+    /// Build a tokenizer from a vocabulary FST.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the vocabulary contains keys that aren't valid UTF-8. Use
+    /// `try_new` to handle that case.
+    pub fn new<D: AsRef<[u8]>>(vocab: &Fst<D>) -> Tokenizer {
+        Tokenizer::try_new(vocab).expect("Invalid vocabulary")
+    }
+
+    /// Build a tokenizer from a vocabulary FST.
+    ///
+    /// If the vocabulary's outputs aren't already the position of each key,
+    /// as with a plain `fst::Set`, keys are numbered in order.
+    pub fn try_new<D: AsRef<[u8]>>(vocab: &Fst<D>) -> Result<Tokenizer> {
+        let mut is_numbered = true;
+        let mut stream = vocab.stream();
+        let mut id = 0u32;
+        while let Some((key, output)) = stream.next() {
+            if std::str::from_utf8(key).is_err() {
+                return Err(Error::InvalidModel(format!(
+                    "vocabulary key {:?} is not valid UTF-8",
+                    key
+                )));
             }
+            is_numbered &= VocabOutput::decode(output.value()).id == id;
+            id += 1;
         }
+        let vocab_bytes = if is_numbered {
+            vocab.as_bytes().to_vec()
+        } else {
+            let mut builder = MapBuilder::memory();
+            let mut stream = vocab.stream();
+            let mut id = 0u32;
+            while let Some((key, _output)) = stream.next() {
+                builder.insert(key, VocabOutput { id, log_prob: None }.encode())?;
+                id += 1;
+            }
+            builder.into_inner()?
+        };
+        Ok(Tokenizer {
+            vocab: Fst::new(ModelBytes::new(vocab_bytes))?,
+            max_segmentations: 1,
+            names: OnceLock::new(),
+        })
+    }
+
+    /// Build a tokenizer that emits `model`'s attribute ids, sharing the
+    /// model's vocabulary rather than copying it. Words are segmented the way
+    /// the model's feature extractor was trained with.
+    pub fn for_model(model: &Model) -> Tokenizer {
         Tokenizer {
-            feature_ids,
-            feature_strings,
-            feature_count: feature_id,
+            vocab: model.get_vocab(),
+            max_segmentations: 1,
+            names: OnceLock::new(),
         }
+        .with_max_segmentations(model.extractor().max_segmentations as usize)
+    }
+
+    /// Take features from the best `k` segmentations of each word rather than
+    /// only the best one.
+    pub fn with_max_segmentations(mut self, k: usize) -> Tokenizer {
+        self.max_segmentations = k.max(1);
+        self
+    }
+
+    /// Log-probability of a piece, if it is in the vocabulary with one
+    pub fn piece_log_prob(&self, piece: &str) -> Option<f64> {
+        self.vocab
+            .get(piece)
+            .and_then(|output| VocabOutput::decode(output.value()).log_prob)
     }
 
     pub fn tokenize(&self, string: &str) -> Vec<Vec<u32>> {
-        let transliterated = deunicode(string).to_ascii_lowercase();
-        let words = transliterated.split_whitespace();
-        let mut feature_vecs = vec![];
-        for word in words {
-            let mut feature_set = HashSet::new();
-            self.features_for_ascii_word(word, &mut feature_set);
-            let features: Vec<u32> = feature_set.into_iter().collect();
-            feature_vecs.push(features);
-        }
-        feature_vecs
+        self.tokenize_with_spans(string)
+            .into_iter()
+            .map(|token| token.features)
+            .collect()
     }
 
-    fn features_for_ascii_word_recursive(&self, word: &str, seed_set: &mut HashSet<u32>) {
-        for i in 1..=word.len() {
-            let prefix = &word[0..i];
-            if let Some(feature_id) = self.feature_ids.get(prefix) {
-                seed_set.insert(*feature_id);
-                self.features_for_ascii_word_recursive(&word[i..word.len()], seed_set);
+    /// Tokenize a string, keeping track of where in the original string each
+    /// transliterated word came from.
+    pub fn tokenize_with_spans(&self, string: &str) -> Vec<Token> {
+        let (transliterated, source_spans) = transliterate(string);
+        let mut tokens = vec![];
+        let mut word_start = None;
+        // Walk one past the end so that the final word gets flushed.
+        for (idx, byte) in transliterated
+            .bytes()
+            .chain(std::iter::once(b' '))
+            .enumerate()
+        {
+            match (byte.is_ascii_whitespace(), word_start) {
+                (false, None) => word_start = Some(idx),
+                (true, Some(start)) => {
+                    let word = &transliterated[start..idx];
+                    tokens.push(self.token_for_ascii_word(
+                        word,
+                        source_spans[start].start..source_spans[idx - 1].end,
+                    ));
+                    word_start = None;
+                }
+                _ => {}
             }
         }
+        tokens
     }
 
-    fn features_for_ascii_word(&self, word: &str, seed_set: &mut HashSet<u32>) {
-        self.features_for_ascii_word_recursive(word, seed_set);
-        if !word.is_empty()
-            && word.chars().all(|ch| match ch {
-                '0' => true,
-                '1' => true,
-                '2' => true,
-                '3' => true,
-                '4' => true,
-                '5' => true,
-                '6' => true,
-                '7' => true,
-                '8' => true,
-                '9' => true,
-                '-' => true,
-                _ => false,
+    /// Find the `k` most likely segmentations of a word, best first.
+    pub fn segment(&self, word: &str, k: usize) -> Vec<Segmentation> {
+        let bytes = word.as_bytes();
+        let k = k.max(1);
+        // paths[j] holds the k cheapest paths reaching byte j, cheapest first.
+        let mut paths: Vec<Vec<LatticePath>> = vec![Vec::new(); bytes.len() + 1];
+        paths[0].push(LatticePath {
+            cost: 0.0,
+            from: 0,
+            rank: 0,
+            piece: None,
+        });
+        for start in 0..bytes.len() {
+            let (done, rest) = paths.split_at_mut(start + 1);
+            let from = &done[start];
+            Tokenizer::extend_paths(from, start, &mut rest[0], None, UNKNOWN_BYTE_COST, k);
+
+            let mut node = self.vocab.root();
+            let mut output = Output::zero();
+            for (len, byte) in bytes[start..].iter().take(MAX_PIECE_LEN).enumerate() {
+                let transition = match node.find_input(*byte) {
+                    Some(index) => node.transition(index),
+                    None => break,
+                };
+                output = output.cat(transition.out);
+                node = self.vocab.node(transition.addr);
+                if node.is_final() {
+                    let piece = VocabOutput::decode(output.cat(node.final_output()).value());
+                    let cost = piece
+                        .log_prob
+                        .map_or(UNSCORED_PIECE_COST, |log_prob| -log_prob);
+                    Tokenizer::extend_paths(from, start, &mut rest[len], Some(piece.id), cost, k);
+                }
+            }
+        }
+
+        (0..paths[bytes.len()].len())
+            .map(|rank| {
+                let mut pieces = vec![];
+                let (mut position, mut rank) = (bytes.len(), rank);
+                let log_prob = -paths[position][rank].cost;
+                while position > 0 {
+                    let path = paths[position][rank];
+                    if let Some((id, cost)) = path.piece {
+                        pieces.push(Piece {
+                            span: path.from..position,
+                            id,
+                            log_prob: -cost,
+                        });
+                    }
+                    position = path.from;
+                    rank = path.rank;
+                }
+                pieces.reverse();
+                Segmentation { pieces, log_prob }
             })
-        {
-            let digit_count = word
-                .chars()
-                .filter(|ch| match ch {
-                    '0' => true,
-                    '1' => true,
-                    '2' => true,
-                    '3' => true,
-                    '4' => true,
-                    '5' => true,
-                    '6' => true,
-                    '7' => true,
-                    '8' => true,
-                    '9' => true,
-                    _ => false,
-                })
-                .count();
-            if digit_count > 0 {
-                seed_set.insert(self.feature_count + digit_count as u32);
+            .collect()
+    }
+
+    /// Extend every path in `from`, which reach byte `start`, by one edge and
+    /// merge the results into the k best paths in `to`.
+    fn extend_paths(
+        from: &[LatticePath],
+        start: usize,
+        to: &mut Vec<LatticePath>,
+        piece: Option<u32>,
+        cost: f64,
+        k: usize,
+    ) {
+        for (rank, path) in from.iter().enumerate() {
+            let extended = LatticePath {
+                cost: path.cost + cost,
+                from: start,
+                rank,
+                piece: piece.map(|id| (id, cost)),
+            };
+            let index = to.partition_point(|other| other.cost <= extended.cost);
+            if index >= k {
+                // `from` is sorted, so later paths won't make it either.
+                break;
+            }
+            to.insert(index, extended);
+            to.truncate(k);
+        }
+    }
+
+    fn token_for_ascii_word(&self, word: &str, span: Range<usize>) -> Token {
+        let mut features = vec![];
+        let mut piece_spans = vec![];
+        let mut piece_probabilities = vec![];
+        let segmentations = self.segment(word, self.max_segmentations);
+        let best = segmentations.first().map_or(0.0, |first| first.log_prob);
+        let total: f64 = segmentations
+            .iter()
+            .map(|segmentation| (segmentation.log_prob - best).exp())
+            .sum();
+        for segmentation in segmentations {
+            let probability = (segmentation.log_prob - best).exp() / total;
+            let mut counted = vec![];
+            for piece in segmentation.pieces {
+                if counted.contains(&piece.id) {
+                    continue;
+                }
+                counted.push(piece.id);
+                match features.iter().position(|id| *id == piece.id) {
+                    Some(index) => piece_probabilities[index] += probability,
+                    None => {
+                        features.push(piece.id);
+                        piece_spans.push(piece.span);
+                        piece_probabilities.push(probability);
+                    }
+                }
+            }
+        }
+        let mut digit_count = None;
+        if !word.is_empty() && word.chars().all(|ch| matches!(ch, '0'..='9' | '-')) {
+            let count = word.chars().filter(char::is_ascii_digit).count() as u32;
+            if count > 0 {
+                features.push(self.digit_feature(count));
+                digit_count = Some(count);
             }
         }
+        Token {
+            span,
+            text: word.to_string(),
+            features,
+            piece_spans,
+            piece_probabilities,
+            digit_count,
+        }
+    }
+
+    /// Attribute id of an arbitrary attribute key, such as a template
+    /// attribute, if the vocabulary has it
+    pub fn attribute_id(&self, key: &[u8]) -> Option<u32> {
+        self.vocab
+            .get(key)
+            .map(|output| VocabOutput::decode(output.value()).id)
+    }
+
+    /// Attribute id of the digit-count feature `D:n`
+    pub(crate) fn digit_feature(&self, digit_count: u32) -> u32 {
+        // Format the key on the stack, since this runs for every numeric word.
+        let mut key = [0u8; 12];
+        let mut start = key.len();
+        let mut remaining = digit_count;
+        loop {
+            start -= 1;
+            key[start] = b'0' + (remaining % 10) as u8;
+            remaining /= 10;
+            if remaining == 0 {
+                break;
+            }
+        }
+        key[start - 2..start].copy_from_slice(b"D:");
+        match self.vocab.get(&key[start - 2..]) {
+            Some(output) => VocabOutput::decode(output.value()).id,
+            None => self.vocab.len() as u32 + digit_count,
+        }
+    }
+
+    fn names(&self) -> &Names {
+        self.names.get_or_init(|| {
+            let mut text = String::new();
+            let mut offsets = vec![0u32];
+            let mut stream = self.vocab.stream();
+            while let Some((key, _output)) = stream.next() {
+                text.push_str(&String::from_utf8_lossy(key));
+                offsets.push(text.len() as u32);
+            }
+            Names { text, offsets }
+        })
     }
 
     pub fn stringify_feature(&self, feature: u32) -> String {
-        if let Some(s) = self.feature_strings.get(&feature) {
-            s.clone()
+        let names = self.names();
+        let feature = feature as usize;
+        if feature + 1 < names.offsets.len() {
+            let (start, end) = (names.offsets[feature], names.offsets[feature + 1]);
+            names.text[start as usize..end as usize].to_string()
         } else {
-            format!("D:{}", feature - self.feature_count)
+            format!("D:{}", feature + 1 - names.offsets.len())
+        }
+    }
+}
+
+/// Transliterate a string to lowercase ASCII, returning the transliterated
+/// string along with the byte range of the source character that produced each
+/// byte of it.
+fn transliterate(string: &str) -> (String, Vec<Range<usize>>) {
+    let mut transliterated = String::with_capacity(string.len());
+    let mut source_spans = Vec::with_capacity(string.len());
+    for (idx, ch) in string.char_indices() {
+        let ascii = deunicode_char(ch).unwrap_or("[?]");
+        transliterated.push_str(&ascii.to_ascii_lowercase());
+        source_spans.extend(std::iter::repeat_n(idx..idx + ch.len_utf8(), ascii.len()));
+    }
+    (transliterated, source_spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fst::SetBuilder;
+
+    fn vocab_tokenizer(keys: &[&str]) -> Tokenizer {
+        let mut keys = keys.to_vec();
+        keys.sort();
+        let mut builder = SetBuilder::memory();
+        builder.extend_iter(keys).unwrap();
+        Tokenizer::new(Fst::new(builder.into_inner().unwrap()).as_ref().unwrap())
+    }
+
+    #[test]
+    fn test_ids_follow_vocab_order() {
+        let tokenizer = vocab_tokenizer(&["main", "ma", "st", "street"]);
+        // "ma" then "main", in bytewise order
+        assert_eq!(tokenizer.tokenize("Main Street"), vec![vec![1], vec![3]]);
+        assert_eq!(tokenizer.stringify_feature(1), "main");
+        assert_eq!(tokenizer.stringify_feature(3), "street");
+    }
+
+    #[test]
+    fn test_digit_features() {
+        // Without a `D:n` entry, digit counts get reserved ids past the vocab.
+        let tokenizer = vocab_tokenizer(&["main"]);
+        assert_eq!(tokenizer.tokenize("12-34"), vec![vec![5]]);
+        assert_eq!(tokenizer.stringify_feature(5), "D:4");
+
+        // With one, they use the vocabulary's id.
+        let with_digits = vocab_tokenizer(&["D:3", "main"]);
+        assert_eq!(with_digits.tokenize("123"), vec![vec![0]]);
+        assert_eq!(with_digits.tokenize("1234567890"), vec![vec![12]]);
+        assert_eq!(with_digits.stringify_feature(12), "D:10");
+    }
+
+    fn scored_tokenizer(pieces: &[(&str, f64)]) -> Tokenizer {
+        let mut pieces = pieces.to_vec();
+        pieces.sort_by(|a, b| a.0.cmp(b.0));
+        let mut builder = MapBuilder::memory();
+        for (id, (piece, log_prob)) in pieces.iter().enumerate() {
+            let output = VocabOutput {
+                id: id as u32,
+                log_prob: Some(*log_prob),
+            };
+            builder.insert(piece, output.encode()).unwrap();
         }
+        Tokenizer::new(&Fst::new(builder.into_inner().unwrap()).unwrap())
+    }
+
+    fn piece_text<'a>(word: &'a str, segmentation: &Segmentation) -> Vec<&'a str> {
+        segmentation
+            .pieces
+            .iter()
+            .map(|piece| &word[piece.span.clone()])
+            .collect()
+    }
+
+    #[test]
+    fn test_vocab_output_roundtrip() {
+        for output in [
+            VocabOutput {
+                id: 7,
+                log_prob: None,
+            },
+            VocabOutput {
+                id: u32::MAX,
+                log_prob: Some(-12.345),
+            },
+            VocabOutput {
+                id: 0,
+                log_prob: Some(0.0),
+            },
+        ] {
+            assert_eq!(VocabOutput::decode(output.encode()), output);
+        }
+    }
+
+    #[test]
+    fn test_best_segmentations() {
+        let tokenizer = scored_tokenizer(&[
+            ("main", -3.0),
+            ("ma", -2.0),
+            ("in", -2.0),
+            ("street", -3.0),
+            ("st", -1.0),
+            ("reet", -4.0),
+        ]);
+        let word = "mainstreet";
+        let segmentations = tokenizer.segment(word, 3);
+        assert_eq!(segmentations.len(), 3);
+        assert_eq!(piece_text(word, &segmentations[0]), vec!["main", "street"]);
+        assert!((segmentations[0].log_prob + 6.0).abs() < 1e-9);
+        assert_eq!(
+            piece_text(word, &segmentations[1]),
+            vec!["ma", "in", "street"]
+        );
+        assert_eq!(
+            piece_text(word, &segmentations[2]),
+            vec!["main", "st", "reet"]
+        );
+        assert!(segmentations
+            .windows(2)
+            .all(|pair| pair[0].log_prob >= pair[1].log_prob));
+
+        // Only the best segmentation's pieces become features by default.
+        let mut features = tokenizer.tokenize(word).remove(0);
+        features.sort();
+        assert_eq!(
+            features,
+            vec![
+                tokenizer.vocab.get("main").unwrap().value() as u32,
+                tokenizer.vocab.get("street").unwrap().value() as u32,
+            ]
+        );
+
+        // With more segmentations, each piece is weighted by the share of
+        // them that contain it.
+        let token = tokenizer
+            .with_max_segmentations(3)
+            .tokenize_with_spans(word)
+            .remove(0);
+        let total = 1.0 + (-1.0f64).exp() + (-2.0f64).exp();
+        let probability = |piece: &str| {
+            let index = token
+                .piece_spans
+                .iter()
+                .position(|span| &word[span.clone()] == piece)
+                .unwrap();
+            token.piece_probabilities[index]
+        };
+        assert!((probability("main") - (1.0 + (-2.0f64).exp()) / total).abs() < 1e-9);
+        assert!((probability("in") - (-1.0f64).exp() / total).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_unknown_bytes_skipped() {
+        let tokenizer = scored_tokenizer(&[("main", -3.0)]);
+        let segmentations = tokenizer.segment("xmainx", 1);
+        assert_eq!(piece_text("xmainx", &segmentations[0]), vec!["main"]);
+        assert!(tokenizer.segment("", 2)[0].pieces.is_empty());
+    }
+
+    #[test]
+    fn test_long_word_is_linear() {
+        // Every substring of this word is a piece, which made the old
+        // exhaustive prefix recursion exponential.
+        let tokenizer = scored_tokenizer(&[("a", -1.0), ("aa", -1.5), ("aaa", -2.0)]);
+        let word = "a".repeat(99_999);
+        let segmentations = tokenizer.segment(&word, 4);
+        assert_eq!(segmentations.len(), 4);
+        assert!((segmentations[0].log_prob + 2.0 * 33_333.0).abs() < 1e-3);
     }
 }
@@ -0,0 +1,831 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rayon::prelude::*;
+
+use crate::context::{Context, Flag, Reset};
+use crate::dataset::{Attribute, Instance};
+use crate::error::{Error, Result};
+use crate::lbfgs::Lbfgs;
+use crate::model::Header;
+use crate::packed::PackedModel;
+use crate::quantize::Quantization;
+use crate::tokenizer::Tokenizer;
+
+/// Number of chunks the training sequences are split into to compute the
+/// gradient. It's fixed, rather than one per thread, so that the gradient is
+/// summed the same way on any machine.
+const GRADIENT_CHUNKS: usize = 64;
+
+/// Labeled item sequences to train a model on
+///
+/// Attributes and labels are interned as they're added, in order of first
+/// appearance. Every sequence is held in memory, since L-BFGS goes over all of
+/// them on each iteration, so memory grows with the corpus. Training doesn't
+/// stream from the corpus; cap the number of sequences to fit.
+#[derive(Debug, Clone, Default)]
+pub struct TrainingData {
+    attributes: Vec<String>,
+    attribute_ids: HashMap<String, u32>,
+    labels: Vec<String>,
+    label_ids: HashMap<String, u32>,
+    instances: Vec<Instance>,
+}
+
+impl TrainingData {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an item sequence, given as the attributes and values of each item,
+    /// along with the label of each item. `group` can be used to hold the
+    /// sequence out of training (see `TrainerConfig::holdout`). Empty
+    /// sequences are ignored.
+    pub fn push<A: AsRef<str>, L: AsRef<str>>(
+        &mut self,
+        items: &[Vec<(A, f64)>],
+        labels: &[L],
+        group: u32,
+    ) -> Result<()> {
+        if items.len() != labels.len() {
+            return Err(Error::InvalidInput(format!(
+                "{} items but {} labels",
+                items.len(),
+                labels.len()
+            )));
+        }
+        if items.is_empty() {
+            return Ok(());
+        }
+        let mut instance = Instance::with_capacity(items.len());
+        instance.group = group;
+        for (item, label) in items.iter().zip(labels) {
+            let item = item
+                .iter()
+                .map(|(name, value)| {
+                    let id = intern(&mut self.attributes, &mut self.attribute_ids, name.as_ref());
+                    Attribute::new(id, *value)
+                })
+                .collect();
+            let label = intern(&mut self.labels, &mut self.label_ids, label.as_ref());
+            instance.push(item, label);
+        }
+        self.instances.push(instance);
+        Ok(())
+    }
+
+    /// Number of item sequences
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+}
+
+fn intern(names: &mut Vec<String>, ids: &mut HashMap<String, u32>, name: &str) -> u32 {
+    if let Some(id) = ids.get(name) {
+        return *id;
+    }
+    let id = names.len() as u32;
+    names.push(name.to_string());
+    ids.insert(name.to_string(), id);
+    id
+}
+
+/// Training algorithm and its parameters
+#[derive(Debug, Clone, PartialEq)]
+pub enum Algorithm {
+    /// Maximize the log-likelihood of the training data with L-BFGS
+    Lbfgs {
+        /// Coefficient of the L1 penalty. A nonzero value switches to OWL-QN,
+        /// which leaves many weights at exactly zero.
+        c1: f64,
+        /// Coefficient of the L2 penalty
+        c2: f64,
+        /// Number of corrections kept to approximate the inverse Hessian
+        memory: usize,
+        max_iterations: usize,
+        /// Stop once the norm of the gradient is this small relative to the
+        /// norm of the weights
+        epsilon: f64,
+        /// Stop once the loss has improved by less than `delta`, relative to
+        /// its value, over the last `past` iterations. Zero disables the test.
+        past: usize,
+        delta: f64,
+        /// Number of backtracking steps to try in each line search
+        max_linesearch: usize,
+    },
+    /// Averaged perceptron, one pass over the training data per epoch
+    AveragedPerceptron { epochs: usize },
+    /// Averaged passive-aggressive (PA-I) updates with aggressiveness `c`
+    PassiveAggressive { epochs: usize, c: f64 },
+}
+
+impl Algorithm {
+    /// L-BFGS with the given penalties and iteration limit, and crfsuite's
+    /// defaults for everything else
+    pub fn lbfgs(c1: f64, c2: f64, max_iterations: usize) -> Self {
+        Algorithm::Lbfgs {
+            c1,
+            c2,
+            memory: 6,
+            max_iterations,
+            epsilon: 1e-5,
+            past: 10,
+            delta: 1e-5,
+            max_linesearch: 20,
+        }
+    }
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::lbfgs(0.0, 1.0, 100)
+    }
+}
+
+/// How to train a model
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrainerConfig {
+    pub algorithm: Algorithm,
+    /// Group of the sequences held out of training and used to measure
+    /// accuracy after each iteration. The weights with the best held-out
+    /// accuracy are kept.
+    pub holdout: Option<u32>,
+    /// Stop after this many iterations without an improvement in held-out
+    /// accuracy
+    pub patience: Option<usize>,
+    /// Pass a snapshot of the model to the progress callback every this many
+    /// iterations
+    pub checkpoint_every: Option<usize>,
+    /// Seed for the order in which online algorithms visit the sequences
+    pub seed: u64,
+}
+
+/// Training progress, reported after every iteration
+#[derive(Debug)]
+pub struct Progress<'a> {
+    pub iteration: usize,
+    /// Value of the training objective: the penalized negative log-likelihood
+    /// for L-BFGS, or the sum of the per-sequence losses for online algorithms
+    pub loss: f64,
+    /// Share of held-out items labeled correctly
+    pub holdout_accuracy: Option<f64>,
+    /// Snapshot of the model, on checkpoint iterations
+    pub checkpoint: Option<&'a TrainedModel>,
+}
+
+/// State features of a training set: one for each attribute and label that
+/// occur together outside the held-out group
+///
+/// The weight vector holds the `[L][L]` transition weights followed by one
+/// weight per state feature.
+#[derive(Debug, Clone)]
+struct FeatureSpace {
+    num_labels: usize,
+    /// Start of each attribute's state features, with a final entry for the
+    /// end of the last attribute's
+    attr_offsets: Vec<usize>,
+    /// Label of each state feature
+    feature_labels: Vec<u32>,
+}
+
+impl FeatureSpace {
+    fn new(data: &TrainingData, holdout: Option<u32>) -> Self {
+        let mut attr_labels = vec![Vec::new(); data.attributes.len()];
+        for instance in data.instances.iter() {
+            if Some(instance.group) == holdout {
+                continue;
+            }
+            for (item, label) in instance.items.iter().zip(&instance.labels) {
+                for attribute in item {
+                    attr_labels[attribute.id as usize].push(*label);
+                }
+            }
+        }
+        let mut attr_offsets = Vec::with_capacity(attr_labels.len() + 1);
+        let mut feature_labels = Vec::new();
+        for mut labels in attr_labels {
+            labels.sort_unstable();
+            labels.dedup();
+            attr_offsets.push(feature_labels.len());
+            feature_labels.extend(labels);
+        }
+        attr_offsets.push(feature_labels.len());
+        Self {
+            num_labels: data.labels.len(),
+            attr_offsets,
+            feature_labels,
+        }
+    }
+
+    fn num_weights(&self) -> usize {
+        self.num_labels * self.num_labels + self.feature_labels.len()
+    }
+
+    /// Indices into the weight vector and labels of an attribute's state
+    /// features
+    fn state_features(&self, attr: u32) -> impl Iterator<Item = (usize, u32)> + '_ {
+        let offset = self.num_labels * self.num_labels;
+        let range = self.attr_offsets[attr as usize]..self.attr_offsets[attr as usize + 1];
+        range
+            .clone()
+            .zip(self.feature_labels[range].iter().copied())
+            .map(move |(index, label)| (offset + index, label))
+    }
+
+    /// Load the scores of `instance` under `weights` into `context`
+    fn set_scores(&self, context: &mut Context, instance: &Instance, weights: &[f64]) {
+        let l = self.num_labels;
+        context.set_num_items(instance.num_items);
+        context.reset(Reset::ALL);
+        context.trans.copy_from_slice(&weights[..l * l]);
+        for (t, item) in instance.items.iter().enumerate() {
+            let state = &mut context.state[l * t..l * (t + 1)];
+            for attribute in item {
+                for (index, label) in self.state_features(attribute.id) {
+                    state[label as usize] += weights[index] * attribute.value;
+                }
+            }
+        }
+    }
+
+    /// Call `f` with the index and value of every feature that fires when
+    /// `instance` is labeled `labels`, scaled by `scale`
+    fn for_each_feature(
+        &self,
+        instance: &Instance,
+        labels: &[u32],
+        scale: f64,
+        mut f: impl FnMut(usize, f64),
+    ) {
+        let l = self.num_labels;
+        for (t, (item, label)) in instance.items.iter().zip(labels).enumerate() {
+            for attribute in item {
+                if let Some((index, _)) = self
+                    .state_features(attribute.id)
+                    .find(|(_, feature_label)| feature_label == label)
+                {
+                    f(index, scale * attribute.value);
+                }
+            }
+            if t > 0 {
+                f(labels[t - 1] as usize * l + *label as usize, scale);
+            }
+        }
+    }
+}
+
+/// A trained model, with weights over the feature space of its training data
+#[derive(Debug, Clone)]
+pub struct TrainedModel {
+    labels: Vec<String>,
+    attributes: Vec<String>,
+    features: FeatureSpace,
+    weights: Vec<f64>,
+}
+
+impl TrainedModel {
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Number of features with a nonzero weight
+    pub fn num_active_features(&self) -> usize {
+        self.weights.iter().filter(|weight| **weight != 0.0).count()
+    }
+
+    /// Label an item sequence, given as the attributes and values of each
+    /// item. Attributes that weren't seen in training are ignored.
+    pub fn tag<A: AsRef<str>>(&self, items: &[Vec<(A, f64)>]) -> Vec<&str> {
+        if items.is_empty() {
+            return Vec::new();
+        }
+        let attribute_ids: HashMap<&str, u32> = self
+            .attributes
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (name.as_str(), id as u32))
+            .collect();
+        let mut instance = Instance::with_capacity(items.len());
+        for item in items {
+            let item = item
+                .iter()
+                .filter_map(|(name, value)| {
+                    let id = attribute_ids.get(name.as_ref())?;
+                    Some(Attribute::new(*id, *value))
+                })
+                .collect();
+            instance.push(item, 0);
+        }
+        let mut context = Context::new(Flag::VITERBI, self.labels.len() as u32, instance.num_items);
+        self.features
+            .set_scores(&mut context, &instance, &self.weights);
+        let (labels, _) = context.viterbi();
+        labels
+            .iter()
+            .map(|label| self.labels[*label as usize].as_str())
+            .collect()
+    }
+
+    /// Pack the model for the parser, leaving out features whose weight is
+    /// zero
+    pub fn pack(
+        &self,
+        pieces: Option<&Tokenizer>,
+        quantization: Quantization,
+    ) -> Result<PackedModel> {
+        let l = self.labels.len();
+        let transitions: Vec<(u32, u32, f64)> = self.weights[..l * l]
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight != 0.0)
+            .map(|(index, weight)| ((index / l) as u32, (index % l) as u32, *weight))
+            .collect();
+        let mut state_features = Vec::new();
+        for (attr, name) in self.attributes.iter().enumerate() {
+            for (index, label) in self.features.state_features(attr as u32) {
+                if self.weights[index] != 0.0 {
+                    state_features.push((name.clone(), label, self.weights[index]));
+                }
+            }
+        }
+        // `pack` fills in the counts and clears the offsets, which only
+        // describe the layout of crfsuite's format.
+        let header = Header {
+            magic: *b"lCRF",
+            size: 0,
+            r#type: *b"FOMC",
+            version: 100,
+            num_features: 0,
+            num_labels: 0,
+            num_attrs: 0,
+            off_features: 0,
+            off_labels: 0,
+            off_attrs: 0,
+            off_label_refs: 0,
+            off_attr_refs: 0,
+        };
+        PackedModel::pack(
+            header,
+            self.labels.clone(),
+            &transitions,
+            &state_features,
+            pieces,
+            quantization,
+        )
+    }
+}
+
+/// Train a model on `data`, calling `progress` after every iteration
+pub fn train(
+    data: &TrainingData,
+    config: &TrainerConfig,
+    mut progress: impl FnMut(&Progress<'_>),
+) -> TrainedModel {
+    let features = FeatureSpace::new(data, config.holdout);
+    let (training, holdout): (Vec<&Instance>, Vec<&Instance>) = data
+        .instances
+        .iter()
+        .partition(|instance| Some(instance.group) != config.holdout);
+    let trainer = Trainer {
+        data,
+        config,
+        features,
+        training,
+        holdout,
+    };
+    let mut best = Best::default();
+    let weights = match &config.algorithm {
+        Algorithm::Lbfgs {
+            c1,
+            c2,
+            memory,
+            max_iterations,
+            epsilon,
+            past,
+            delta,
+            max_linesearch,
+        } => {
+            let lbfgs = Lbfgs {
+                memory: *memory,
+                c1: *c1,
+                max_iterations: *max_iterations,
+                epsilon: *epsilon,
+                past: *past,
+                delta: *delta,
+                max_linesearch: *max_linesearch,
+            };
+            trainer.lbfgs(&lbfgs, *c2, &mut best, &mut progress)
+        }
+        Algorithm::AveragedPerceptron { epochs } => {
+            trainer.online(*epochs, None, &mut best, &mut progress)
+        }
+        Algorithm::PassiveAggressive { epochs, c } => {
+            trainer.online(*epochs, Some(*c), &mut best, &mut progress)
+        }
+    };
+    let weights = match best.weights {
+        Some((_, best)) => best,
+        None => weights,
+    };
+    trainer.model(weights)
+}
+
+struct Trainer<'a> {
+    data: &'a TrainingData,
+    config: &'a TrainerConfig,
+    features: FeatureSpace,
+    training: Vec<&'a Instance>,
+    holdout: Vec<&'a Instance>,
+}
+
+/// The iteration with the best held-out accuracy so far
+#[derive(Default)]
+struct Best {
+    /// Held-out accuracy and weights of the best iteration
+    weights: Option<(f64, Vec<f64>)>,
+    /// Number of iterations since the best one
+    stale: usize,
+}
+
+impl<'a> Trainer<'a> {
+    fn model(&self, weights: Vec<f64>) -> TrainedModel {
+        TrainedModel {
+            labels: self.data.labels.clone(),
+            attributes: self.data.attributes.clone(),
+            features: self.features.clone(),
+            weights,
+        }
+    }
+
+    fn context(&self) -> Context {
+        Context::new(
+            Flag::VITERBI | Flag::MARGINALS,
+            self.features.num_labels as u32,
+            1,
+        )
+    }
+
+    /// Negative log-likelihood of the training data and its gradient
+    fn log_likelihood(&self, weights: &[f64], gradient: &mut [f64]) -> f64 {
+        let chunk_size = self.training.len().div_ceil(GRADIENT_CHUNKS).max(1);
+        let chunks: Vec<&[&Instance]> = self.training.chunks(chunk_size).collect();
+        gradient.fill(0.0);
+        let mut loss = 0.0;
+        // A batch of chunks at a time, one per thread, to hold only as many
+        // gradients as there are threads. The chunks are summed in order,
+        // since floating point addition in whatever order the threads finish
+        // wouldn't give the same model twice.
+        for batch in chunks.chunks(rayon::current_num_threads()) {
+            let results: Vec<(f64, Vec<f64>)> = batch
+                .par_iter()
+                .map(|chunk| self.chunk_log_likelihood(chunk, weights))
+                .collect();
+            for (chunk_loss, chunk_gradient) in results {
+                loss += chunk_loss;
+                gradient
+                    .iter_mut()
+                    .zip(chunk_gradient)
+                    .for_each(|(a, b)| *a += b);
+            }
+        }
+        loss
+    }
+
+    /// Negative log-likelihood of some training sequences and its gradient
+    fn chunk_log_likelihood(&self, chunk: &[&Instance], weights: &[f64]) -> (f64, Vec<f64>) {
+        let l = self.features.num_labels;
+        let mut context = self.context();
+        let mut loss = 0.0;
+        let mut gradient = vec![0.0; weights.len()];
+        for instance in chunk {
+            self.features.set_scores(&mut context, instance, weights);
+            context.exp_transition();
+            context.exp_state();
+            context.alpha_score();
+            context.beta_score();
+            context.marginals();
+            loss += instance.weight * (context.lognorm() - context.score(&instance.labels));
+
+            // Expected minus observed feature counts
+            for (t, item) in instance.items.iter().enumerate() {
+                let marginals = context.state_marginals(t as u32);
+                for attribute in item {
+                    for (index, label) in self.features.state_features(attribute.id) {
+                        gradient[index] +=
+                            instance.weight * attribute.value * marginals[label as usize];
+                    }
+                }
+            }
+            for (index, marginal) in context.transition_marginals().iter().enumerate() {
+                gradient[index] += instance.weight * marginal;
+            }
+            self.features.for_each_feature(
+                instance,
+                &instance.labels,
+                -instance.weight,
+                |index, value| gradient[index] += value,
+            );
+        }
+        debug_assert_eq!(gradient.len(), l * l + self.features.feature_labels.len());
+        (loss, gradient)
+    }
+
+    /// Share of held-out items that `weights` label correctly
+    fn holdout_accuracy(&self, weights: &[f64]) -> Option<f64> {
+        if self.holdout.is_empty() {
+            return None;
+        }
+        let chunk_size = self
+            .holdout
+            .len()
+            .div_ceil(rayon::current_num_threads())
+            .max(1);
+        let (correct, total) = self
+            .holdout
+            .par_chunks(chunk_size)
+            .map(|chunk| {
+                let mut context = self.context();
+                let mut correct = 0usize;
+                let mut total = 0usize;
+                for instance in chunk {
+                    self.features.set_scores(&mut context, instance, weights);
+                    let (labels, _) = context.viterbi();
+                    correct += labels
+                        .iter()
+                        .zip(&instance.labels)
+                        .filter(|(predicted, gold)| predicted == gold)
+                        .count();
+                    total += labels.len();
+                }
+                (correct, total)
+            })
+            .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+        Some(correct as f64 / total as f64)
+    }
+
+    /// Report progress for an iteration, keeping track of the best held-out
+    /// accuracy. Returns whether training should go on.
+    fn report(
+        &self,
+        best: &mut Best,
+        iteration: usize,
+        loss: f64,
+        weights: &[f64],
+        progress: &mut impl FnMut(&Progress<'_>),
+    ) -> bool {
+        let holdout_accuracy = self.holdout_accuracy(weights);
+        if let Some(accuracy) = holdout_accuracy {
+            match &best.weights {
+                Some((best_accuracy, _)) if *best_accuracy >= accuracy => best.stale += 1,
+                _ => {
+                    best.weights = Some((accuracy, weights.to_vec()));
+                    best.stale = 0;
+                }
+            }
+        }
+        let checkpoint = match self.config.checkpoint_every {
+            Some(every) if every > 0 && iteration.is_multiple_of(every) => {
+                Some(self.model(weights.to_vec()))
+            }
+            _ => None,
+        };
+        progress(&Progress {
+            iteration,
+            loss,
+            holdout_accuracy,
+            checkpoint: checkpoint.as_ref(),
+        });
+        match self.config.patience {
+            Some(patience) => best.stale < patience,
+            None => true,
+        }
+    }
+
+    fn lbfgs(
+        &self,
+        lbfgs: &Lbfgs,
+        c2: f64,
+        best: &mut Best,
+        progress: &mut impl FnMut(&Progress<'_>),
+    ) -> Vec<f64> {
+        let mut weights = vec![0.0; self.features.num_weights()];
+        let evaluate = |weights: &[f64], gradient: &mut [f64]| {
+            let mut loss = self.log_likelihood(weights, gradient);
+            if c2 > 0.0 {
+                for (gradient, weight) in gradient.iter_mut().zip(weights) {
+                    loss += c2 * weight * weight;
+                    *gradient += 2.0 * c2 * weight;
+                }
+            }
+            loss
+        };
+        // A failed line search leaves the weights of the last iteration that
+        // improved the objective, which are as good as this run gets.
+        lbfgs.minimize(&mut weights, evaluate, |iteration, weights, loss| {
+            self.report(best, iteration, loss, weights, progress)
+        });
+        weights
+    }
+
+    /// Averaged perceptron, or passive-aggressive updates when `c` is given
+    fn online(
+        &self,
+        epochs: usize,
+        c: Option<f64>,
+        best: &mut Best,
+        progress: &mut impl FnMut(&Progress<'_>),
+    ) -> Vec<f64> {
+        let n = self.features.num_weights();
+        let mut weights = vec![0.0; n];
+        // Sum of each update scaled by the number of updates before it, from
+        // which the average is recovered
+        let mut weighted_updates = vec![0.0; n];
+        let mut count = 1.0;
+        let mut rng = StdRng::seed_from_u64(self.config.seed);
+        let mut order: Vec<usize> = (0..self.training.len()).collect();
+        let mut context = self.context();
+        // Ordered, so that the norm below is summed the same way every time
+        let mut delta: BTreeMap<usize, f64> = BTreeMap::new();
+        for epoch in 1..=epochs {
+            order.shuffle(&mut rng);
+            let mut loss = 0.0;
+            for index in order.iter() {
+                let instance = self.training[*index];
+                self.features.set_scores(&mut context, instance, &weights);
+                let (predicted, predicted_score) = context.viterbi();
+                let errors = predicted
+                    .iter()
+                    .zip(&instance.labels)
+                    .filter(|(predicted, gold)| predicted != gold)
+                    .count();
+                if errors > 0 {
+                    delta.clear();
+                    self.features.for_each_feature(
+                        instance,
+                        &instance.labels,
+                        1.0,
+                        |index, value| *delta.entry(index).or_insert(0.0) += value,
+                    );
+                    self.features
+                        .for_each_feature(instance, &predicted, -1.0, |index, value| {
+                            *delta.entry(index).or_insert(0.0) += value
+                        });
+                    let step = match c {
+                        None => {
+                            loss += errors as f64;
+                            instance.weight
+                        }
+                        Some(c) => {
+                            let margin = predicted_score - context.score(&instance.labels)
+                                + (errors as f64).sqrt();
+                            let norm: f64 = delta.values().map(|value| value * value).sum();
+                            loss += margin;
+                            if norm > 0.0 {
+                                (margin / norm).min(c) * instance.weight
+                            } else {
+                                0.0
+                            }
+                        }
+                    };
+                    for (index, value) in delta.iter() {
+                        weights[*index] += step * value;
+                        weighted_updates[*index] += count * step * value;
+                    }
+                }
+                count += 1.0;
+            }
+            let averaged: Vec<f64> = weights
+                .iter()
+                .zip(&weighted_updates)
+                .map(|(weight, update)| weight - update / count)
+                .collect();
+            if !self.report(best, epoch, loss, &averaged, progress) || epoch == epochs {
+                return averaged;
+            }
+        }
+        weights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sequences of a house number, a street name and a street suffix, where
+    /// a few of the street names also appear as suffixes
+    fn training_data() -> TrainingData {
+        let mut data = TrainingData::new();
+        let streets = ["main", "oak", "pine", "elm", "cedar", "park"];
+        let suffixes = ["st", "ave", "rd", "park"];
+        for (i, street) in streets.iter().enumerate() {
+            for (j, suffix) in suffixes.iter().enumerate() {
+                let items = vec![
+                    vec![("digits".to_string(), 1.0)],
+                    vec![(format!("w={}", street), 1.0)],
+                    vec![(format!("w={}", suffix), 1.0), ("last".to_string(), 1.0)],
+                ];
+                let group = if (i + j) % 7 == 0 { 1 } else { 0 };
+                data.push(&items, &["house_number", "road", "suffix"], group)
+                    .unwrap();
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_gradient() {
+        let data = training_data();
+        let config = TrainerConfig::default();
+        let trainer = Trainer {
+            data: &data,
+            config: &config,
+            features: FeatureSpace::new(&data, None),
+            training: data.instances.iter().collect(),
+            holdout: Vec::new(),
+        };
+        let n = trainer.features.num_weights();
+        let weights: Vec<f64> = (0..n).map(|i| ((i * 7) % 5) as f64 * 0.1 - 0.2).collect();
+        let mut gradient = vec![0.0; n];
+        trainer.log_likelihood(&weights, &mut gradient);
+        let mut scratch = vec![0.0; n];
+        let epsilon = 1e-6;
+        for i in 0..n {
+            let mut plus = weights.clone();
+            plus[i] += epsilon;
+            let mut minus = weights.clone();
+            minus[i] -= epsilon;
+            let numeric = (trainer.log_likelihood(&plus, &mut scratch)
+                - trainer.log_likelihood(&minus, &mut scratch))
+                / (2.0 * epsilon);
+            assert!(
+                (numeric - gradient[i]).abs() < 1e-4,
+                "weight {}: {} != {}",
+                i,
+                numeric,
+                gradient[i]
+            );
+        }
+    }
+
+    fn check_predictions(model: &TrainedModel) {
+        let items = vec![
+            vec![("digits", 1.0)],
+            vec![("w=park", 1.0)],
+            vec![("w=park", 1.0), ("last", 1.0)],
+        ];
+        assert_eq!(model.tag(&items), vec!["house_number", "road", "suffix"]);
+        let items = vec![vec![("digits", 1.0)], vec![("w=unseen", 1.0)]];
+        assert_eq!(model.tag(&items), vec!["house_number", "road"]);
+    }
+
+    #[test]
+    fn test_train() {
+        let data = training_data();
+        assert!(TrainingData::new()
+            .push(&[vec![("digits", 1.0)]], &["house_number", "road"], 0)
+            .is_err());
+        let algorithms = [
+            Algorithm::default(),
+            Algorithm::Lbfgs {
+                c1: 0.1,
+                c2: 0.0,
+                memory: 6,
+                max_iterations: 100,
+                epsilon: 1e-5,
+                past: 0,
+                delta: 0.0,
+                max_linesearch: 20,
+            },
+            Algorithm::AveragedPerceptron { epochs: 5 },
+            Algorithm::PassiveAggressive { epochs: 5, c: 1.0 },
+        ];
+        for algorithm in algorithms {
+            let config = TrainerConfig {
+                algorithm: algorithm.clone(),
+                holdout: Some(1),
+                checkpoint_every: Some(2),
+                ..Default::default()
+            };
+            let mut iterations = 0;
+            let mut checkpoints = 0;
+            let model = train(&data, &config, |progress| {
+                iterations += 1;
+                assert_eq!(progress.iteration, iterations);
+                assert!(progress.holdout_accuracy.is_some());
+                checkpoints += progress.checkpoint.is_some() as usize;
+            });
+            assert!(iterations > 0, "{:?}", algorithm);
+            assert_eq!(checkpoints, iterations / 2, "{:?}", algorithm);
+            check_predictions(&model);
+            assert!(model.pack(None, Quantization::default()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use std::{error::Error, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::lp_file_stream::{
+    Libpostal, LineFormat, LineStream, LpEntryToken, LpFileEntry, ReadStats,
+};
+
+/// A corpus of labeled token sequences to train on
+pub trait TrainingSource: Iterator<Item = LpFileEntry> + Send {
+    /// What's been read so far, which stays available after the source is
+    /// consumed
+    fn stats(&self) -> ReadStats;
+}
+
+impl<F: LineFormat> TrainingSource for LineStream<F> {
+    fn stats(&self) -> ReadStats {
+        LineStream::stats(self)
+    }
+}
+
+/// The formats a training corpus can be in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SourceFormat {
+    /// libpostal's tab-separated training data
+    #[default]
+    Libpostal,
+    /// OpenAddresses CSV, made into queries
+    OpenAddresses,
+    /// One token and its tag per line, with blank lines between sequences
+    Conll,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl FromStr for SourceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "libpostal" => Ok(SourceFormat::Libpostal),
+            "openaddresses" => Ok(SourceFormat::OpenAddresses),
+            "conll" | "bio" => Ok(SourceFormat::Conll),
+            "jsonl" => Ok(SourceFormat::Jsonl),
+            _ => Err(format!(
+                "unknown corpus format `{}`, expected `libpostal`, `openaddresses`, `conll` or `jsonl`",
+                s
+            )),
+        }
+    }
+}
+
+impl SourceFormat {
+    /// Read inputs in this format as one source. Inputs are given as for
+    /// `LineStream::with_format`.
+    pub fn open<S: AsRef<str>>(
+        self,
+        patterns: &[S],
+    ) -> Result<Box<dyn TrainingSource>, Box<dyn Error>> {
+        Ok(match self {
+            SourceFormat::Libpostal => Box::new(LineStream::with_format(patterns, Libpostal)?),
+            SourceFormat::OpenAddresses => {
+                Box::new(LineStream::with_format(patterns, OpenAddresses::default())?)
+            }
+            SourceFormat::Conll => Box::new(LineStream::with_format(patterns, Conll::default())?),
+            SourceFormat::Jsonl => Box::new(LineStream::with_format(patterns, Jsonl)?),
+        })
+    }
+}
+
+/// OpenAddresses columns that make up queries, and their labels
+const OPENADDRESSES_COLUMNS: [(&str, &str); 6] = [
+    ("number", "house_number"),
+    ("street", "road"),
+    ("unit", "unit"),
+    ("city", "city"),
+    ("region", "state"),
+    ("postcode", "postcode"),
+];
+
+/// Groups of `OPENADDRESSES_COLUMNS` that are separated by commas in queries
+const OPENADDRESSES_GROUPS: [&[usize]; 3] = [&[0, 1, 2], &[3], &[4, 5]];
+
+/// OpenAddresses CSV. Each row becomes a query like
+/// `5 main st, seattle, wa 98101`, with commas labeled `FSEP` as libpostal
+/// labels them. Columns are found by the header, which every input starts
+/// with.
+#[derive(Debug, Clone, Default)]
+pub struct OpenAddresses {
+    /// The header of the current input, once it's read
+    header: Option<Result<Header, String>>,
+}
+
+#[derive(Debug, Clone)]
+struct Header {
+    /// Number of columns
+    width: usize,
+    /// Where each of `OPENADDRESSES_COLUMNS` is
+    columns: Vec<Option<usize>>,
+}
+
+impl LineFormat for OpenAddresses {
+    fn start(&mut self) {
+        self.header = None;
+    }
+
+    fn line(&mut self, line: &str) -> Result<Option<LpFileEntry>, String> {
+        let fields = split_csv(line)?;
+        let Header { width, columns } = match &self.header {
+            Some(header) => header.clone()?,
+            None => {
+                let columns: Vec<Option<usize>> = OPENADDRESSES_COLUMNS
+                    .iter()
+                    .map(|(name, _)| {
+                        fields
+                            .iter()
+                            .position(|field| field.trim().eq_ignore_ascii_case(name))
+                    })
+                    .collect();
+                let header = if columns[0].is_some() && columns[1].is_some() {
+                    Ok(Header {
+                        width: fields.len(),
+                        columns,
+                    })
+                } else {
+                    Err("the header has no NUMBER and STREET columns".to_string())
+                };
+                self.header = Some(header.clone());
+                return header.map(|_| None);
+            }
+        };
+        if fields.len() != width {
+            return Err(format!(
+                "expected {} fields but found {}",
+                width,
+                fields.len()
+            ));
+        }
+
+        let mut tokens = vec![];
+        for group in OPENADDRESSES_GROUPS {
+            let mut group_tokens = vec![];
+            for component in group {
+                let value = match columns[*component] {
+                    Some(column) => &fields[column],
+                    None => continue,
+                };
+                let label = OPENADDRESSES_COLUMNS[*component].1;
+                group_tokens.extend(
+                    value
+                        .split_whitespace()
+                        .map(|word| LpEntryToken::new(word, label)),
+                );
+            }
+            if group_tokens.is_empty() {
+                continue;
+            }
+            if !tokens.is_empty() {
+                tokens.push(LpEntryToken::new(",", "FSEP"));
+            }
+            tokens.extend(group_tokens);
+        }
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(LpFileEntry {
+            lang: String::new(),
+            country: String::new(),
+            tokens,
+        }))
+    }
+}
+
+/// Split a line of CSV into fields, which may be quoted
+fn split_csv(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (_, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+/// CoNLL-style token-per-line data: a token and its tag separated by
+/// whitespace, with any columns between them ignored and blank lines between
+/// sequences. BIO prefixes like `B-` are taken off tags, so `B-road` and
+/// `I-road` are both `road`. Outside tokens keep the tag `O`, which a label
+/// schema can drop.
+#[derive(Debug, Clone, Default)]
+pub struct Conll {
+    tokens: Vec<LpEntryToken>,
+}
+
+impl LineFormat for Conll {
+    fn start(&mut self) {
+        self.tokens.clear();
+    }
+
+    fn line(&mut self, line: &str) -> Result<Option<LpFileEntry>, String> {
+        let cells: Vec<&str> = line.split_whitespace().collect();
+        if cells.is_empty() {
+            return Ok(self.finish());
+        }
+        if cells[0] == "-DOCSTART-" {
+            return Ok(None);
+        }
+        if cells.len() < 2 {
+            return Err("expected a token and a tag".to_string());
+        }
+        let tag = cells[cells.len() - 1];
+        let label = ["B-", "I-", "E-", "S-", "L-", "U-"]
+            .iter()
+            .find_map(|prefix| tag.strip_prefix(prefix))
+            .unwrap_or(tag);
+        self.tokens.push(LpEntryToken::new(cells[0], label));
+        Ok(None)
+    }
+
+    fn finish(&mut self) -> Option<LpFileEntry> {
+        if self.tokens.is_empty() {
+            return None;
+        }
+        Some(LpFileEntry {
+            lang: String::new(),
+            country: String::new(),
+            tokens: std::mem::take(&mut self.tokens),
+        })
+    }
+}
+
+/// JSON lines, each an object with optional `lang` and `country` and either
+/// `tokens`, a list of `{"word": ..., "label": ...}`, or `text` and `spans`,
+/// a list of `{"start": ..., "end": ..., "label": ...}` with character
+/// offsets. Text outside spans is left out.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Jsonl;
+
+#[derive(Deserialize)]
+struct JsonEntry {
+    #[serde(default)]
+    lang: String,
+    #[serde(default)]
+    country: String,
+    tokens: Option<Vec<JsonToken>>,
+    text: Option<String>,
+    #[serde(default)]
+    spans: Vec<JsonSpan>,
+}
+
+#[derive(Deserialize)]
+struct JsonToken {
+    word: String,
+    label: String,
+}
+
+#[derive(Deserialize)]
+struct JsonSpan {
+    start: usize,
+    end: usize,
+    label: String,
+}
+
+impl LineFormat for Jsonl {
+    fn line(&mut self, line: &str) -> Result<Option<LpFileEntry>, String> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        let entry: JsonEntry = serde_json::from_str(line).map_err(|err| err.to_string())?;
+        let tokens = match (entry.tokens, entry.text) {
+            (Some(tokens), _) => tokens
+                .iter()
+                .map(|token| LpEntryToken::new(&token.word, &token.label))
+                .collect(),
+            (None, Some(text)) => {
+                let chars: Vec<char> = text.chars().collect();
+                let mut spans = entry.spans;
+                spans.sort_by_key(|span| span.start);
+                let mut tokens = vec![];
+                let mut covered = 0;
+                for span in &spans {
+                    if span.start < covered || span.start >= span.end || span.end > chars.len() {
+                        return Err(format!(
+                            "span {}..{} overlaps another or isn't within the text",
+                            span.start, span.end
+                        ));
+                    }
+                    covered = span.end;
+                    let words: String = chars[span.start..span.end].iter().collect();
+                    tokens.extend(
+                        words
+                            .split_whitespace()
+                            .map(|word| LpEntryToken::new(word, &span.label)),
+                    );
+                }
+                tokens
+            }
+            (None, None) => return Err("expected `tokens` or `text`".to_string()),
+        };
+        Ok(Some(LpFileEntry {
+            lang: entry.lang,
+            country: entry.country,
+            tokens,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labeled(entry: &LpFileEntry) -> Vec<(&str, &str)> {
+        entry
+            .tokens
+            .iter()
+            .map(|token| (token.transliterated.as_str(), token.label.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_openaddresses() {
+        let mut format = OpenAddresses::default();
+        format.start();
+        assert!(format
+            .line("LON,LAT,NUMBER,STREET,UNIT,CITY,DISTRICT,REGION,POSTCODE,ID,HASH")
+            .unwrap()
+            .is_none());
+        let entry = format
+            .line("-122.3,47.6,5,\"Main St\",,Seattle,King,WA,98101,,abc")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            labeled(&entry),
+            [
+                ("5", "house_number"),
+                ("main", "road"),
+                ("st", "road"),
+                (",", "FSEP"),
+                ("seattle", "city"),
+                (",", "FSEP"),
+                ("wa", "state"),
+                ("98101", "postcode"),
+            ]
+        );
+        assert!(format.line("-122.3,47.6,5").is_err());
+        assert!(format.line("-122.3,\"47.6").is_err());
+
+        format.start();
+        assert!(format.line("lon,lat,city").is_err());
+        assert!(format.line("1,2,seattle").is_err());
+    }
+
+    #[test]
+    fn test_conll() {
+        let mut format = Conll::default();
+        format.start();
+        assert!(format.line("-DOCSTART- O").unwrap().is_none());
+        assert!(format.line("5\tB-house_number").unwrap().is_none());
+        assert!(format.line("Main\tNNP\tB-road").unwrap().is_none());
+        assert!(format.line("St\tI-road").unwrap().is_none());
+        let entry = format.line("").unwrap().unwrap();
+        assert_eq!(
+            labeled(&entry),
+            [("5", "house_number"), ("main", "road"), ("st", "road")]
+        );
+        assert!(format.line("lonely").is_err());
+        assert!(format.line("Seattle\tB-city").unwrap().is_none());
+        let entry = format.finish().unwrap();
+        assert_eq!(labeled(&entry), [("seattle", "city")]);
+        assert!(format.finish().is_none());
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let mut format = Jsonl;
+        let entry = format
+            .line(r#"{"lang": "en", "tokens": [{"word": "5", "label": "house_number"}, {"word": "Main", "label": "road"}]}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.lang, "en");
+        assert_eq!(labeled(&entry), [("5", "house_number"), ("main", "road")]);
+
+        let entry = format
+            .line(r#"{"text": "5 Main St, Zürich", "spans": [{"start": 11, "end": 17, "label": "city"}, {"start": 2, "end": 9, "label": "road"}]}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            labeled(&entry),
+            [("main", "road"), ("st", "road"), ("zurich", "city")]
+        );
+
+        assert!(format
+            .line(r#"{"text": "5 Main", "spans": [{"start": 2, "end": 9, "label": "road"}]}"#)
+            .is_err());
+        assert!(format.line(r#"{"lang": "en"}"#).is_err());
+        assert!(format.line("not json").is_err());
+        assert!(format.line("").unwrap().is_none());
+        assert_eq!(
+            "conll".parse::<SourceFormat>().unwrap(),
+            SourceFormat::Conll
+        );
+    }
+}
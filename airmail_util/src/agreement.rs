@@ -0,0 +1,293 @@
+use std::{cmp::Ordering, collections::HashMap, fmt};
+
+use serde::Serialize;
+
+use crate::model::Weights;
+use crate::stats::{ratio, Rank, Worst};
+
+/// Scores label paths with a model's unquantized weights
+#[derive(Debug, Clone)]
+pub struct Scorer<'a> {
+    num_labels: usize,
+    transitions: Vec<f64>,
+    state_features: HashMap<&'a str, Vec<(u32, f64)>>,
+    labels: HashMap<&'a str, u32>,
+}
+
+impl<'a> Scorer<'a> {
+    pub fn new(weights: &'a Weights) -> Scorer<'a> {
+        let num_labels = weights.labels.len();
+        let mut transitions = vec![0.0; num_labels * num_labels];
+        for (source, target, weight) in &weights.transitions {
+            transitions[*source as usize * num_labels + *target as usize] = *weight;
+        }
+        let mut state_features: HashMap<&str, Vec<(u32, f64)>> = HashMap::new();
+        for (attribute, target, weight) in &weights.state_features {
+            state_features
+                .entry(attribute.as_str())
+                .or_default()
+                .push((*target, *weight));
+        }
+        let labels = weights
+            .labels
+            .iter()
+            .enumerate()
+            .map(|(id, label)| (label.as_str(), id as u32))
+            .collect();
+        Scorer {
+            num_labels,
+            transitions,
+            state_features,
+            labels,
+        }
+    }
+
+    /// Score of labeling `items`, given as the attributes and values of each
+    /// item, with `labels`. Unknown labels score nothing.
+    pub fn score<A: AsRef<str>>(&self, items: &[Vec<(A, f64)>], labels: &[&str]) -> f64 {
+        let ids: Vec<Option<u32>> = labels
+            .iter()
+            .map(|label| self.labels.get(label).copied())
+            .collect();
+        let mut score = 0.0;
+        for (item, id) in items.iter().zip(&ids) {
+            let id = match id {
+                Some(id) => *id,
+                None => continue,
+            };
+            for (name, value) in item {
+                for (target, weight) in self.state_features.get(name.as_ref()).into_iter().flatten()
+                {
+                    if *target == id {
+                        score += weight * value;
+                    }
+                }
+            }
+        }
+        for pair in ids.windows(2) {
+            if let [Some(source), Some(target)] = pair {
+                score += self.transitions[*source as usize * self.num_labels + *target as usize];
+            }
+        }
+        score
+    }
+}
+
+/// Running totals of how a packed model's parses differ from those of the
+/// model it was converted from
+#[derive(Debug, Clone, Default)]
+pub struct Agreement {
+    queries: usize,
+    changed_queries: usize,
+    tokens: usize,
+    disagreements: usize,
+    drift: f64,
+    abs_drift: f64,
+    max_abs_drift: f64,
+    changed: Worst<Change>,
+}
+
+/// A query the packed model parses differently
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub tokens: Vec<String>,
+    pub original: Vec<String>,
+    pub packed: Vec<String>,
+    pub original_score: f64,
+    pub packed_score: f64,
+}
+
+/// Results of an agreement check, ready to be printed or serialized
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub queries: usize,
+    pub tokens: usize,
+    /// Share of tokens labeled differently
+    pub disagreement_rate: f64,
+    /// Share of queries with any token labeled differently
+    pub changed_query_rate: f64,
+    /// Mean of the packed model's best score minus the original's
+    pub mean_score_drift: f64,
+    pub mean_abs_score_drift: f64,
+    pub max_abs_score_drift: f64,
+    /// Changed queries, largest score drift first
+    pub changed: Vec<Change>,
+}
+
+impl Agreement {
+    /// Start a check that lists up to `max_changed` of the changed queries
+    pub fn new(max_changed: usize) -> Self {
+        Self {
+            changed: Worst::new(max_changed),
+            ..Default::default()
+        }
+    }
+
+    /// Add a query's tokens with each model's best labels and their score
+    pub fn add<S: AsRef<str>>(
+        &mut self,
+        tokens: &[S],
+        original: &[&str],
+        original_score: f64,
+        packed: &[&str],
+        packed_score: f64,
+    ) {
+        assert_eq!(original.len(), packed.len());
+        let disagreements = original
+            .iter()
+            .zip(packed)
+            .filter(|(original, packed)| original != packed)
+            .count();
+        let drift = packed_score - original_score;
+        self.queries += 1;
+        self.changed_queries += (disagreements > 0) as usize;
+        self.tokens += original.len();
+        self.disagreements += disagreements;
+        self.drift += drift;
+        self.abs_drift += drift.abs();
+        self.max_abs_drift = self.max_abs_drift.max(drift.abs());
+
+        if disagreements > 0 && self.changed.max() > 0 {
+            self.changed.push(Change {
+                tokens: tokens
+                    .iter()
+                    .map(|token| token.as_ref().to_string())
+                    .collect(),
+                original: original.iter().map(|label| label.to_string()).collect(),
+                packed: packed.iter().map(|label| label.to_string()).collect(),
+                original_score,
+                packed_score,
+            });
+        }
+    }
+
+    /// Combine with a check of other queries
+    pub fn merge(&mut self, other: Agreement) {
+        self.queries += other.queries;
+        self.changed_queries += other.changed_queries;
+        self.tokens += other.tokens;
+        self.disagreements += other.disagreements;
+        self.drift += other.drift;
+        self.abs_drift += other.abs_drift;
+        self.max_abs_drift = self.max_abs_drift.max(other.max_abs_drift);
+        self.changed.merge(other.changed);
+    }
+
+    pub fn report(&self) -> Report {
+        let queries = self.queries.max(1) as f64;
+        Report {
+            queries: self.queries,
+            tokens: self.tokens,
+            disagreement_rate: ratio(self.disagreements, self.tokens),
+            changed_query_rate: ratio(self.changed_queries, self.queries),
+            mean_score_drift: self.drift / queries,
+            mean_abs_score_drift: self.abs_drift / queries,
+            max_abs_score_drift: self.max_abs_drift,
+            changed: self.changed.to_vec(),
+        }
+    }
+}
+
+impl Change {
+    fn abs_drift(&self) -> f64 {
+        (self.packed_score - self.original_score).abs()
+    }
+}
+
+impl Rank for Change {
+    /// Changes with a larger score drift are worse
+    fn rank(&self, other: &Self) -> Ordering {
+        other
+            .abs_drift()
+            .partial_cmp(&self.abs_drift())
+            .unwrap()
+            .then(self.tokens.cmp(&other.tokens))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} queries, {} tokens: {:.4} of tokens and {:.4} of queries labeled differently",
+            self.queries, self.tokens, self.disagreement_rate, self.changed_query_rate
+        )?;
+        writeln!(
+            f,
+            "Score drift: mean {:.5}, mean absolute {:.5}, max absolute {:.5}",
+            self.mean_score_drift, self.mean_abs_score_drift, self.max_abs_score_drift
+        )?;
+        writeln!(f, "\nChanged queries:")?;
+        for change in &self.changed {
+            writeln!(
+                f,
+                "{} (score {:.3} -> {:.3})",
+                change.tokens.join(" "),
+                change.original_score,
+                change.packed_score
+            )?;
+            writeln!(f, "    original: {}", change.original.join(" "))?;
+            writeln!(f, "    packed:   {}", change.packed.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scorer() {
+        let weights = Weights {
+            labels: vec!["road".to_string(), "locality".to_string()],
+            transitions: vec![(0, 1, 0.5), (1, 0, -1.0)],
+            state_features: vec![
+                ("main".to_string(), 0, 2.0),
+                ("main".to_string(), 1, 0.25),
+                ("seattle".to_string(), 1, 3.0),
+            ],
+        };
+        let scorer = Scorer::new(&weights);
+        let items = [
+            vec![("main", 1.0)],
+            vec![("seattle", 0.5), ("nowhere", 1.0)],
+        ];
+        assert_eq!(scorer.score(&items, &["road", "locality"]), 2.0 + 0.5 + 1.5);
+        assert_eq!(scorer.score(&items, &["locality", "road"]), 0.25 - 1.0);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut agreement = Agreement::new(1);
+        agreement.add(
+            &["main", "st"],
+            &["road", "road"],
+            4.0,
+            &["road", "road"],
+            3.5,
+        );
+        let mut other = Agreement::new(1);
+        other.add(&["seattle"], &["locality"], 2.0, &["road"], 2.5);
+        other.add(
+            &["5", "main"],
+            &["house_number", "road"],
+            1.0,
+            &["house_number", "locality"],
+            2.0,
+        );
+        agreement.merge(other);
+        let report = agreement.report();
+
+        assert_eq!(report.queries, 3);
+        assert_eq!(report.tokens, 5);
+        assert!((report.disagreement_rate - 2.0 / 5.0).abs() < 1e-9);
+        assert!((report.changed_query_rate - 2.0 / 3.0).abs() < 1e-9);
+        assert!((report.mean_score_drift - 1.0 / 3.0).abs() < 1e-9);
+        assert!((report.mean_abs_score_drift - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(report.max_abs_score_drift, 1.0);
+        assert_eq!(report.changed.len(), 1);
+        assert_eq!(report.changed[0].tokens, vec!["5", "main"]);
+        assert!(report.to_string().contains("Changed queries"));
+    }
+}
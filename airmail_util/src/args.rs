@@ -0,0 +1,154 @@
+use std::error::Error;
+
+use airmail_lib::{
+    augment::AugmentConfig,
+    extractor::{AttributeValues, ExtractorConfig},
+    quantize::{Quantization, CURVE_BITS},
+    schema::LabelSchema,
+    template::FeatureTemplates,
+};
+use serde::de::DeserializeOwned;
+
+/// Command line flags for the feature extractor. Training and conversion must
+/// be given the same ones.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ExtractorArgs {
+    /// Keep the word pieces of numeric tokens rather than giving them only
+    /// their digit count.
+    #[clap(long)]
+    pub numeric_pieces: bool,
+    /// How each piece attribute is valued: `binary`, `piece-length`,
+    /// `segmentation-probability` or `id` (legacy, by vocabulary id, as models
+    /// packed before the scheme was recorded were trained).
+    #[clap(long, value_parser, default_value = "binary")]
+    pub values: AttributeValues,
+    /// Take pieces from this many of the best segmentations of each word.
+    #[clap(long, value_parser, default_value_t = 1)]
+    pub max_segmentations: u32,
+    /// Offsets of neighboring tokens whose attributes are copied onto each
+    /// token, such as `-2,-1,1,2`.
+    #[clap(
+        long,
+        value_parser,
+        use_value_delimiter = true,
+        allow_hyphen_values = true
+    )]
+    pub neighbors: Vec<i32>,
+    /// Mark the first and last token of each query.
+    #[clap(long)]
+    pub boundaries: bool,
+    /// Split each token's relative position into this many buckets.
+    #[clap(long, value_parser, default_value_t = 0)]
+    pub position_buckets: u32,
+    /// Give each query length up to this many tokens its own attribute.
+    #[clap(long, value_parser, default_value_t = 0)]
+    pub max_token_count: u32,
+}
+
+impl ExtractorArgs {
+    pub fn extractor(&self) -> ExtractorConfig {
+        ExtractorConfig {
+            digits_only: !self.numeric_pieces,
+            values: self.values,
+            max_segmentations: self.max_segmentations,
+            templates: FeatureTemplates {
+                neighbors: self.neighbors.clone(),
+                boundaries: self.boundaries,
+                position_buckets: self.position_buckets,
+                max_token_count: self.max_token_count,
+            },
+        }
+    }
+}
+
+/// Command line flag for the label schema. Training and conversion must be
+/// given the same one.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct SchemaArgs {
+    /// Label schema file, in TOML, or JSON if its name ends in `.json`.
+    /// Defaults to `labels.toml`.
+    #[clap(long, value_parser)]
+    pub labels: Option<String>,
+}
+
+impl SchemaArgs {
+    pub fn label_schema(&self) -> Result<LabelSchema, Box<dyn Error>> {
+        read_config(self.labels.as_deref(), include_str!("../labels.toml"))
+    }
+}
+
+/// Command line flags for augmenting training examples
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct AugmentArgs {
+    /// Augmentation settings file, in TOML, or JSON if its name ends in
+    /// `.json`. Defaults to `augment.toml`.
+    #[clap(long, value_parser)]
+    pub augment: Option<String>,
+}
+
+impl AugmentArgs {
+    pub fn augment_config(&self) -> Result<AugmentConfig, Box<dyn Error>> {
+        read_config(self.augment.as_deref(), include_str!("../augment.toml"))
+    }
+}
+
+/// Read a settings file in TOML, or JSON if its name ends in `.json`, or the
+/// TOML `default` if there's no file
+fn read_config<T: DeserializeOwned>(
+    path: Option<&str>,
+    default: &str,
+) -> Result<T, Box<dyn Error>> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(toml::from_str(default)?),
+    };
+    let contents = std::fs::read_to_string(path)?;
+    if path.ends_with(".json") {
+        Ok(serde_json::from_str(&contents)?)
+    } else {
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+/// Command line flags for quantizing state feature weights when packing a
+/// model
+#[derive(clap::Args, Debug, Clone)]
+pub struct QuantizationArgs {
+    /// How state feature weights are quantized: `curve`, `kmeans` (one
+    /// learned codebook) or `per-label` (a learned codebook per label).
+    #[clap(long, value_parser, default_value = "curve")]
+    pub quantization: Quantization,
+    /// Bits per quantized weight, from 1 to 16, or from 2 for `curve`. Fewer
+    /// bits leave more for
+    /// labels and can keep state features in two bytes.
+    #[clap(long, value_parser, default_value_t = CURVE_BITS)]
+    pub weight_bits: u32,
+}
+
+impl QuantizationArgs {
+    pub fn quantization(&self) -> Quantization {
+        self.quantization.with_bits(self.weight_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_label_schema() {
+        let schema = SchemaArgs::default().label_schema().unwrap();
+        assert_eq!(schema.target("city"), Some("locality"));
+        assert_eq!(schema.target("level"), None);
+        assert_eq!(schema.target("road"), Some("road"));
+        assert_eq!(schema.keep_rate("postcode"), 0.2);
+        assert_eq!(schema.coarse("road"), "street");
+        assert!(schema.skip_examples.contains(&"po_box".to_string()));
+    }
+
+    #[test]
+    fn test_default_augment_config() {
+        let config = AugmentArgs::default().augment_config().unwrap();
+        assert_eq!(config, AugmentConfig::default());
+    }
+}
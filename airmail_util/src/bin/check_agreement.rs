@@ -0,0 +1,151 @@
+use std::fs::File;
+
+use airmail_lib::{
+    extractor::{AttributeKey, FeatureExtractor},
+    model::Model as PackedModel,
+    tagger::Attribute,
+    tokenizer::Tokenizer,
+    training_source::SourceFormat,
+};
+use airmail_util::{
+    agreement::{Agreement, Scorer},
+    model::Model,
+};
+use clap::Parser;
+use fst::raw::Fst;
+use rayon::prelude::{ParallelBridge, ParallelIterator};
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// The crfsuite model the packed model was converted from.
+    #[clap(long, value_parser)]
+    model: String,
+    /// The packed model to check.
+    #[clap(long, value_parser)]
+    packed: String,
+    /// The vocabulary both models were trained with.
+    #[clap(long, value_parser)]
+    vocab: String,
+    /// The files of queries to tag with both models, in `--format`, which may
+    /// be globs, `-` for stdin or compressed.
+    #[clap(long, value_parser, required = true, multiple_values = true)]
+    tsv: Vec<String>,
+    /// The format of the query files: `libpostal`, `openaddresses`, `conll`
+    /// or `jsonl`.
+    #[clap(long, value_parser, default_value = "libpostal")]
+    format: SourceFormat,
+    /// Tag at most this many queries of the query files.
+    #[clap(long, value_parser)]
+    limit: Option<usize>,
+    /// Number of the changed queries to list.
+    #[clap(long, value_parser, default_value_t = 20)]
+    changed: usize,
+    /// Also write the report as JSON to this file.
+    #[clap(long, value_parser)]
+    json: Option<String>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let model_data = std::fs::read(&args.model).unwrap();
+    let original = crfs::Model::new(&model_data).unwrap();
+    let weights = Model::new(&model_data).unwrap().weights().unwrap();
+    let scorer = Scorer::new(&weights);
+    let packed = PackedModel::from_bytes(std::fs::read(&args.packed).unwrap()).unwrap();
+    for warning in packed.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+
+    // Both models are given the attribute names they were trained with, so
+    // the only difference between them is the packing.
+    let extractor = packed.extractor();
+    let vocab = Fst::new(std::fs::read(&args.vocab).unwrap()).unwrap();
+    let tokenizer =
+        Tokenizer::new(&vocab).with_max_segmentations(extractor.max_segmentations as usize);
+
+    let tsv_stream = args.format.open(&args.tsv).unwrap();
+    let agreement = tsv_stream
+        .take(args.limit.unwrap_or(usize::MAX))
+        .par_bridge()
+        .fold(
+            || {
+                (
+                    original.tagger().unwrap(),
+                    packed.tagger().unwrap(),
+                    Agreement::new(args.changed),
+                )
+            },
+            |(mut original_tagger, mut packed_tagger, mut agreement), tsv_item| {
+                let mut words = vec![];
+                let mut tokens = vec![];
+                for token in tsv_item.tokens.iter() {
+                    if token.label == "FSEP" {
+                        continue;
+                    }
+                    if let Some(labeled) =
+                        extractor.labeled_token(&tokenizer, &token.transliterated)
+                    {
+                        words.push(token.transliterated.as_str());
+                        tokens.push(labeled);
+                    }
+                }
+                if tokens.is_empty() {
+                    return (original_tagger, packed_tagger, agreement);
+                }
+                let mut items: Vec<Vec<(String, f64)>> = vec![vec![]; tokens.len()];
+                extractor.extract(&tokens, &mut |position, attribute, value| {
+                    let name = match attribute {
+                        AttributeKey::Id(id) => tokenizer.stringify_feature(id),
+                        AttributeKey::Template(attribute) => attribute.to_string(),
+                    };
+                    items[position].push((name, value));
+                });
+
+                let original_xseq: Vec<Vec<crfs::Attribute>> = items
+                    .iter()
+                    .map(|item| {
+                        item.iter()
+                            .map(|(name, value)| crfs::Attribute::new(name, *value))
+                            .collect()
+                    })
+                    .collect();
+                let original_labels = original_tagger.tag(&original_xseq).unwrap();
+                let original_score = scorer.score(&items, &original_labels);
+
+                let packed_xseq: Vec<Vec<Attribute>> = items
+                    .iter()
+                    .map(|item| {
+                        item.iter()
+                            .map(|(name, value)| Attribute::new(name, *value))
+                            .collect()
+                    })
+                    .collect();
+                let best = packed_tagger.tag_nbest(&packed_xseq, 1).unwrap().remove(0);
+
+                agreement.add(
+                    &words,
+                    &original_labels,
+                    original_score,
+                    &best.labels,
+                    best.score,
+                );
+                (original_tagger, packed_tagger, agreement)
+            },
+        )
+        .map(|(_, _, agreement)| agreement)
+        .reduce(
+            || Agreement::new(args.changed),
+            |mut a, b| {
+                a.merge(b);
+                a
+            },
+        );
+
+    let report = agreement.report();
+    print!("{}", report);
+    if let Some(path) = &args.json {
+        serde_json::to_writer_pretty(File::create(path).unwrap(), &report).unwrap();
+    }
+}
@@ -1,7 +1,24 @@
-use std::{fs::File, io::Read};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use airmail_util::model::Model;
+use airmail_lib::{
+    extractor::{AttributeKey, FeatureExtractor},
+    packed::ModelMetadata,
+    quantize::decision_changes,
+    tokenizer::Tokenizer,
+    training_source::SourceFormat,
+};
+use airmail_util::{
+    args::{ExtractorArgs, QuantizationArgs, SchemaArgs},
+    eval::evaluate_tsv,
+    model::{Model, Weights},
+    prune::{prune, vocab_predictivity, Budget},
+};
 use clap::Parser;
+use fst::raw::Fst;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -12,6 +29,48 @@ struct Args {
     /// The model file to dump.
     #[clap(long, value_parser)]
     packed: String,
+    /// The vocabulary the model was trained with, whose piece
+    /// log-probabilities are carried into the packed model.
+    #[clap(long, value_parser)]
+    vocab: String,
+    /// Description of the corpus the model was trained on, recorded in the
+    /// packed model.
+    #[clap(long, value_parser)]
+    corpus: Option<String>,
+    /// A tsv file of queries on which to count the Viterbi decisions that
+    /// quantization changes.
+    #[clap(long, value_parser)]
+    sample_tsv: Option<String>,
+    /// Number of queries of the sample tsv file to decode.
+    #[clap(long, value_parser, default_value_t = 1000)]
+    sample_size: usize,
+    /// The format of the sample and eval tsv files: `libpostal`,
+    /// `openaddresses`, `conll` or `jsonl`.
+    #[clap(long, value_parser, default_value = "libpostal")]
+    format: SourceFormat,
+    /// Drop state features whose absolute weight is below this.
+    #[clap(long, value_parser, default_value_t = 0.0)]
+    min_weight: f64,
+    /// Keep only this many of the most predictive attributes.
+    #[clap(long, value_parser)]
+    max_attrs: Option<usize>,
+    /// Drop the least predictive attributes until the packed model is at
+    /// most this many bytes.
+    #[clap(long, value_parser)]
+    max_bytes: Option<usize>,
+    /// A held-out tsv file on which to measure the accuracy that pruning
+    /// costs.
+    #[clap(long, value_parser)]
+    eval_tsv: Option<String>,
+    /// Evaluate on at most this many lines of the eval tsv file.
+    #[clap(long, value_parser)]
+    eval_limit: Option<usize>,
+    #[clap(flatten)]
+    extractor: ExtractorArgs,
+    #[clap(flatten)]
+    schema: SchemaArgs,
+    #[clap(flatten)]
+    quantization: QuantizationArgs,
 }
 
 fn main() {
@@ -26,6 +85,124 @@ fn main() {
         .read_to_end(&mut model_data)
         .unwrap();
     let model = Model::new(&model_data).unwrap();
-    let mut packed_file = File::create(args.packed).unwrap();
-    model.dump(&mut packed_file).unwrap();
+    let vocab_data = std::fs::read(&args.vocab).unwrap();
+    let pieces = Tokenizer::new(&Fst::new(vocab_data.as_slice()).unwrap());
+    let quantization = args.quantization.quantization();
+    let weights = model.weights().unwrap();
+    let extractor = args.extractor.extractor();
+    let label_schema = args.schema.label_schema().unwrap();
+    let metadata = ModelMetadata {
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|elapsed| elapsed.as_secs()),
+        corpus: args.corpus.clone(),
+        vocab_checksum: Some(crc32fast::hash(&vocab_data)),
+    };
+    let pack = |weights: &Weights| {
+        let mut packed = weights
+            .pack(model.header(), Some(&pieces), quantization)
+            .unwrap();
+        packed.extractor = extractor.clone();
+        packed.label_schema = label_schema.clone();
+        packed.metadata = metadata.clone();
+        packed
+    };
+
+    let budget = Budget {
+        min_weight: args.min_weight,
+        max_attrs: args.max_attrs,
+        max_bytes: args.max_bytes,
+    };
+    let pruned = prune(&weights, &budget, |weights| {
+        pack(weights)
+            .to_bytes()
+            .map(|bytes| bytes.len())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    })
+    .unwrap();
+    let packed = pack(&pruned);
+    let bytes = packed.to_bytes().unwrap();
+    println!(
+        "Packed model is {} bytes, with {} of {} attributes and {} of {} state features",
+        bytes.len(),
+        packed.header.num_attrs,
+        vocab_predictivity(&weights).len(),
+        pruned.state_features.len(),
+        weights.state_features.len()
+    );
+    println!(
+        "Quantized with {} to {} bit weights: {}",
+        quantization,
+        quantization.bits(),
+        quantization.reconstruction_error(&packed.codebook, &pruned.state_features)
+    );
+
+    if let Some(sample_tsv) = &args.sample_tsv {
+        // Decode the sample with the attribute names the model was trained
+        // on, so that the unquantized weights can be looked up by name.
+        let tokenizer = pieces
+            .clone()
+            .with_max_segmentations(extractor.max_segmentations as usize);
+        let sequences: Vec<Vec<Vec<(String, f64)>>> = args
+            .format
+            .open(&[sample_tsv])
+            .unwrap()
+            .take(args.sample_size)
+            .map(|tsv_item| {
+                let tokens: Vec<_> = tsv_item
+                    .tokens
+                    .iter()
+                    .filter(|token| token.label != "FSEP")
+                    .filter_map(|token| extractor.labeled_token(&tokenizer, &token.transliterated))
+                    .collect();
+                let mut items = vec![vec![]; tokens.len()];
+                extractor.extract(&tokens, &mut |position, attribute, value| {
+                    let name = match attribute {
+                        AttributeKey::Id(id) => tokenizer.stringify_feature(id),
+                        AttributeKey::Template(attribute) => attribute.to_string(),
+                    };
+                    items[position].push((name, value));
+                });
+                items
+            })
+            .collect();
+        // Changes are counted against the unpruned weights, so they include
+        // those from pruning.
+        let quantized = airmail_lib::model::Model::from_bytes(bytes.clone()).unwrap();
+        let changes = decision_changes(
+            &quantized,
+            &weights.transitions,
+            &weights.state_features,
+            &sequences,
+        )
+        .unwrap();
+        println!("On the sample, {}", changes);
+    }
+
+    if let Some(eval_tsv) = &args.eval_tsv {
+        let accuracy = |bytes: Vec<u8>| {
+            let model = airmail_lib::model::Model::from_bytes(bytes).unwrap();
+            evaluate_tsv(&model, args.format, &[eval_tsv], args.eval_limit, 0)
+                .unwrap()
+                .report()
+                .overall
+        };
+        let pruned_accuracy = accuracy(bytes.clone());
+        println!("Packed model: {}", pruned_accuracy);
+        if pruned.state_features.len() < weights.state_features.len() {
+            let unpruned_accuracy = accuracy(pack(&weights).to_bytes().unwrap());
+            println!("Unpruned:     {}", unpruned_accuracy);
+            println!(
+                "Pruning costs {:.4} token accuracy and {:.4} exact match",
+                unpruned_accuracy.token_accuracy - pruned_accuracy.token_accuracy,
+                unpruned_accuracy.exact_match - pruned_accuracy.exact_match
+            );
+        }
+    }
+
+    File::create(args.packed)
+        .unwrap()
+        .write_all(&bytes)
+        .unwrap();
 }
@@ -0,0 +1,76 @@
+use std::{fs::File, process::exit};
+
+use airmail_lib::{model::Model, training_source::SourceFormat};
+use airmail_util::eval::evaluate_tsv;
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// The packed model file to evaluate.
+    #[clap(long, value_parser)]
+    model: String,
+    /// The held-out files to evaluate on, in `--format`, which may be globs,
+    /// `-` for stdin or compressed.
+    #[clap(long, value_parser, required = true, multiple_values = true)]
+    tsv: Vec<String>,
+    /// The format of the held-out files: `libpostal`, `openaddresses`,
+    /// `conll` or `jsonl`.
+    #[clap(long, value_parser, default_value = "libpostal")]
+    format: SourceFormat,
+    /// Evaluate on at most this many queries of the held-out files.
+    #[clap(long, value_parser)]
+    limit: Option<usize>,
+    /// Number of the worst examples to report.
+    #[clap(long, value_parser, default_value_t = 20)]
+    worst: usize,
+    /// Also write the report as JSON to this file.
+    #[clap(long, value_parser)]
+    json: Option<String>,
+    /// Exit with an error if token accuracy is below this.
+    #[clap(long, value_parser)]
+    min_token_accuracy: Option<f64>,
+    /// Exit with an error if the share of exactly matched queries is below
+    /// this.
+    #[clap(long, value_parser)]
+    min_exact_match: Option<f64>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let model = Model::from_bytes(std::fs::read(&args.model).unwrap()).unwrap();
+    for warning in model.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    let evaluation = evaluate_tsv(&model, args.format, &args.tsv, args.limit, args.worst).unwrap();
+
+    let report = evaluation.report();
+    print!("{}", report);
+    if let Some(path) = &args.json {
+        serde_json::to_writer_pretty(File::create(path).unwrap(), &report).unwrap();
+    }
+
+    let mut failed = false;
+    if let Some(min) = args.min_token_accuracy {
+        if report.overall.token_accuracy < min {
+            eprintln!(
+                "Token accuracy {:.4} is below {:.4}",
+                report.overall.token_accuracy, min
+            );
+            failed = true;
+        }
+    }
+    if let Some(min) = args.min_exact_match {
+        if report.overall.exact_match < min {
+            eprintln!(
+                "Exact match {:.4} is below {:.4}",
+                report.overall.exact_match, min
+            );
+            failed = true;
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
@@ -1,162 +1,139 @@
-use std::{
-    collections::{HashMap, HashSet},
-    error::Error,
-    fs::File,
-    sync::mpsc::{sync_channel, Receiver, SyncSender},
-    time::Duration,
-    usize,
-};
+use std::{error::Error, fs::File, sync::mpsc::sync_channel};
 
-use airmail_lib::lp_file_stream::{LpEntryToken, LpFileStream};
-use clap::{command, Arg, ArgAction};
-use fst::SetBuilder;
+use airmail_lib::{tokenizer::VocabOutput, training_source::SourceFormat};
+use airmail_util::vocab::{merge, train_label, Summary, VocabAlgorithm, VocabConfig, WordCounts};
+use clap::Parser;
+use fst::MapBuilder;
 use rayon::prelude::{IntoParallelRefIterator, ParallelBridge, ParallelIterator};
-use tokenizers::{
-    models::unigram::{Unigram, UnigramTrainer},
-    normalizers::{Sequence, Strip, NFC},
-    processors::byte_level::ByteLevel,
-    TokenizerBuilder,
-};
 
-struct ClampedExpandedVocab<'a> {
-    _token_frequencies: &'a HashMap<(String, String), usize>,
-    label: String,
-    iter: std::collections::hash_map::Iter<'a, (String, String), usize>,
-    token: (String, String),
-    remaining_count: usize,
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// The files to learn the vocabulary from, in `--format`, which may be
+    /// globs, `-` for stdin or compressed.
+    #[clap(long, value_parser, required = true, multiple_values = true)]
+    files: Vec<String>,
+    /// The format of the files: `libpostal`, `openaddresses`, `conll` or
+    /// `jsonl`.
+    #[clap(long, value_parser, default_value = "libpostal")]
+    format: SourceFormat,
+    /// The vocabulary file to write.
+    #[clap(long, value_parser)]
+    out: String,
+    /// A TOML file of vocabulary settings. The flags below override it.
+    #[clap(long, value_parser)]
+    config: Option<String>,
+    /// Number of pieces to learn for each label.
+    #[clap(long, value_parser)]
+    vocab_size: Option<u32>,
+    /// Number of pieces to learn for one label, as `label=size`. May be
+    /// repeated.
+    #[clap(long = "label-vocab-size", value_parser = parse_label_size)]
+    label_vocab_sizes: Vec<(String, u32)>,
+    /// Leave out words seen fewer times than this.
+    #[clap(long, value_parser)]
+    min_frequency: Option<usize>,
+    /// Count each word at most this many times.
+    #[clap(long, value_parser)]
+    clamp: Option<usize>,
+    /// How pieces are learned: `unigram`, `bpe` or `wordpiece`.
+    #[clap(long, value_parser)]
+    algorithm: Option<VocabAlgorithm>,
+    /// Also write the summary as JSON to this file.
+    #[clap(long, value_parser)]
+    report: Option<String>,
 }
 
-impl<'a> ClampedExpandedVocab<'a> {
-    fn new(
-        token_frequencies: &'a HashMap<(String, String), usize>,
-        label: &str,
-    ) -> ClampedExpandedVocab<'a> {
-        ClampedExpandedVocab {
-            _token_frequencies: token_frequencies,
-            label: label.to_string(),
-            iter: token_frequencies.iter(),
-            token: ("".to_string(), "".to_string()),
-            remaining_count: 0,
-        }
-    }
-}
-
-impl<'a> Iterator for ClampedExpandedVocab<'a> {
-    type Item = String;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.remaining_count == 0 {
-            loop {
-                if let Some((new_token, new_frequency)) = self.iter.next() {
-                    if new_token.1 != self.label {
-                        continue;
-                    }
-                    self.token = new_token.clone();
-                    self.remaining_count = usize::min(*new_frequency, 100);
-                    return Some(self.token.clone().0);
-                } else {
-                    return None;
-                }
-            }
-        } else {
-            self.remaining_count -= 1;
-            Some(self.token.clone().0)
-        }
-    }
+fn parse_label_size(arg: &str) -> Result<(String, u32), String> {
+    let (label, size) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected `label=size`, got `{}`", arg))?;
+    let size = size
+        .parse()
+        .map_err(|err| format!("invalid size in `{}`: {}", arg, err))?;
+    Ok((label.to_string(), size))
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let matches = command!()
-        .arg(
-            Arg::new("f")
-                .long("files")
-                .required(true)
-                .takes_value(true)
-                .multiple_values(true),
-        )
-        .arg(Arg::new("o").long("out").takes_value(true).required(true))
-        .arg(Arg::new("p").long("pretty").action(ArgAction::SetTrue))
-        .get_matches();
-
-    if let Some(files) = matches.get_many::<String>("f") {
-        let out_file = matches.get_one::<String>("o").unwrap();
-        rayon::scope(|scope| {
-            let (sender, reciever): (SyncSender<LpEntryToken>, Receiver<LpEntryToken>) =
-                sync_channel(10000);
-            scope.spawn(move |_| {
-                let mut token_frequencies = HashMap::new();
-                let mut all_labels = HashSet::new();
-                while let Ok(token) = reciever.recv_timeout(Duration::from_secs(1)) {
-                    all_labels.insert(token.label.clone());
-                    let token_with_suffix = format!("{}Ė", token.transliterated);
-                    if let Some(prev) =
-                        token_frequencies.get(&(token_with_suffix.clone(), token.label.clone()))
-                    {
-                        token_frequencies
-                            .insert((token_with_suffix.clone(), token.label.clone()), prev + 1);
-                    } else {
-                        token_frequencies
-                            .insert((token_with_suffix.clone(), token.label.clone()), 1);
-                    }
-                }
-                let mut vocab: HashSet<String> = HashSet::new();
-                let per_label_vocab: Vec<HashSet<String>> = all_labels
-                    .par_iter()
-                    .map(|label| {
-                        println!("Generating vocab for label `{}`", label);
-                        let data = ClampedExpandedVocab::new(&token_frequencies, &label);
-                        let vocab_size: u32 = 200000;
-                        let mut trainer = UnigramTrainer::builder()
-                            .show_progress(true)
-                            .vocab_size(vocab_size)
-                            .build()
-                            .unwrap();
+    let args = Args::parse();
 
-                        let mut tokenizer = TokenizerBuilder::new()
-                            .with_model(Unigram::default())
-                            .with_normalizer(Some(Sequence::new(vec![
-                                Strip::new(true, true).into(),
-                                NFC.into(),
-                            ])))
-                            .with_pre_tokenizer(Some(ByteLevel::default()))
-                            .with_post_processor(Some(ByteLevel::default()))
-                            .with_decoder(Some(ByteLevel::default()))
-                            .build()
-                            .unwrap();
-                        let tokenizer_impl = tokenizer.train(&mut trainer, data).unwrap();
-                        let vocab_map = tokenizer_impl.get_vocab(false);
-                        let vocab: HashSet<String> = vocab_map.into_keys().collect();
-                        println!("{} vocab contains {} items", label, vocab.len());
-                        vocab
-                    })
-                    .collect();
+    let mut config: VocabConfig = match &args.config {
+        Some(path) => toml::from_str(&std::fs::read_to_string(path)?)?,
+        None => VocabConfig::default(),
+    };
+    if let Some(vocab_size) = args.vocab_size {
+        config.vocab_size = vocab_size;
+    }
+    config
+        .label_vocab_sizes
+        .extend(args.label_vocab_sizes.iter().cloned());
+    if let Some(min_frequency) = args.min_frequency {
+        config.min_frequency = min_frequency;
+    }
+    if let Some(clamp) = args.clamp {
+        config.clamp = clamp;
+    }
+    if let Some(algorithm) = args.algorithm {
+        config.algorithm = algorithm;
+    }
 
-                for label_vocab in per_label_vocab {
-                    vocab.extend(label_vocab);
+    // The reader sends every labeled word and hangs up at the end of the
+    // input, which ends the loop counting them.
+    let stream = args.format.open(&args.files)?;
+    let read_stats = stream.stats();
+    let mut counts = WordCounts::new();
+    std::thread::scope(|scope| {
+        let (sender, receiver) = sync_channel::<(String, String)>(10000);
+        scope.spawn(move || {
+            stream.par_bridge().for_each_with(sender, |sender, entry| {
+                for token in entry.tokens {
+                    sender.send((token.label, token.transliterated)).unwrap();
                 }
-
-                let mut vocab: Vec<&String> = vocab.iter().collect();
-                vocab.sort();
-                let mut builder = SetBuilder::new(File::create(out_file).unwrap()).unwrap();
-
-                vocab
-                    .iter()
-                    .for_each(|key| builder.insert(key.clone()).unwrap());
-                builder.finish().unwrap();
             });
-            for f in files {
-                println!("Processing file: {}", f);
-                let stream = LpFileStream::new(f.to_string()).unwrap();
-                stream.par_bridge().for_each(|entry| {
-                    for token in &entry.tokens {
-                        sender.clone().send(token.clone()).unwrap();
-                    }
-                });
-            }
         });
+        for (label, word) in receiver {
+            counts.add(&label, &word);
+        }
+    });
+    print!("{}", read_stats.counts());
+
+    let labels: Vec<&str> = counts.labels().collect();
+    let vocabs: Vec<_> = labels
+        .par_iter()
+        .map(|label| {
+            println!(
+                "Generating vocab for label `{}` ({} pieces)",
+                label,
+                config.vocab_size(label)
+            );
+            train_label(&counts, label, &config)
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|err| err as Box<dyn Error>)?;
+
+    let vocab = merge(&vocabs);
+    let mut builder = MapBuilder::new(File::create(&args.out)?)?;
+    for (id, (piece, score)) in vocab.iter().enumerate() {
+        let output = VocabOutput {
+            id: id as u32,
+            log_prob: Some(*score),
+        };
+        builder.insert(piece, output.encode())?;
+    }
+    builder.finish()?;
 
-        Ok(())
-    } else {
-        Ok(())
+    let summary = Summary {
+        config,
+        pieces: vocab.len(),
+        labels: labels
+            .iter()
+            .zip(&vocabs)
+            .map(|(label, vocab)| (label.to_string(), vocab.summary))
+            .collect(),
+    };
+    print!("{}", summary);
+    if let Some(path) = &args.report {
+        serde_json::to_writer_pretty(File::create(path)?, &summary)?;
     }
+    Ok(())
 }
@@ -1,10 +1,4 @@
-use std::fs::File;
-
-use airmail_lib::{
-    model::{Model, PackedModel},
-    tagger::Attribute,
-    tokenizer::Tokenizer,
-};
+use airmail_lib::{extractor::FeatureExtractor, model::Model, tokenizer::Tokenizer};
 use clap::Parser;
 
 #[derive(Parser, Debug)]
@@ -16,51 +10,68 @@ struct Args {
     /// The address string to parse.
     #[clap(long, value_parser)]
     str: String,
+    /// Also print this many of the best alternative parses.
+    #[clap(long, value_parser)]
+    nbest: Option<usize>,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let packed: PackedModel = bincode2::deserialize_from(File::open(args.model).unwrap()).unwrap();
-    let model = Model::from(packed);
+    let model = Model::from_bytes(std::fs::read(args.model).unwrap()).unwrap();
+    for warning in model.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
     let mut tagger = model.tagger().unwrap();
 
-    let tokenizer = Tokenizer::new(&model.get_vocab());
-    let features = tokenizer.tokenize(&args.str);
-    for word_features in &features {
-        let mut word_feature_strings: Vec<String> = word_features
+    let tokenizer = Tokenizer::for_model(&model);
+    let extractor = model.extractor();
+    let tokens = extractor.tokenize(&tokenizer, &args.str);
+    let attributes = extractor.attributes(&tokenizer, &tokens);
+    for token_attributes in &attributes {
+        let mut word_feature_strings: Vec<String> = token_attributes
             .iter()
-            .map(|id| tokenizer.stringify_feature(*id))
+            .map(|attribute| {
+                model
+                    .to_attr(attribute.id)
+                    .unwrap_or_else(|| tokenizer.stringify_feature(attribute.id))
+            })
             .collect();
         word_feature_strings.sort_by(|a, b| b.len().partial_cmp(&a.len()).unwrap());
         println!("{:?}", word_feature_strings);
     }
 
-    let attributes: Vec<Vec<Attribute>> = features
-        .iter()
-        .map(|token_attribs| {
-            let attrib_vec: Vec<Attribute> = token_attribs
-                .iter()
-                .map(|id| Attribute::new(tokenizer.stringify_feature(*id), *id as f64))
-                .collect();
-            attrib_vec
-        })
-        .collect();
+    tagger.set_attributes(&attributes).unwrap();
+    if let Some(k) = args.nbest {
+        for hypothesis in tagger.nbest(k).unwrap() {
+            println!(
+                "Alternative: {:?} (score = {}, p = {:?})",
+                hypothesis.labels, hypothesis.score, hypothesis.probability
+            );
+        }
+    }
 
-    let labels = tagger.tag(&attributes).unwrap();
-    println!("Parsed as: {:?}", labels);
-    // for i in 0..attributes.len() {
-    //     let mut other_probs: Vec<(&String, f64)> = labels
-    //         .iter()
-    //         .filter(|label| label != &&viterbi[i])
-    //         .map(|other_label| (other_label, tagger.marginal(other_label, i as i32).unwrap()))
-    //         .collect();
-    //     other_probs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    //     let mut alternate_viterbi = viterbi.clone();
-    //     alternate_viterbi[i] = other_probs[0].0.to_string();
-    //     println!(
-    //         "{} chance of it being {:?}",
-    //         other_probs[0].1, alternate_viterbi
-    //     );
-    // }
+    let labels = tagger.viterbi().unwrap();
+    let probability = tagger.probability(&labels).unwrap();
+    let labels: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+    println!("Parsed as: {:?} (p = {})", labels, probability);
+    println!("Log partition: {}", tagger.lognorm().unwrap());
+    if labels.is_empty() {
+        return;
+    }
+    let marginals = tagger.marginals().unwrap();
+    for (i, token_marginals) in marginals.iter().enumerate() {
+        let mut other_probs: Vec<(&str, f64)> = token_marginals
+            .iter()
+            .enumerate()
+            .map(|(label_id, prob)| (model.to_label(label_id as u32).unwrap(), *prob))
+            .filter(|(label, _prob)| label != &labels[i])
+            .collect();
+        other_probs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        if let Some((other_label, other_prob)) = other_probs.first() {
+            let mut alternate_viterbi = labels.clone();
+            alternate_viterbi[i] = other_label.to_string();
+            println!("{} chance of it being {:?}", other_prob, alternate_viterbi);
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use std::{fs::File, io::Read};
 
-use airmail_lib::tokenizer::Tokenizer;
+use airmail_lib::tokenizer::{Tokenizer, VocabOutput};
 use clap::Parser;
 use fst::{raw::Fst, Streamer};
 
@@ -13,6 +13,9 @@ struct Args {
     /// The string to tokenize.
     #[clap(long, value_parser)]
     str: Option<String>,
+    /// Print this many of the most likely segmentations of each word.
+    #[clap(long, value_parser)]
+    nbest: Option<usize>,
 }
 
 fn main() {
@@ -27,6 +30,18 @@ fn main() {
     if let Some(string) = args.str {
         let fst = Fst::new(vocab_data).unwrap();
         let tokenizer = Tokenizer::new(&fst);
+        if let Some(k) = args.nbest {
+            for token in tokenizer.tokenize_with_spans(&string) {
+                for segmentation in tokenizer.segment(&token.text, k) {
+                    let pieces: Vec<&str> = segmentation
+                        .pieces
+                        .iter()
+                        .map(|piece| &token.text[piece.span.clone()])
+                        .collect();
+                    println!("{:?} (log p = {})", pieces, segmentation.log_prob);
+                }
+            }
+        }
         let features = tokenizer.tokenize(&string);
         for word_features in features {
             let mut word_feature_strings: Vec<String> = word_features
@@ -39,9 +54,9 @@ fn main() {
     } else {
         let vocab = Fst::new(vocab_data).unwrap();
         let mut vocab_stream = vocab.stream();
-        while let Some((key, _out)) = vocab_stream.next() {
+        while let Some((key, out)) = vocab_stream.next() {
             if let Ok(s) = String::from_utf8(key.to_vec()) {
-                println!("Key: {}", s);
+                println!("Key: {} {:?}", s, VocabOutput::decode(out.value()));
             } else {
                 println!("Error");
             }
@@ -1,179 +1,301 @@
 use std::{
+    collections::BTreeMap,
     fs::File,
     io::Read,
-    sync::mpsc::{sync_channel, Receiver, SyncSender},
-    time::Duration,
+    sync::mpsc::sync_channel,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use airmail_lib::{
-    lp_file_stream::{LpEntryToken, LpFileStream},
+    augment::example_rng,
+    extractor::{AttributeKey, FeatureExtractor},
+    lp_file_stream::LpEntryToken,
+    packed::ModelMetadata,
     tokenizer::Tokenizer,
+    trainer::{train, Algorithm, TrainedModel, TrainerConfig, TrainingData},
+    training_source::SourceFormat,
+};
+use airmail_util::{
+    args::{AugmentArgs, ExtractorArgs, QuantizationArgs, SchemaArgs},
+    manifest::RunManifest,
 };
 use clap::Parser;
-use crfsuite::{Algorithm, Attribute, GraphicalModel, Trainer};
 use fst::raw::Fst;
-use rand::{thread_rng, Rng};
 use rayon::prelude::{ParallelBridge, ParallelIterator};
 
+/// Attributes of each token of an example and the label it's trained as
+type Example = (Vec<Vec<(String, f64)>>, Vec<String>);
+
+/// Trains a CRF model. Given the same seed and inputs, and `SOURCE_DATE_EPOCH`
+/// for the creation time, it writes the same model however many threads it
+/// runs on. What went into the model is written next to it in
+/// `<packed>.manifest.json`.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     /// The vocabulary file to use.
     #[clap(long, value_parser)]
     vocab: String,
-    /// The tsv training file to use.
+    /// The training files to use, in `--format`, which may be globs, `-` for
+    /// stdin or compressed. They're read in order as one stream.
+    #[clap(long, value_parser, required = true, multiple_values = true)]
+    tsv: Vec<String>,
+    /// The format of the training files: `libpostal`, `openaddresses`,
+    /// `conll` or `jsonl`.
+    #[clap(long, value_parser, default_value = "libpostal")]
+    format: SourceFormat,
+    /// The packed model file to write.
+    #[clap(long, value_parser)]
+    packed: String,
+    /// Description of the corpus, recorded in the packed model.
     #[clap(long, value_parser)]
-    tsv: String,
-    /// The string to tokenize.
+    corpus: Option<String>,
+    /// Train on at most this many entries of the training files. Every
+    /// example is held in memory while training, so this bounds memory use.
+    #[clap(long, value_parser, default_value_t = 50000000)]
+    limit: usize,
+    /// Training algorithm: `lbfgs`, `ap` (averaged perceptron) or `pa`
+    /// (passive-aggressive).
+    #[clap(long, value_parser, default_value = "lbfgs")]
+    algorithm: String,
+    /// L1 penalty for `lbfgs`.
+    #[clap(long, value_parser, default_value_t = 0.0)]
+    c1: f64,
+    /// L2 penalty for `lbfgs`.
+    #[clap(long, value_parser, default_value_t = 1.0)]
+    c2: f64,
+    /// Maximum number of iterations for `lbfgs`.
+    #[clap(long, value_parser, default_value_t = 100)]
+    max_iterations: usize,
+    /// Number of passes over the training data for `ap` and `pa`.
+    #[clap(long, value_parser, default_value_t = 10)]
+    epochs: usize,
+    /// Aggressiveness of `pa` updates.
+    #[clap(long, value_parser, default_value_t = 1.0)]
+    pa_c: f64,
+    /// Hold out every this many examples to measure accuracy during training.
+    /// Zero trains on everything.
+    #[clap(long, value_parser, default_value_t = 100)]
+    holdout_every: usize,
+    /// Stop after this many iterations without an improvement in held-out
+    /// accuracy.
     #[clap(long, value_parser)]
-    str: Option<String>,
+    patience: Option<usize>,
+    /// Write the model so far to `<packed>.checkpoint` every this many
+    /// iterations.
+    #[clap(long, value_parser)]
+    checkpoint_every: Option<usize>,
+    /// Seed for augmentation, label sampling and the order in which `ap` and
+    /// `pa` visit the examples. Holdout assignment and the order of examples
+    /// follow the corpus.
+    #[clap(long, value_parser, default_value_t = 0)]
+    seed: u64,
+    #[clap(flatten)]
+    extractor: ExtractorArgs,
+    #[clap(flatten)]
+    schema: SchemaArgs,
+    #[clap(flatten)]
+    augment: AugmentArgs,
+    #[clap(flatten)]
+    quantization: QuantizationArgs,
 }
 
 fn main() {
     let args = Args::parse();
     let mut vocab_data = vec![];
-    File::open(args.vocab)
+    File::open(&args.vocab)
         .unwrap()
         .read_to_end(&mut vocab_data)
         .unwrap();
+    let vocab_checksum = crc32fast::hash(&vocab_data);
     let fst = Fst::new(vocab_data).unwrap();
-    let tokenizer = Tokenizer::new(&fst);
-
-    let tsv_stream = LpFileStream::new(args.tsv).unwrap();
+    let extractor = args.extractor.extractor();
+    let label_schema = args.schema.label_schema().unwrap();
+    let augment_config = args.augment.augment_config().unwrap();
+    let augmenter = augment_config.augmenter();
+    let tokenizer =
+        Tokenizer::new(&fst).with_max_segmentations(extractor.max_segmentations as usize);
+    let algorithm = match args.algorithm.as_str() {
+        "lbfgs" => Algorithm::lbfgs(args.c1, args.c2, args.max_iterations),
+        "ap" => Algorithm::AveragedPerceptron {
+            epochs: args.epochs,
+        },
+        "pa" => Algorithm::PassiveAggressive {
+            epochs: args.epochs,
+            c: args.pa_c,
+        },
+        other => panic!("unknown algorithm {}", other),
+    };
 
-    rayon::scope(|scope| {
-        let (sender, reciever): (
-            SyncSender<(Box<Vec<Vec<(String, f64)>>>, Vec<String>)>,
-            Receiver<(Box<Vec<Vec<(String, f64)>>>, Vec<String>)>,
-        ) = sync_channel(1000000);
-        scope.spawn(move |_| {
-            let mut trainer = Trainer::new(true);
-            trainer
-                .select(Algorithm::PA, GraphicalModel::CRF1D)
-                .unwrap();
-            let mut counter = 0usize;
-            while let Ok((attribute_vec_per_token, target_per_token)) =
-                reciever.recv_timeout(Duration::from_secs(3))
-            {
-                let group = if counter % 100 == 0 { 1 } else { 0 };
-                let actual_attributes: Vec<Vec<Attribute>> = attribute_vec_per_token
-                    .iter()
-                    .map(|token_attribs| {
-                        let attrib_vec: Vec<Attribute> = token_attribs
-                            .iter()
-                            .map(|(name, id)| Attribute::new(name, *id))
-                            .collect();
-                        attrib_vec
-                    })
-                    .collect();
-                trainer
-                    .append(&actual_attributes, &target_per_token, group)
-                    .unwrap();
-                counter += 1;
-                if counter % 100000 == 0 {
-                    println!("Processed {} lines", counter);
-                }
-            }
-            println!("training");
-            trainer.train("model.crf", 1).unwrap();
-            println!("done training");
-            panic!();
-        });
-        tsv_stream.take(50000000).par_bridge().for_each(|tsv_item| {
-            let mut attribute_vec_per_token = vec![];
-            let mut target_per_token = vec![];
-            let all_tokens: Vec<&LpEntryToken> = tsv_item
-                .tokens
-                .iter()
-                .filter(|token| token.label != "FSEP")
-                .collect();
-            let tokens_len = all_tokens.len();
-            let tokens_to_use = if tokens_len < 2 || thread_rng().gen::<f64>() < 0.2 {
-                all_tokens
-            } else {
-                all_tokens
-                    .into_iter()
-                    .take(thread_rng().gen_range(1..tokens_len))
-                    .collect()
-            };
-            let use_postcode = thread_rng().gen::<f64>() < 0.2;
-            if tokens_to_use
-                .iter()
-                .filter(|token| token.label == "po_box")
-                .count()
-                > 0
-            {
-                // PO boxes aren't useful for geocoding.
-                return;
-            }
-            for token in tokens_to_use {
-                if token.label == "FSEP" {
-                    continue;
-                }
-                let actual_label = match token.label.as_str() {
-                    // The nuance associated with these different labels is too much for our parser to deal with given the size budget.
-                    "level" => continue,
-                    "entrance" => continue,
-                    "staircase" => continue,
-                    // Postal codes aren't nearly as important as other aspects of geocoding, but don't completely ignore them.
-                    "postcode" => {
-                        if use_postcode {
-                            "postcode"
-                        } else {
-                            continue;
+    let tsv_stream = args.format.open(&args.tsv).unwrap();
+    let read_stats = tsv_stream.stats();
+    let mut data = TrainingData::new();
+    let mut holdout_examples = 0;
+    std::thread::scope(|scope| {
+        let (sender, receiver) = sync_channel::<(usize, Option<Example>)>(1000000);
+        scope.spawn(|| {
+            tsv_stream
+                .take(args.limit)
+                .enumerate()
+                .par_bridge()
+                .for_each_with(sender, |sender, (index, tsv_item)| {
+                    let mut rng = example_rng(args.seed, index as u64);
+                    let mut tokens = vec![];
+                    let mut target_per_token = vec![];
+                    let mut tokens_to_use: Vec<LpEntryToken> = tsv_item
+                        .tokens
+                        .into_iter()
+                        .filter(|token| token.label != "FSEP")
+                        .collect();
+                    augmenter.augment(&mut tokens_to_use, &mut rng);
+                    let labels: Vec<&str> = tokens_to_use
+                        .iter()
+                        .map(|token| token.label.as_str())
+                        .collect();
+                    let targets = match label_schema.label_example(&labels, &mut rng) {
+                        Some(targets) => targets,
+                        None => {
+                            sender
+                                .send((index, None))
+                                .expect("training example receiver hung up");
+                            return;
                         }
+                    };
+                    for (token, target) in tokens_to_use.iter().zip(targets) {
+                        let target = match target {
+                            Some(target) => target,
+                            None => continue,
+                        };
+                        match extractor.labeled_token(&tokenizer, &token.transliterated) {
+                            Some(token) => tokens.push(token),
+                            None => continue,
+                        }
+                        target_per_token.push(target.to_string());
                     }
-                    // This is something we can deal with downstream.
-                    "city" => "locality",
-                    "suburb" => "neighborhood",
-                    "city_district" => "neighborhood",
-                    // Similarly, a structured search system can easily deal with this ambiguity.
-                    "state_district" => "region",
-                    "state" => "region",
-                    "island" => "region",
-                    x => x,
-                };
-                let features = {
-                    let split: Vec<&str> = token.transliterated.split_ascii_whitespace().collect();
-                    let cat_token = split.join("");
-                    let all_features = tokenizer.tokenize(&cat_token);
-                    if all_features.len() != 1 {
-                        continue;
-                    }
-                    all_features[0].clone()
+                    let mut attribute_vec_per_token: Vec<Vec<(String, f64)>> =
+                        vec![vec![]; tokens.len()];
+                    extractor.extract(&tokens, &mut |position, attribute, value| {
+                        let name = match attribute {
+                            AttributeKey::Id(id) => tokenizer.stringify_feature(id),
+                            AttributeKey::Template(attribute) => attribute.to_string(),
+                        };
+                        attribute_vec_per_token[position].push((name, value));
+                    });
+                    sender
+                        .send((index, Some((attribute_vec_per_token, target_per_token))))
+                        .expect("training example receiver hung up");
+                });
+        });
+        // Examples arrive in whatever order the threads finish them, so
+        // they're put back in corpus order before holdout assignment and
+        // training see them.
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        let mut counter = 0usize;
+        for (index, example) in receiver {
+            pending.insert(index, example);
+            while let Some(example) = pending.remove(&next) {
+                next += 1;
+                let (attribute_vec_per_token, target_per_token) = match example {
+                    Some(example) => example,
+                    None => continue,
                 };
-                let mut attributes: Vec<(String, f64)> = features
-                    .iter()
-                    .map(|id| {
-                        let name = tokenizer.stringify_feature(*id);
-                        (name.clone(), *id as f64)
-                    })
-                    .collect();
-                if attributes
-                    .iter()
-                    .filter(|(attr, _id)| attr.starts_with("D:"))
-                    .count()
-                    > 0
+                let group = if args.holdout_every > 0 && counter.is_multiple_of(args.holdout_every)
                 {
-                    attributes = attributes
-                        .into_iter()
-                        .filter(|(attr, _id)| attr.starts_with("D:"))
-                        .collect();
-                }
-                attribute_vec_per_token.push(attributes);
-                target_per_token.push(actual_label.to_string());
-            }
-            match sender
-                .clone()
-                .send((Box::new(attribute_vec_per_token), target_per_token))
-            {
-                Ok(_) => {}
-                Err(_) => {
-                    println!("Failed to send");
-                    panic!();
+                    holdout_examples += 1;
+                    1
+                } else {
+                    0
+                };
+                data.push(&attribute_vec_per_token, &target_per_token, group)
+                    .unwrap();
+                counter += 1;
+                if counter.is_multiple_of(100000) {
+                    println!("Processed {} lines", counter);
                 }
             }
-        });
-        drop(sender);
+        }
     });
+    print!("{}", read_stats.counts());
+    print!("{}", augmenter.stats());
+
+    let config = TrainerConfig {
+        algorithm,
+        holdout: (args.holdout_every > 0).then_some(1),
+        patience: args.patience,
+        checkpoint_every: args.checkpoint_every,
+        seed: args.seed,
+    };
+    // SOURCE_DATE_EPOCH stands in for the current time when rebuilding a
+    // model.
+    let created_at = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => Some(epoch.parse().expect("SOURCE_DATE_EPOCH isn't a number")),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|elapsed| elapsed.as_secs()),
+    };
+    let write = |model: &TrainedModel, path: &str| {
+        let mut packed = model
+            .pack(Some(&tokenizer), args.quantization.quantization())
+            .unwrap();
+        packed.extractor = extractor.clone();
+        packed.label_schema = label_schema.clone();
+        packed.metadata = ModelMetadata {
+            created_at,
+            corpus: args.corpus.clone(),
+            vocab_checksum: Some(vocab_checksum),
+        };
+        let mut bytes = vec![];
+        packed.write(&mut bytes).unwrap();
+        std::fs::write(path, &bytes).unwrap();
+        bytes
+    };
+    let checkpoint_path = format!("{}.checkpoint", args.packed);
+
+    println!("training on {} examples", data.len());
+    let mut iterations = 0;
+    let model = train(&data, &config, |progress| {
+        iterations = progress.iteration;
+        match progress.holdout_accuracy {
+            Some(accuracy) => println!(
+                "iteration {}: loss {:.3}, held-out accuracy {:.4}",
+                progress.iteration, progress.loss, accuracy
+            ),
+            None => println!(
+                "iteration {}: loss {:.3}",
+                progress.iteration, progress.loss
+            ),
+        }
+        if let Some(checkpoint) = progress.checkpoint {
+            write(checkpoint, &checkpoint_path);
+        }
+    });
+    println!(
+        "done training, {} active features",
+        model.num_active_features()
+    );
+    let bytes = write(&model, &args.packed);
+
+    let manifest = RunManifest {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        arguments: std::env::args().collect(),
+        seed: args.seed,
+        created_at,
+        vocab: args.vocab.clone(),
+        vocab_crc32: vocab_checksum,
+        extractor: extractor.clone(),
+        label_schema: label_schema.clone(),
+        augment: augment_config,
+        read: read_stats.counts(),
+        augmentation: augmenter.stats(),
+        training_examples: data.len() - holdout_examples,
+        holdout_examples,
+        iterations,
+        active_features: model.num_active_features(),
+        model_bytes: bytes.len(),
+        model_crc32: crc32fast::hash(&bytes),
+    };
+    manifest.write(&args.packed).unwrap();
+    println!("wrote {}", RunManifest::path(&args.packed));
 }
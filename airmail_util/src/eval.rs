@@ -0,0 +1,450 @@
+use std::{cmp::Ordering, collections::BTreeMap, error::Error, fmt};
+
+use airmail_lib::{
+    extractor::FeatureExtractor, model::Model, tokenizer::Tokenizer, training_source::SourceFormat,
+};
+use rayon::prelude::{ParallelBridge, ParallelIterator};
+use serde::Serialize;
+
+use crate::stats::{ratio, Rank, Worst};
+
+/// Running totals of a model's predictions on labeled queries
+#[derive(Debug, Clone, Default)]
+pub struct Evaluation {
+    overall: Counts,
+    components: BTreeMap<String, ComponentCounts>,
+    confusion: BTreeMap<String, BTreeMap<String, usize>>,
+    by_lang: BTreeMap<String, Counts>,
+    by_country: BTreeMap<String, Counts>,
+    worst: Worst<Example>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    queries: usize,
+    exact: usize,
+    tokens: usize,
+    correct: usize,
+}
+
+impl Counts {
+    fn add(&mut self, tokens: usize, correct: usize) {
+        self.queries += 1;
+        self.exact += (correct == tokens) as usize;
+        self.tokens += tokens;
+        self.correct += correct;
+    }
+
+    fn merge(&mut self, other: &Counts) {
+        self.queries += other.queries;
+        self.exact += other.exact;
+        self.tokens += other.tokens;
+        self.correct += other.correct;
+    }
+
+    fn summary(&self) -> Summary {
+        Summary {
+            queries: self.queries,
+            tokens: self.tokens,
+            token_accuracy: ratio(self.correct, self.tokens),
+            exact_match: ratio(self.exact, self.queries),
+        }
+    }
+}
+
+/// Components are runs of consecutive tokens with the same label. A predicted
+/// component is correct if a gold component has the same label and tokens.
+#[derive(Debug, Clone, Copy, Default)]
+struct ComponentCounts {
+    true_positives: usize,
+    predicted: usize,
+    gold: usize,
+}
+
+/// A query the model got wrong
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Example {
+    pub tokens: Vec<String>,
+    pub gold: Vec<String>,
+    pub predicted: Vec<String>,
+    pub errors: usize,
+}
+
+impl Example {
+    fn error_rate(&self) -> f64 {
+        ratio(self.errors, self.tokens.len())
+    }
+}
+
+impl Rank for Example {
+    /// Examples with a larger share of errors, then more errors, are worse
+    fn rank(&self, other: &Self) -> Ordering {
+        other
+            .error_rate()
+            .partial_cmp(&self.error_rate())
+            .unwrap()
+            .then(other.errors.cmp(&self.errors))
+            .then(self.tokens.cmp(&other.tokens))
+    }
+}
+
+/// Accuracy over a set of queries
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Summary {
+    pub queries: usize,
+    pub tokens: usize,
+    /// Share of tokens labeled correctly
+    pub token_accuracy: f64,
+    /// Share of queries with every token labeled correctly
+    pub exact_match: f64,
+}
+
+/// Component-level metrics for one label
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LabelMetrics {
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+    /// Number of gold components with the label
+    pub support: usize,
+}
+
+/// Results of an evaluation, ready to be printed or serialized
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub overall: Summary,
+    pub labels: BTreeMap<String, LabelMetrics>,
+    /// Number of tokens with each gold label, then predicted label
+    pub confusion: BTreeMap<String, BTreeMap<String, usize>>,
+    pub by_lang: BTreeMap<String, Summary>,
+    pub by_country: BTreeMap<String, Summary>,
+    /// The queries with the largest share of mislabeled tokens
+    pub worst: Vec<Example>,
+}
+
+impl Evaluation {
+    /// Start an evaluation that keeps up to `max_worst` of the worst examples
+    pub fn new(max_worst: usize) -> Self {
+        Self {
+            worst: Worst::new(max_worst),
+            ..Default::default()
+        }
+    }
+
+    /// Add a query's tokens with their gold and predicted labels
+    pub fn add<S: AsRef<str>>(
+        &mut self,
+        lang: &str,
+        country: &str,
+        tokens: &[S],
+        gold: &[&str],
+        predicted: &[&str],
+    ) {
+        assert_eq!(gold.len(), predicted.len());
+        let correct = gold
+            .iter()
+            .zip(predicted)
+            .filter(|(gold, predicted)| gold == predicted)
+            .count();
+        self.overall.add(gold.len(), correct);
+        self.by_lang
+            .entry(lang.to_string())
+            .or_default()
+            .add(gold.len(), correct);
+        self.by_country
+            .entry(country.to_string())
+            .or_default()
+            .add(gold.len(), correct);
+        for (gold, predicted) in gold.iter().zip(predicted) {
+            *self
+                .confusion
+                .entry(gold.to_string())
+                .or_default()
+                .entry(predicted.to_string())
+                .or_default() += 1;
+        }
+
+        let gold_components = components(gold);
+        let predicted_components = components(predicted);
+        for (_, _, label) in gold_components.iter() {
+            self.components.entry(label.to_string()).or_default().gold += 1;
+        }
+        for component in predicted_components.iter() {
+            let counts = self.components.entry(component.2.to_string()).or_default();
+            counts.predicted += 1;
+            if gold_components.contains(component) {
+                counts.true_positives += 1;
+            }
+        }
+
+        if correct < gold.len() && self.worst.max() > 0 {
+            self.worst.push(Example {
+                tokens: tokens
+                    .iter()
+                    .map(|token| token.as_ref().to_string())
+                    .collect(),
+                gold: gold.iter().map(|label| label.to_string()).collect(),
+                predicted: predicted.iter().map(|label| label.to_string()).collect(),
+                errors: gold.len() - correct,
+            });
+        }
+    }
+
+    /// Combine with an evaluation of other queries
+    pub fn merge(&mut self, other: Evaluation) {
+        self.overall.merge(&other.overall);
+        for (label, counts) in other.components {
+            let entry = self.components.entry(label).or_default();
+            entry.true_positives += counts.true_positives;
+            entry.predicted += counts.predicted;
+            entry.gold += counts.gold;
+        }
+        for (gold, row) in other.confusion {
+            let entry = self.confusion.entry(gold).or_default();
+            for (predicted, count) in row {
+                *entry.entry(predicted).or_default() += count;
+            }
+        }
+        for (lang, counts) in other.by_lang {
+            self.by_lang.entry(lang).or_default().merge(&counts);
+        }
+        for (country, counts) in other.by_country {
+            self.by_country.entry(country).or_default().merge(&counts);
+        }
+        self.worst.merge(other.worst);
+    }
+
+    pub fn report(&self) -> Report {
+        let labels = self
+            .components
+            .iter()
+            .map(|(label, counts)| {
+                let precision = ratio(counts.true_positives, counts.predicted);
+                let recall = ratio(counts.true_positives, counts.gold);
+                let f1 = if precision + recall > 0.0 {
+                    2.0 * precision * recall / (precision + recall)
+                } else {
+                    0.0
+                };
+                let metrics = LabelMetrics {
+                    precision,
+                    recall,
+                    f1,
+                    support: counts.gold,
+                };
+                (label.clone(), metrics)
+            })
+            .collect();
+        let summaries = |counts: &BTreeMap<String, Counts>| {
+            counts
+                .iter()
+                .map(|(key, counts)| (key.clone(), counts.summary()))
+                .collect()
+        };
+        Report {
+            overall: self.overall.summary(),
+            labels,
+            confusion: self.confusion.clone(),
+            by_lang: summaries(&self.by_lang),
+            by_country: summaries(&self.by_country),
+            worst: self.worst.to_vec(),
+        }
+    }
+}
+
+/// Evaluate `model` on up to `limit` queries of files in `format`, given as
+/// for `SourceFormat::open`, keeping up to `max_worst` of the worst examples
+///
+/// Gold labels follow the schema the model was trained with, without the
+/// sampling applied in training.
+pub fn evaluate_tsv<S: AsRef<str>>(
+    model: &Model,
+    format: SourceFormat,
+    tsv: &[S],
+    limit: Option<usize>,
+    max_worst: usize,
+) -> Result<Evaluation, Box<dyn Error>> {
+    let tokenizer = Tokenizer::for_model(model);
+    let extractor = model.extractor();
+    let label_schema = model.label_schema();
+
+    let tsv_stream = format.open(tsv)?;
+    let evaluation = tsv_stream
+        .take(limit.unwrap_or(usize::MAX))
+        .par_bridge()
+        .fold(
+            || (model.tagger().unwrap(), Evaluation::new(max_worst)),
+            |(mut tagger, mut evaluation), tsv_item| {
+                if tsv_item
+                    .tokens
+                    .iter()
+                    .any(|token| label_schema.skip_examples.contains(&token.label))
+                {
+                    return (tagger, evaluation);
+                }
+                let mut words = vec![];
+                let mut tokens = vec![];
+                let mut gold = vec![];
+                for token in tsv_item.tokens.iter() {
+                    if token.label == "FSEP" {
+                        continue;
+                    }
+                    let target = match label_schema.target(&token.label) {
+                        Some(target) => target,
+                        None => continue,
+                    };
+                    match extractor.labeled_token(&tokenizer, &token.transliterated) {
+                        Some(token) => tokens.push(token),
+                        None => continue,
+                    }
+                    words.push(token.transliterated.as_str());
+                    gold.push(target);
+                }
+                if tokens.is_empty() {
+                    return (tagger, evaluation);
+                }
+                let attributes = extractor.attributes(&tokenizer, &tokens);
+                tagger.set_attributes(&attributes).unwrap();
+                let predicted = tagger.viterbi().unwrap();
+                evaluation.add(&tsv_item.lang, &tsv_item.country, &words, &gold, &predicted);
+                (tagger, evaluation)
+            },
+        )
+        .map(|(_, evaluation)| evaluation)
+        .reduce(
+            || Evaluation::new(max_worst),
+            |mut a, b| {
+                a.merge(b);
+                a
+            },
+        );
+    Ok(evaluation)
+}
+
+/// Start, end and label of each run of tokens with the same label
+fn components<'a>(labels: &[&'a str]) -> Vec<(usize, usize, &'a str)> {
+    let mut components: Vec<(usize, usize, &str)> = Vec::new();
+    for (position, label) in labels.iter().enumerate() {
+        match components.last_mut() {
+            Some(last) if last.2 == *label => last.1 = position + 1,
+            _ => components.push((position, position + 1, label)),
+        }
+    }
+    components
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>8} queries {:>9} tokens  token accuracy {:.4}  exact match {:.4}",
+            self.queries, self.tokens, self.token_accuracy, self.exact_match
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Overall: {}", self.overall)?;
+
+        writeln!(f, "\nComponents:")?;
+        writeln!(
+            f,
+            "{:<20} {:>9} {:>9} {:>9} {:>9}",
+            "label", "precision", "recall", "f1", "support"
+        )?;
+        for (label, metrics) in &self.labels {
+            writeln!(
+                f,
+                "{:<20} {:>9.4} {:>9.4} {:>9.4} {:>9}",
+                label, metrics.precision, metrics.recall, metrics.f1, metrics.support
+            )?;
+        }
+
+        writeln!(f, "\nConfusion (rows are gold labels, columns predicted):")?;
+        let mut columns: Vec<&String> =
+            self.confusion.values().flat_map(|row| row.keys()).collect();
+        columns.sort();
+        columns.dedup();
+        write!(f, "{:<20}", "")?;
+        for column in &columns {
+            write!(f, " {:>12.12}", column)?;
+        }
+        writeln!(f)?;
+        for (gold, row) in &self.confusion {
+            write!(f, "{:<20}", gold)?;
+            for column in &columns {
+                write!(f, " {:>12}", row.get(*column).copied().unwrap_or(0))?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "\nBy language:")?;
+        for (lang, summary) in &self.by_lang {
+            writeln!(f, "{:<8} {}", lang, summary)?;
+        }
+        writeln!(f, "\nBy country:")?;
+        for (country, summary) in &self.by_country {
+            writeln!(f, "{:<8} {}", country, summary)?;
+        }
+
+        writeln!(f, "\nWorst examples:")?;
+        for example in &self.worst {
+            writeln!(f, "{} errors: {}", example.errors, example.tokens.join(" "))?;
+            writeln!(f, "    gold:      {}", example.gold.join(" "))?;
+            writeln!(f, "    predicted: {}", example.predicted.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut evaluation = Evaluation::new(1);
+        evaluation.add(
+            "en",
+            "us",
+            &["123", "main", "st"],
+            &["house_number", "road", "road"],
+            &["house_number", "road", "road"],
+        );
+        let mut other = Evaluation::new(1);
+        other.add(
+            "de",
+            "de",
+            &["hauptstr", "5", "berlin"],
+            &["road", "house_number", "locality"],
+            &["road", "house_number", "road"],
+        );
+        other.add("de", "at", &["wien"], &["locality"], &["road"]);
+        evaluation.merge(other);
+        let report = evaluation.report();
+
+        assert_eq!(report.overall.queries, 3);
+        assert_eq!(report.overall.tokens, 7);
+        assert!((report.overall.token_accuracy - 5.0 / 7.0).abs() < 1e-9);
+        assert!((report.overall.exact_match - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(report.by_lang["de"].queries, 2);
+        assert_eq!(report.by_country["at"].token_accuracy, 0.0);
+
+        // "main st" is one road component. Of the four predicted road
+        // components only two are right, and no locality was found.
+        let road = report.labels["road"];
+        assert!((road.precision - 2.0 / 4.0).abs() < 1e-9);
+        assert_eq!(road.recall, 1.0);
+        assert_eq!(road.support, 2);
+        assert_eq!(report.labels["locality"].recall, 0.0);
+        assert_eq!(report.confusion["locality"]["road"], 2);
+
+        assert_eq!(report.worst.len(), 1);
+        assert_eq!(report.worst[0].tokens, vec!["wien"]);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["overall"]["queries"], 3);
+        assert!(report.to_string().contains("Worst examples"));
+    }
+}
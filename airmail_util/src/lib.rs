@@ -1,2 +1,9 @@
+pub mod agreement;
+pub mod args;
+pub mod eval;
 pub mod feature;
+pub mod manifest;
 pub mod model;
+pub mod prune;
+pub mod stats;
+pub mod vocab;
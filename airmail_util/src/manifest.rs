@@ -0,0 +1,50 @@
+use std::{error::Error, fs::File};
+
+use airmail_lib::{
+    augment::{AugmentConfig, AugmentStats},
+    extractor::ExtractorConfig,
+    lp_file_stream::ReadCounts,
+    schema::LabelSchema,
+};
+use serde::Serialize;
+
+/// Everything that went into a training run, written next to the model it
+/// made. Running the same command on inputs with the same checksums, with
+/// `SOURCE_DATE_EPOCH` set to `created_at`, rebuilds the model bit for bit on
+/// any machine.
+#[derive(Debug, Serialize)]
+pub struct RunManifest {
+    /// Version of airmail_util that trained the model
+    pub version: String,
+    /// The command line, starting with the program
+    pub arguments: Vec<String>,
+    pub seed: u64,
+    /// Creation time recorded in the model, in seconds since the epoch
+    pub created_at: Option<u64>,
+    pub vocab: String,
+    pub vocab_crc32: u32,
+    pub extractor: ExtractorConfig,
+    pub label_schema: LabelSchema,
+    pub augment: AugmentConfig,
+    /// What was read from the training inputs, with a checksum of each
+    pub read: ReadCounts,
+    pub augmentation: AugmentStats,
+    pub training_examples: usize,
+    pub holdout_examples: usize,
+    pub iterations: usize,
+    pub active_features: usize,
+    pub model_bytes: usize,
+    pub model_crc32: u32,
+}
+
+impl RunManifest {
+    /// Where the manifest of the packed model at `packed` goes
+    pub fn path(packed: &str) -> String {
+        format!("{}.manifest.json", packed)
+    }
+
+    pub fn write(&self, packed: &str) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer_pretty(File::create(Self::path(packed))?, self)?;
+        Ok(())
+    }
+}
@@ -1,17 +1,11 @@
-use std::{
-    collections::HashMap,
-    convert::TryInto,
-    f64::consts::PI,
-    fmt,
-    io::{self, Write},
-    mem,
-};
+use std::{convert::TryInto, fmt, io, mem};
 
 use crate::feature::{Feature, FeatureRefs};
-use airmail_lib::model::{Header, PackedModel};
+use airmail_lib::{
+    model::Header, packed::PackedModel, quantize::Quantization, tokenizer::Tokenizer,
+};
 use bstr::ByteSlice;
 use cqdb::CQDB;
-use fst::{Set, SetBuilder};
 
 const CHUNK_SIZE: usize = 12;
 const FEATURE_SIZE: usize = 20;
@@ -67,6 +61,38 @@ pub struct Model<'a> {
     attrs: CQDB<'a>,
 }
 
+/// Labels and unquantized weights of a model
+#[derive(Debug, Clone)]
+pub struct Weights {
+    /// Label strings, in label id order
+    pub labels: Vec<String>,
+    /// `(source, target, weight)` transition features
+    pub transitions: Vec<(u32, u32, f64)>,
+    /// `(attribute, target, weight)` state features
+    pub state_features: Vec<(String, u32, f64)>,
+}
+
+impl Weights {
+    /// Quantize the weights into a packed model, as `Model::pack` does for
+    /// the model with `header` they came from
+    pub fn pack(
+        &self,
+        header: &Header,
+        pieces: Option<&Tokenizer>,
+        quantization: Quantization,
+    ) -> io::Result<PackedModel> {
+        PackedModel::pack(
+            header.clone(),
+            self.labels.clone(),
+            &self.transitions,
+            &self.state_features,
+            pieces,
+            quantization,
+        )
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
 impl<'a> fmt::Debug for Model<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Model")
@@ -213,145 +239,58 @@ impl<'a> Model<'a> {
         })
     }
 
-    /// Print the model in human-readable format
-    pub fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        // Dump the file header
+    /// The model's labels and weights, before they're quantized
+    pub fn weights(&self) -> io::Result<Weights> {
         let header = &self.header;
+
         // Dump the transition features
+        let mut transitions = vec![];
         for i in 0..header.num_labels {
             let label_refs = self.label_ref(i)?;
             for j in 0..label_refs.num_features {
                 let fid = label_refs.get(j as usize)?;
                 let feature = self.feature(fid)?;
-                let _source = self.to_label(feature.source).unwrap();
-                let _target = self.to_label(feature.target).unwrap();
+                transitions.push((feature.source, feature.target, feature.weight));
             }
         }
 
-        // Dump the state transition features
-        let mut vocab_predictivity = HashMap::new();
+        // Dump the state features
+        let mut state_features = vec![];
         for i in 0..header.num_attrs {
             let attr_refs = self.attr_ref(i)?;
             for j in 0..attr_refs.num_features {
                 let fid = attr_refs.get(j as usize)?;
                 let feature = self.feature(fid)?;
                 let attr = self.to_attr(feature.source).unwrap();
-                if let Some(&mut predictivity) = vocab_predictivity.get_mut(attr) {
-                    vocab_predictivity.insert(attr, f64::abs(feature.weight) + predictivity);
-                } else {
-                    vocab_predictivity.insert(attr, f64::abs(feature.weight));
-                }
+                state_features.push((attr.to_string(), feature.target, feature.weight));
             }
         }
 
-        let (important_attrs, attr_indices): (Vec<String>, HashMap<String, usize>) = {
-            let mut tmp_attrs: Vec<String> = vocab_predictivity
-                .iter()
-                .map(|(attr, _predictivity)| attr.to_string())
-                .collect();
-            tmp_attrs.sort();
-            (
-                tmp_attrs.clone(),
-                tmp_attrs
-                    .into_iter()
-                    .enumerate()
-                    .map(|(idx, key)| (key, idx))
-                    .collect(),
-            )
-        };
-
         let label_current_order: Vec<String> = self
             .labels
             .iter()
             .map(|label| label.unwrap().1.to_str().unwrap().to_string())
             .collect();
 
-        let vocab_fst_data = {
-            let mut fst_builder = SetBuilder::memory();
-            important_attrs
-                .iter()
-                .for_each(|attr| fst_builder.insert(attr.to_string()).unwrap());
-            fst_builder.into_inner().unwrap()
-        };
-        let vocab_fst = Set::new(vocab_fst_data.clone()).unwrap();
-
-        let lex_sorted_weights = {
-            let mut all_weights = vec![];
-
-            for i in 0..header.num_attrs {
-                let attr_refs = self.attr_ref(i)?;
-                for j in 0..attr_refs.num_features {
-                    let fid = attr_refs.get(j as usize)?;
-                    let feature = self.feature(fid)?;
-                    let attr = self.to_attr(feature.source).unwrap();
-                    all_weights.push((attr.to_string(), feature.target, feature.weight));
-                }
-            }
-            all_weights.sort_by(|a, b| {
-                a.0.partial_cmp(&b.0)
-                    .unwrap()
-                    .then(a.1.partial_cmp(&b.1).unwrap())
-            });
-            all_weights
-        };
-
-        let mut packed_weights = vec![];
-        for (index, (attr, target, weight)) in lex_sorted_weights.iter().enumerate() {
-            let attr_has_more = if index + 1 < lex_sorted_weights.len()
-                && &lex_sorted_weights[index + 1].0 == attr
-            {
-                0x8000u16
-            } else {
-                0
-            };
-            let curved_weight = f64::signum(*weight)
-                * f64::powf(f64::atan(5.0 * f64::abs(*weight)), 1.0 / 7.0)
-                / PI
-                + 0.5;
-
-            if *target > 15 {
-                println!("Can't pack target into 4 bits");
-                panic!();
-            }
-            if attr == "california" {
-                println!("{} {} {}", attr, target, weight)
-            }
-            let packed = attr_has_more
-                | ((*target as u16) << 11)
-                | (f64::round(curved_weight * 2047.0) as u16) & 0x7FF;
-            packed_weights.push(packed);
-        }
-
-        let unquantized_label_weights = {
-            let mut unquantized_label_weights = vec![];
-            self.labels.iter().for_each(|label| {
-                let feature_refs = self.label_ref(label.unwrap().0).unwrap();
-                for i in 0..feature_refs.num_features {
-                    let feature_id = feature_refs.get(i as usize).unwrap();
-                    let feature = self.feature(feature_id).unwrap();
-                    unquantized_label_weights.push((
-                        feature.source as u8,
-                        feature.target as u8,
-                        feature.weight as f32,
-                    ));
-                }
-            });
-            unquantized_label_weights
-        };
+        Ok(Weights {
+            labels: label_current_order,
+            transitions,
+            state_features,
+        })
+    }
 
-        w.write_all(
-            &bincode2::serialize(&PackedModel {
-                header: header.clone(),
-                packed_attr_weights: packed_weights,
-                attr_vocab_fst: vocab_fst_data,
-                labels: label_current_order,
-                unquantized_label_weights,
-            })
-            .unwrap(),
-        )
-        .unwrap();
+    /// Quantize the model into a packed model. The packed model's extractor
+    /// and label schema are left at their defaults for the caller to fill in.
+    pub fn pack(
+        &self,
+        pieces: Option<&Tokenizer>,
+        quantization: Quantization,
+    ) -> io::Result<PackedModel> {
+        self.weights()?.pack(&self.header, pieces, quantization)
+    }
 
-        Ok(())
+    pub fn header(&self) -> &Header {
+        &self.header
     }
 }
 
@@ -0,0 +1,165 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+use crate::model::Weights;
+
+/// Limits on the state features `prune` keeps
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    /// Drop state features whose absolute weight is below this
+    pub min_weight: f64,
+    /// Keep at most this many attributes
+    pub max_attrs: Option<usize>,
+    /// Keep the packed model to at most this many bytes
+    pub max_bytes: Option<usize>,
+}
+
+/// Sum of the absolute weights of each attribute's state features, which is
+/// how far the attribute can sway a decision
+pub fn vocab_predictivity(weights: &Weights) -> HashMap<&str, f64> {
+    let mut predictivity = HashMap::new();
+    for (attr, _, weight) in &weights.state_features {
+        *predictivity.entry(attr.as_str()).or_default() += weight.abs();
+    }
+    predictivity
+}
+
+/// Drop state features to fit `budget`: first those with small weights, then
+/// those of the least predictive attributes until few enough attributes are
+/// left and `size` of the rest, the size of the packed model, fits.
+///
+/// Attributes that are word pieces leave the packed model's vocabulary with
+/// their last state feature, so pruning can change how words are segmented.
+pub fn prune<F>(weights: &Weights, budget: &Budget, mut size: F) -> io::Result<Weights>
+where
+    F: FnMut(&Weights) -> io::Result<usize>,
+{
+    let weights = Weights {
+        state_features: weights
+            .state_features
+            .iter()
+            .filter(|(_, _, weight)| weight.abs() >= budget.min_weight)
+            .cloned()
+            .collect(),
+        ..weights.clone()
+    };
+    let predictivity = vocab_predictivity(&weights);
+    let mut attrs: Vec<(&str, f64)> = predictivity.into_iter().collect();
+    attrs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(b.0)));
+    let keep = |count: usize| {
+        let kept: HashSet<&str> = attrs.iter().take(count).map(|(attr, _)| *attr).collect();
+        Weights {
+            state_features: weights
+                .state_features
+                .iter()
+                .filter(|(attr, _, _)| kept.contains(attr.as_str()))
+                .cloned()
+                .collect(),
+            ..weights.clone()
+        }
+    };
+
+    let most = attrs.len().min(budget.max_attrs.unwrap_or(usize::MAX));
+    let max_bytes = match budget.max_bytes {
+        Some(max_bytes) => max_bytes,
+        None => return Ok(keep(most)),
+    };
+    let pruned = keep(most);
+    if size(&pruned)? <= max_bytes {
+        return Ok(pruned);
+    }
+    if size(&keep(0))? > max_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} bytes is too small even for a model without state features",
+                max_bytes
+            ),
+        ));
+    }
+    // Keeping `fits` attributes is within budget and keeping `too_many` isn't.
+    let (mut fits, mut too_many) = (0, most);
+    while too_many - fits > 1 {
+        let middle = fits + (too_many - fits) / 2;
+        if size(&keep(middle))? <= max_bytes {
+            fits = middle;
+        } else {
+            too_many = middle;
+        }
+    }
+    Ok(keep(fits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weights() -> Weights {
+        Weights {
+            labels: vec!["road".to_string(), "locality".to_string()],
+            transitions: vec![(0, 1, 0.5)],
+            state_features: vec![
+                ("a".to_string(), 0, 1.0),
+                ("a".to_string(), 1, -0.5),
+                ("b".to_string(), 0, 0.1),
+                ("c".to_string(), 1, -2.0),
+                ("d".to_string(), 0, 0.3),
+            ],
+        }
+    }
+
+    fn attrs(weights: &Weights) -> Vec<&str> {
+        let mut attrs: Vec<&str> = weights
+            .state_features
+            .iter()
+            .map(|(attr, _, _)| attr.as_str())
+            .collect();
+        attrs.dedup();
+        attrs
+    }
+
+    #[test]
+    fn test_vocab_predictivity() {
+        let weights = weights();
+        let predictivity = vocab_predictivity(&weights);
+        assert_eq!(predictivity["a"], 1.5);
+        assert_eq!(predictivity["c"], 2.0);
+    }
+
+    #[test]
+    fn test_prune() {
+        let weights = weights();
+        let count = |weights: &Weights| Ok(10 + 10 * weights.state_features.len());
+
+        let budget = Budget {
+            min_weight: 0.2,
+            ..Budget::default()
+        };
+        assert_eq!(
+            attrs(&prune(&weights, &budget, count).unwrap()),
+            ["a", "c", "d"]
+        );
+
+        let budget = Budget {
+            max_attrs: Some(2),
+            ..Budget::default()
+        };
+        let pruned = prune(&weights, &budget, count).unwrap();
+        assert_eq!(attrs(&pruned), ["a", "c"]);
+        assert_eq!(pruned.transitions, weights.transitions);
+
+        // Keeping "c" and "a" takes 40 bytes, so only "c" fits in 35.
+        let budget = Budget {
+            max_bytes: Some(35),
+            ..Budget::default()
+        };
+        assert_eq!(attrs(&prune(&weights, &budget, count).unwrap()), ["c"]);
+        let budget = Budget {
+            max_bytes: Some(5),
+            ..Budget::default()
+        };
+        assert!(prune(&weights, &budget, count).is_err());
+    }
+}
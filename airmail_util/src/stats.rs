@@ -0,0 +1,118 @@
+use std::cmp::Ordering;
+
+/// Items that can be ranked from worst to best
+pub trait Rank {
+    /// Orders `self` before `other` if it is worse. Only equal items may tie,
+    /// so the ranking doesn't depend on the order items were added in.
+    fn rank(&self, other: &Self) -> Ordering;
+}
+
+/// The worst `max` of the items added to it
+///
+/// Items are kept until there are twice as many as needed, then the best are
+/// dropped, so adding is cheap and the list can be built up in parallel and
+/// merged.
+#[derive(Debug, Clone)]
+pub struct Worst<T> {
+    items: Vec<T>,
+    max: usize,
+}
+
+impl<T> Default for Worst<T> {
+    fn default() -> Self {
+        Worst {
+            items: Vec::new(),
+            max: 0,
+        }
+    }
+}
+
+impl<T: Rank + Clone> Worst<T> {
+    pub fn new(max: usize) -> Self {
+        Worst {
+            items: Vec::new(),
+            max,
+        }
+    }
+
+    /// Number of items kept
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.max == 0 {
+            return;
+        }
+        self.items.push(item);
+        if self.items.len() >= self.max * 2 {
+            self.trim();
+        }
+    }
+
+    /// Combine with another list, keeping the larger number of items
+    pub fn merge(&mut self, other: Worst<T>) {
+        self.max = self.max.max(other.max);
+        self.items.extend(other.items);
+        self.trim();
+    }
+
+    /// The worst items, worst first
+    pub fn to_vec(&self) -> Vec<T> {
+        let mut worst = Worst {
+            items: self.items.clone(),
+            max: self.max,
+        };
+        worst.trim();
+        worst.items
+    }
+
+    fn trim(&mut self) {
+        self.items.sort_by(T::rank);
+        self.items.truncate(self.max);
+    }
+}
+
+/// `numerator / denominator`, or zero when there's nothing to divide by
+pub fn ratio(numerator: usize, denominator: usize) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Rank for (u32, &str) {
+        fn rank(&self, other: &Self) -> Ordering {
+            other.0.cmp(&self.0).then(self.1.cmp(other.1))
+        }
+    }
+
+    #[test]
+    fn test_worst() {
+        let items = [(1, "a"), (3, "b"), (2, "c"), (3, "a"), (1, "b")];
+        let mut worst = Worst::new(2);
+        for item in items {
+            worst.push(item);
+        }
+        assert_eq!(worst.to_vec(), vec![(3, "a"), (3, "b")]);
+
+        let mut reversed = Worst::new(1);
+        let mut other = Worst::new(2);
+        for (index, item) in items.iter().rev().enumerate() {
+            if index % 2 == 0 {
+                reversed.push(*item);
+            } else {
+                other.push(*item);
+            }
+        }
+        reversed.merge(other);
+        assert_eq!(reversed.to_vec(), worst.to_vec());
+        assert!(Worst::<(u32, &str)>::new(0).to_vec().is_empty());
+        assert_eq!(ratio(1, 0), 0.0);
+    }
+}
@@ -0,0 +1,763 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    str::FromStr,
+};
+
+use airmail_lib::tokenizer::MAX_PIECE_LEN;
+use serde::{Deserialize, Serialize};
+use tokenizers::{
+    models::{
+        bpe::{BpeTrainer, BPE},
+        wordpiece::WordPiece,
+    },
+    AddedToken, Model,
+};
+
+/// Marks WordPiece pieces that continue a word. The tokenizer doesn't tell
+/// them apart, so they're written as the text they match.
+const CONTINUING_PREFIX: &str = "##";
+/// Unigram training starts from up to this many times as many pieces as it's
+/// asked for, the substrings of the words that cover the most text
+const SEED_FACTOR: usize = 4;
+/// Each round of Unigram pruning keeps at least this share of the pieces
+const SHRINK_FACTOR: f64 = 0.75;
+/// EM iterations before each round of Unigram pruning
+const EM_ITERATIONS: usize = 2;
+/// Unigram pieces expected to be used less than this many times are dropped
+const MIN_EXPECTED_COUNT: f64 = 0.5;
+
+/// How pieces are learned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VocabAlgorithm {
+    /// Start from the most common substrings and prune those that explain the
+    /// words least
+    #[default]
+    Unigram,
+    /// Repeatedly merge the most common pair of adjacent pieces
+    Bpe,
+    /// BPE that learns pieces continuing a word apart from those starting one
+    WordPiece,
+}
+
+impl FromStr for VocabAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unigram" => Ok(VocabAlgorithm::Unigram),
+            "bpe" => Ok(VocabAlgorithm::Bpe),
+            "wordpiece" | "word-piece" => Ok(VocabAlgorithm::WordPiece),
+            _ => Err(format!(
+                "unknown vocabulary algorithm `{}`, expected `unigram`, `bpe` or `wordpiece`",
+                s
+            )),
+        }
+    }
+}
+
+/// Settings for learning a vocabulary, which can be read from TOML
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VocabConfig {
+    /// Number of pieces to learn for each label. Every character of the
+    /// label's words is a piece, even if that makes more.
+    pub vocab_size: u32,
+    /// Number of pieces to learn for particular labels, instead of
+    /// `vocab_size`
+    pub label_vocab_sizes: BTreeMap<String, u32>,
+    /// Leave out words seen fewer times than this
+    pub min_frequency: usize,
+    /// Count each word at most this many times, so that common words don't
+    /// crowd out the rest
+    pub clamp: usize,
+    pub algorithm: VocabAlgorithm,
+}
+
+impl Default for VocabConfig {
+    fn default() -> Self {
+        VocabConfig {
+            vocab_size: 200000,
+            label_vocab_sizes: BTreeMap::new(),
+            min_frequency: 1,
+            clamp: 100,
+            algorithm: VocabAlgorithm::Unigram,
+        }
+    }
+}
+
+impl VocabConfig {
+    /// Number of pieces to learn for `label`
+    pub fn vocab_size(&self, label: &str) -> u32 {
+        self.label_vocab_sizes
+            .get(label)
+            .copied()
+            .unwrap_or(self.vocab_size)
+    }
+}
+
+/// How many times each word was seen with each label
+///
+/// Labels and words are kept in order, and the trainers are handed them in
+/// that order, so the same counts always learn the same vocabulary.
+#[derive(Debug, Clone, Default)]
+pub struct WordCounts {
+    counts: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+/// A word a label's pieces are learned from
+struct Word<'a> {
+    text: &'a str,
+    /// Times the word was seen
+    count: usize,
+    /// Times the word counts when learning pieces, up to the clamp
+    weight: usize,
+}
+
+impl WordCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, label: &str, word: &str) {
+        let word = word.trim();
+        if word.is_empty() {
+            return;
+        }
+        let words = match self.counts.get_mut(label) {
+            Some(words) => words,
+            None => self.counts.entry(label.to_string()).or_default(),
+        };
+        *words.entry(word.to_string()).or_default() += 1;
+    }
+
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.counts.keys().map(String::as_str)
+    }
+
+    /// Words seen with `label` at least `min_frequency` times
+    fn words(&self, label: &str, config: &VocabConfig) -> Vec<Word<'_>> {
+        self.counts
+            .get(label)
+            .into_iter()
+            .flatten()
+            .filter(|(_, count)| **count >= config.min_frequency)
+            .map(|(text, count)| Word {
+                text,
+                count: *count,
+                weight: (*count).min(config.clamp),
+            })
+            .collect()
+    }
+}
+
+/// Pieces learned for one label, in bytewise order, with their
+/// log-probabilities
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelVocab {
+    pub pieces: Vec<(String, f64)>,
+    pub summary: LabelSummary,
+}
+
+/// How well a label's pieces cover its words
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LabelSummary {
+    /// Number of times a word was seen with the label
+    pub words: usize,
+    pub distinct_words: usize,
+    /// Share of the words seen at least `min_frequency` times
+    pub kept_share: f64,
+    pub pieces: usize,
+    /// Share of the kept words that are a single piece
+    pub single_piece_share: f64,
+}
+
+/// Learn the pieces of `label`'s words. BPE and WordPiece are learned by the
+/// `tokenizers` trainers.
+///
+/// Unigram pieces are scored by the model's log-probabilities. BPE and
+/// WordPiece have none, so their pieces are scored by how often they're used
+/// in the words' segmentations, with add-one smoothing. Pieces longer than
+/// the tokenizer looks for are left out.
+pub fn train_label(
+    counts: &WordCounts,
+    label: &str,
+    config: &VocabConfig,
+) -> tokenizers::Result<LabelVocab> {
+    let words = counts.words(label, config);
+    let vocab_size = config.vocab_size(label);
+    let (mut pieces, lengths) = if words.is_empty() {
+        (vec![], vec![])
+    } else {
+        match config.algorithm {
+            VocabAlgorithm::Unigram => train_unigram(&words, vocab_size as usize),
+            VocabAlgorithm::Bpe => usage_scores(&train_bpe(&words, vocab_size)?, &words, None)?,
+            VocabAlgorithm::WordPiece => usage_scores(
+                &train_wordpiece(&words, vocab_size)?,
+                &words,
+                Some(CONTINUING_PREFIX),
+            )?,
+        }
+    };
+    pieces.retain(|(piece, _)| !piece.is_empty() && piece.len() <= MAX_PIECE_LEN);
+
+    let seen = counts.counts.get(label);
+    let total: usize = seen.into_iter().flat_map(|words| words.values()).sum();
+    let kept: usize = words.iter().map(|word| word.count).sum();
+    let single_piece: usize = words
+        .iter()
+        .zip(&lengths)
+        .filter(|(_, length)| **length == 1)
+        .map(|(word, _)| word.count)
+        .sum();
+    let ratio = |numerator: usize, denominator: usize| {
+        if denominator == 0 {
+            0.0
+        } else {
+            numerator as f64 / denominator as f64
+        }
+    };
+    Ok(LabelVocab {
+        summary: LabelSummary {
+            words: total,
+            distinct_words: seen.map_or(0, BTreeMap::len),
+            kept_share: ratio(kept, total),
+            pieces: pieces.len(),
+            single_piece_share: ratio(single_piece, kept),
+        },
+        pieces,
+    })
+}
+
+/// Pieces learned for a label, in bytewise order, with their scores, and how
+/// many pieces each word is segmented into
+type Trained = (Vec<(String, f64)>, Vec<usize>);
+
+/// Byte offsets of the character boundaries of `text`, including its end
+fn boundaries(text: &str) -> Vec<usize> {
+    text.char_indices()
+        .map(|(offset, _)| offset)
+        .chain([text.len()])
+        .collect()
+}
+
+fn is_single_char(piece: &str) -> bool {
+    piece.chars().nth(1).is_none()
+}
+
+fn log_add_exp(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
+    }
+    if b == f64::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
+
+/// Pieces of a Unigram model, in bytewise order, with their log-probabilities
+struct UnigramModel {
+    pieces: Vec<String>,
+    scores: Vec<f64>,
+    index: HashMap<String, usize>,
+}
+
+impl UnigramModel {
+    fn new(mut pieces: Vec<(String, f64)>) -> UnigramModel {
+        pieces.sort_by(|a, b| a.0.cmp(&b.0));
+        let index = pieces
+            .iter()
+            .enumerate()
+            .map(|(id, (piece, _))| (piece.clone(), id))
+            .collect();
+        let (pieces, scores) = pieces.into_iter().unzip();
+        UnigramModel {
+            pieces,
+            scores,
+            index,
+        }
+    }
+
+    /// Start from every character and the substrings that cover the most
+    /// text, scored by how often they occur
+    fn seed(words: &[Word], vocab_size: usize) -> UnigramModel {
+        let mut frequencies: HashMap<&str, usize> = HashMap::new();
+        for word in words {
+            let bounds = boundaries(word.text);
+            for start in 0..bounds.len() {
+                for end in &bounds[start + 1..] {
+                    if end - bounds[start] > MAX_PIECE_LEN {
+                        break;
+                    }
+                    *frequencies
+                        .entry(&word.text[bounds[start]..*end])
+                        .or_default() += word.weight;
+                }
+            }
+        }
+        let (mut seeds, longer): (Vec<_>, Vec<_>) = frequencies
+            .into_iter()
+            .partition(|(piece, _)| is_single_char(piece));
+        let mut longer: Vec<(&str, usize)> = longer
+            .into_iter()
+            .filter(|(_, frequency)| *frequency > 1)
+            .collect();
+        longer.sort_by(|a, b| {
+            let coverage = |(piece, frequency): &(&str, usize)| frequency * piece.chars().count();
+            coverage(b).cmp(&coverage(a)).then(a.0.cmp(b.0))
+        });
+        longer.truncate(vocab_size.saturating_mul(SEED_FACTOR));
+        seeds.extend(longer);
+
+        let total: usize = seeds.iter().map(|(_, frequency)| frequency).sum();
+        UnigramModel::new(
+            seeds
+                .into_iter()
+                .map(|(piece, frequency)| {
+                    (piece.to_string(), (frequency as f64 / total as f64).ln())
+                })
+                .collect(),
+        )
+    }
+
+    /// Every piece in `text`, as the indices of the boundaries it starts and
+    /// ends at and its id, in order of where they end
+    fn lattice(&self, text: &str, bounds: &[usize]) -> Vec<(usize, usize, usize)> {
+        let mut edges = vec![];
+        for end in 1..bounds.len() {
+            for start in (0..end).rev() {
+                if bounds[end] - bounds[start] > MAX_PIECE_LEN {
+                    break;
+                }
+                if let Some(id) = self.index.get(&text[bounds[start]..bounds[end]]) {
+                    edges.push((start, end, *id));
+                }
+            }
+        }
+        edges
+    }
+
+    /// Add how many times each piece is expected to be used in `word` to
+    /// `counts`
+    fn expect(&self, word: &Word, counts: &mut [f64]) {
+        let bounds = boundaries(word.text);
+        let mut edges = self.lattice(word.text, &bounds);
+        let last = bounds.len() - 1;
+        let mut alpha = vec![f64::NEG_INFINITY; bounds.len()];
+        alpha[0] = 0.0;
+        for (start, end, id) in &edges {
+            alpha[*end] = log_add_exp(alpha[*end], alpha[*start] + self.scores[*id]);
+        }
+        edges.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        let mut beta = vec![f64::NEG_INFINITY; bounds.len()];
+        beta[last] = 0.0;
+        for (start, end, id) in &edges {
+            beta[*start] = log_add_exp(beta[*start], beta[*end] + self.scores[*id]);
+        }
+        for (start, end, id) in &edges {
+            let posterior = alpha[*start] + self.scores[*id] + beta[*end] - alpha[last];
+            counts[*id] += word.weight as f64 * posterior.exp();
+        }
+    }
+
+    /// The most likely segmentation of `text` without the piece `skip`, as
+    /// its log-probability and pieces
+    fn viterbi(&self, text: &str, skip: Option<usize>) -> (f64, Vec<usize>) {
+        let bounds = boundaries(text);
+        let mut best: Vec<(f64, usize, usize)> =
+            vec![(f64::NEG_INFINITY, 0, usize::MAX); bounds.len()];
+        best[0].0 = 0.0;
+        for (start, end, id) in self.lattice(text, &bounds) {
+            let score = best[start].0 + self.scores[id];
+            if Some(id) != skip && score > best[end].0 {
+                best[end] = (score, start, id);
+            }
+        }
+        let mut pieces = vec![];
+        let mut position = bounds.len() - 1;
+        while position > 0 && best[position].2 != usize::MAX {
+            pieces.push(best[position].2);
+            position = best[position].1;
+        }
+        pieces.reverse();
+        (best[bounds.len() - 1].0, pieces)
+    }
+
+    /// Re-estimate the scores from the pieces' expected use, dropping pieces
+    /// that are hardly used. Single characters stay so that every word can
+    /// still be segmented.
+    fn em(self, words: &[Word]) -> UnigramModel {
+        let mut counts = vec![0.0; self.pieces.len()];
+        for word in words {
+            self.expect(word, &mut counts);
+        }
+        let kept: Vec<(String, f64)> = self
+            .pieces
+            .into_iter()
+            .zip(counts)
+            .filter(|(piece, count)| *count >= MIN_EXPECTED_COUNT || is_single_char(piece))
+            .map(|(piece, count)| (piece, count.max(MIN_EXPECTED_COUNT)))
+            .collect();
+        let total: f64 = kept.iter().map(|(_, count)| count).sum();
+        UnigramModel::new(
+            kept.into_iter()
+                .map(|(piece, count)| (piece, (count / total).ln()))
+                .collect(),
+        )
+    }
+
+    /// Drop the longer pieces whose loss would cost the words' best
+    /// segmentations least, keeping at least `vocab_size` pieces
+    fn prune(self, words: &[Word], vocab_size: usize) -> UnigramModel {
+        let mut usage = vec![0.0; self.pieces.len()];
+        for word in words {
+            for id in self.viterbi(word.text, None).1 {
+                usage[id] += word.weight as f64;
+            }
+        }
+        let mut losses: Vec<(f64, usize)> = vec![];
+        let mut kept: Vec<(String, f64)> = vec![];
+        for (id, piece) in self.pieces.iter().enumerate() {
+            if is_single_char(piece) {
+                kept.push((piece.clone(), self.scores[id]));
+            } else {
+                let (alternative, _) = self.viterbi(piece, Some(id));
+                losses.push((usage[id] * (self.scores[id] - alternative), id));
+            }
+        }
+        losses.sort_by(|a, b| {
+            b.0.total_cmp(&a.0)
+                .then(self.pieces[a.1].cmp(&self.pieces[b.1]))
+        });
+        let target = vocab_size.max((self.pieces.len() as f64 * SHRINK_FACTOR) as usize);
+        for (_, id) in losses.into_iter().take(target.saturating_sub(kept.len())) {
+            kept.push((self.pieces[id].clone(), self.scores[id]));
+        }
+        UnigramModel::new(kept)
+    }
+}
+
+/// Learn a Unigram model, returning its pieces and how many pieces each word
+/// is segmented into.
+///
+/// `UnigramTrainer` only seeds substrings that are followed by more than one
+/// character, which no piece at the end of a word is, so it can't learn the
+/// ends of words.
+fn train_unigram(words: &[Word], vocab_size: usize) -> Trained {
+    let mut model = UnigramModel::seed(words, vocab_size);
+    loop {
+        for _ in 0..EM_ITERATIONS {
+            model = model.em(words);
+        }
+        let prunable = model.pieces.iter().any(|piece| !is_single_char(piece));
+        if model.pieces.len() <= vocab_size || !prunable {
+            break;
+        }
+        model = model.prune(words, vocab_size);
+    }
+    let lengths = words
+        .iter()
+        .map(|word| model.viterbi(word.text, None).1.len())
+        .collect();
+    (
+        model.pieces.into_iter().zip(model.scores).collect(),
+        lengths,
+    )
+}
+
+/// Learn BPE merges from the words
+fn train_bpe(words: &[Word], vocab_size: u32) -> tokenizers::Result<BPE> {
+    let trainer = BpeTrainer::builder()
+        .show_progress(false)
+        .vocab_size(vocab_size as usize)
+        .build();
+    let mut model = BPE::default();
+    trainer.do_train(&word_weights(words), &mut model)?;
+    Ok(model)
+}
+
+/// Learn a WordPiece vocabulary from the words. `WordPieceTrainer` is BPE
+/// with a prefix on continuing pieces, but only takes words through `feed`,
+/// so its BPE trainer is used directly.
+fn train_wordpiece(words: &[Word], vocab_size: u32) -> tokenizers::Result<WordPiece> {
+    // The trainer numbers continuing pieces in the order it comes across
+    // them in a `HashMap`, and breaks ties between merges by number, so
+    // they're numbered up front, in order.
+    let alphabet: BTreeSet<char> = words.iter().flat_map(|word| word.text.chars()).collect();
+    let continuing = alphabet
+        .iter()
+        .map(|c| AddedToken::from(format!("{}{}", CONTINUING_PREFIX, c), true))
+        .collect();
+    let trainer = BpeTrainer::builder()
+        .show_progress(false)
+        .vocab_size(vocab_size as usize)
+        .continuing_subword_prefix(CONTINUING_PREFIX.to_string())
+        .special_tokens(continuing)
+        .build();
+    let mut bpe = BPE::default();
+    trainer.do_train(&word_weights(words), &mut bpe)?;
+    WordPiece::builder()
+        .vocab(bpe.get_vocab())
+        .continuing_subword_prefix(CONTINUING_PREFIX.to_string())
+        .max_input_chars_per_word(usize::MAX)
+        .build()
+}
+
+fn word_weights(words: &[Word]) -> HashMap<String, u32> {
+    words
+        .iter()
+        .map(|word| (word.text.to_string(), word.weight as u32))
+        .collect()
+}
+
+/// Score every piece of `model` by how often it's used in the words, weighted
+/// as in training, with add-one smoothing. Pieces starting with `continuing`
+/// count as the text they match.
+fn usage_scores(
+    model: &impl Model,
+    words: &[Word],
+    continuing: Option<&str>,
+) -> tokenizers::Result<Trained> {
+    let text = |piece: String| match continuing.and_then(|prefix| piece.strip_prefix(prefix)) {
+        Some(text) => text.to_string(),
+        None => piece,
+    };
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    let mut lengths = Vec::with_capacity(words.len());
+    for word in words {
+        let tokens = model.tokenize(word.text)?;
+        lengths.push(tokens.len());
+        for token in tokens {
+            *counts.entry(text(token.value)).or_default() += word.weight;
+        }
+    }
+    for piece in model.get_vocab().into_keys() {
+        counts.entry(text(piece)).or_default();
+    }
+    let total = (counts.values().sum::<usize>() + counts.len()) as f64;
+    let pieces = counts
+        .into_iter()
+        .map(|(piece, count)| (piece, ((count + 1) as f64 / total).ln()))
+        .collect();
+    Ok((pieces, lengths))
+}
+
+/// The pieces of every label in bytewise order. A piece in several labels'
+/// vocabularies keeps its best score.
+pub fn merge<'a>(vocabs: impl IntoIterator<Item = &'a LabelVocab>) -> Vec<(String, f64)> {
+    let mut pieces: BTreeMap<String, f64> = BTreeMap::new();
+    for vocab in vocabs {
+        for (piece, score) in &vocab.pieces {
+            let best = pieces.entry(piece.clone()).or_insert(f64::NEG_INFINITY);
+            *best = best.max(*score);
+        }
+    }
+    pieces.into_iter().collect()
+}
+
+/// What a vocabulary run learned, ready to be printed or serialized
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub config: VocabConfig,
+    /// Number of pieces in the merged vocabulary
+    pub pieces: usize,
+    pub labels: BTreeMap<String, LabelSummary>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<20} {:>10} {:>10} {:>8} {:>8} {:>8}",
+            "label", "words", "distinct", "kept", "pieces", "single"
+        )?;
+        for (label, summary) in &self.labels {
+            writeln!(
+                f,
+                "{:<20} {:>10} {:>10} {:>8.4} {:>8} {:>8.4}",
+                label,
+                summary.words,
+                summary.distinct_words,
+                summary.kept_share,
+                summary.pieces,
+                summary.single_piece_share
+            )?;
+        }
+        writeln!(f, "{} pieces in the merged vocabulary", self.pieces)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use airmail_lib::tokenizer::{Tokenizer, VocabOutput};
+    use fst::{raw::Fst, MapBuilder};
+
+    fn counts() -> WordCounts {
+        let mut counts = WordCounts::new();
+        for _ in 0..3 {
+            for word in ["main", "maple", "market", "street", "stone"] {
+                counts.add("road", word);
+            }
+        }
+        counts.add("road", "rare");
+        for word in ["seattle", "seaside", "tacoma"] {
+            counts.add("city", word);
+        }
+        counts
+    }
+
+    #[test]
+    fn test_words() {
+        let counts = counts();
+        assert_eq!(counts.labels().collect::<Vec<_>>(), ["city", "road"]);
+        let config = VocabConfig {
+            min_frequency: 2,
+            clamp: 2,
+            ..VocabConfig::default()
+        };
+        let words = counts.words("road", &config);
+        assert_eq!(words.len(), 5);
+        assert_eq!(words[0].text, "main");
+        assert_eq!((words[0].count, words[0].weight), (3, 2));
+        assert!(words.iter().all(|word| word.text != "rare"));
+    }
+
+    #[test]
+    fn test_train_label() {
+        let counts = counts();
+        for algorithm in [
+            VocabAlgorithm::Unigram,
+            VocabAlgorithm::Bpe,
+            VocabAlgorithm::WordPiece,
+        ] {
+            let config = VocabConfig {
+                vocab_size: 30,
+                label_vocab_sizes: BTreeMap::from([("city".to_string(), 5)]),
+                min_frequency: 2,
+                algorithm,
+                ..VocabConfig::default()
+            };
+            let road = train_label(&counts, "road", &config).unwrap();
+            assert!(road.pieces.len() <= 30, "{:?}", algorithm);
+            assert!(road.pieces.iter().all(|(_, score)| *score < 0.0));
+            assert!(road.pieces.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            // Every character is a piece.
+            for c in "mainpletrso".chars() {
+                assert!(road.pieces.iter().any(|(piece, _)| *piece == c.to_string()));
+            }
+            assert_eq!(road.summary.words, 16);
+            assert_eq!(road.summary.distinct_words, 6);
+            assert_eq!(road.summary.kept_share, 15.0 / 16.0);
+            // Runs on the same counts learn the same pieces.
+            for _ in 0..3 {
+                assert_eq!(train_label(&counts, "road", &config).unwrap(), road);
+            }
+
+            // No city word is seen twice, so there's nothing to learn from.
+            let city = train_label(&counts, "city", &config).unwrap();
+            assert!(city.pieces.is_empty());
+            assert_eq!(city.summary.kept_share, 0.0);
+            assert_eq!(merge([&road, &city]), road.pieces);
+        }
+
+        // With room for them, the most common words become pieces.
+        let config = VocabConfig {
+            vocab_size: 1000,
+            ..VocabConfig::default()
+        };
+        let road = train_label(&counts, "road", &config).unwrap();
+        assert!(road.pieces.iter().any(|(piece, _)| piece == "street"));
+        assert!(road.summary.single_piece_share > 0.9);
+    }
+
+    /// The pieces the tokenizer finds in `word` with a vocabulary built from
+    /// `pieces`, as `gen_vocab` builds it
+    fn segment(pieces: &[(String, f64)], word: &str) -> Vec<String> {
+        let mut builder = MapBuilder::memory();
+        for (id, (piece, score)) in pieces.iter().enumerate() {
+            let output = VocabOutput {
+                id: id as u32,
+                log_prob: Some(*score),
+            };
+            builder.insert(piece, output.encode()).unwrap();
+        }
+        let tokenizer = Tokenizer::new(&Fst::new(builder.into_inner().unwrap()).unwrap());
+        let tokens = tokenizer.tokenize(word);
+        assert_eq!(tokens.len(), 1);
+        tokens[0]
+            .iter()
+            .map(|id| tokenizer.stringify_feature(*id))
+            .collect()
+    }
+
+    #[test]
+    fn test_segmentation() {
+        let mut counts = WordCounts::new();
+        let stems = ["haupt", "bahnhof", "linden", "schiller", "garten", "kirch"];
+        let suffixes = [
+            "strasse", "weg", "platz", "gasse", "allee", "damm", "ufer", "steig",
+        ];
+        for stem in stems {
+            for suffix in suffixes {
+                let word = format!("{}{}", stem, suffix);
+                if word != "bahnhofweg" && word != "lindenplatz" {
+                    for _ in 0..5 {
+                        counts.add("road", &word);
+                    }
+                }
+            }
+        }
+        for _ in 0..50 {
+            counts.add("road", "ring");
+        }
+        // Merges build pieces up a pair at a time, so BPE and WordPiece need
+        // room for the pieces along the way.
+        for (algorithm, vocab_size) in [
+            (VocabAlgorithm::Unigram, 50),
+            (VocabAlgorithm::Bpe, 100),
+            (VocabAlgorithm::WordPiece, 150),
+        ] {
+            let config = VocabConfig {
+                vocab_size,
+                algorithm,
+                ..VocabConfig::default()
+            };
+            let road = train_label(&counts, "road", &config).unwrap();
+            // The most common word is a piece of its own.
+            assert_eq!(segment(&road.pieces, "ring"), ["ring"], "{:?}", algorithm);
+            // Words never seen split into the stems and suffixes they're
+            // made of.
+            assert_eq!(
+                segment(&road.pieces, "bahnhofweg"),
+                ["bahnhof", "weg"],
+                "{:?}",
+                algorithm
+            );
+            assert_eq!(
+                segment(&road.pieces, "lindenplatz"),
+                ["linden", "platz"],
+                "{:?}",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(
+            "wordpiece".parse::<VocabAlgorithm>().unwrap(),
+            VocabAlgorithm::WordPiece
+        );
+        assert!("sentencepiece".parse::<VocabAlgorithm>().is_err());
+        let config: VocabConfig =
+            toml::from_str("clamp = 5\nalgorithm = \"bpe\"\n[label_vocab_sizes]\nroad = 10\n")
+                .unwrap();
+        assert_eq!(config.clamp, 5);
+        assert_eq!(config.algorithm, VocabAlgorithm::Bpe);
+        assert_eq!(config.vocab_size("road"), 10);
+        assert_eq!(config.vocab_size("city"), 200000);
+    }
+}
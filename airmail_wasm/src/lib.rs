@@ -1,6 +1,6 @@
 mod utils;
 
-use airmail_lib::parser::Parser;
+use airmail_lib::{parser::Parser, Error};
 use once_cell::sync::Lazy;
 use wasm_bindgen::prelude::*;
 
@@ -8,13 +8,18 @@ use wasm_bindgen::prelude::*;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
-static PARSER: Lazy<Parser> = Lazy::new(|| Parser::new(include_bytes!("model.airmail")));
+static PARSER: Lazy<Result<Parser, Error>> =
+    Lazy::new(|| Parser::try_new(include_bytes!("model.airmail")));
 
 #[wasm_bindgen]
-pub fn parse(query: &str) -> Vec<JsValue> {
-    PARSER
+pub fn parse(query: &str) -> Result<Vec<JsValue>, JsValue> {
+    let parser = PARSER
+        .as_ref()
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+    Ok(parser
         .parse(query)
+        .map_err(|err| JsValue::from_str(&err.to_string()))?
         .iter()
-        .map(|tag| JsValue::from_str(&tag.clone()))
-        .collect()
+        .map(|tag| JsValue::from_str(tag))
+        .collect())
 }
@@ -10,6 +10,6 @@ wasm_bindgen_test_configure!(run_in_browser);
 
 #[wasm_bindgen_test]
 fn pass() {
-    let query_results = parse("Seattle");
+    let query_results = parse("Seattle").unwrap();
     assert_eq!(query_results, vec![JsValue::from_str("locality")]);
 }